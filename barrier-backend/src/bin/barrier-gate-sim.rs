@@ -0,0 +1,84 @@
+//! Gate controller simulator for local development.
+//!
+//! Point `gate_server` at it and it answers `ControlAccess` and `GetStatus` like a real
+//! controller, see `barrier-gate-sim --help` for the failure injection options.
+
+use actix_web::{middleware::Logger, App, HttpServer};
+use std::{process, sync::Arc, time::Duration};
+
+#[path = "../services/gate/rpc.rs"]
+#[allow(dead_code)]
+mod rpc;
+#[path = "../services/gate/sim.rs"]
+mod sim;
+
+use sim::{SimConfig, Simulator};
+
+const USAGE: &str = "usage: barrier-gate-sim [options]
+
+    --listen ADDR         address to listen on (127.0.0.1:8000)
+    --latency-ms MS       delay of every answer (0)
+    --failure-rate RATE   share of requests answered with HTTP 500 (0)
+    --fault-rate RATE     share of requests answered with an XML-RPC fault (0)
+    --reject-rate RATE    share of commands answered with a negative Result (0)
+    --pulse-ms MS         how long an opened gate stays open (5000)
+    --callback            call MethodNameForAnswer back on IPSERVER:PORTSERVER
+    --callback-path PATH  path of the callback (/)";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn value<T: std::str::FromStr>(args: &mut impl Iterator<Item = String>) -> T {
+    args.next()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn parse_args() -> (String, SimConfig) {
+    let mut listen = "127.0.0.1:8000".to_string();
+    let mut config = SimConfig::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => listen = value(&mut args),
+            "--latency-ms" => config.latency = Duration::from_millis(value(&mut args)),
+            "--failure-rate" => config.failure_rate = value(&mut args),
+            "--fault-rate" => config.fault_rate = value(&mut args),
+            "--reject-rate" => config.reject_rate = value(&mut args),
+            "--pulse-ms" => config.pulse = Duration::from_millis(value(&mut args)),
+            "--callback" => config.callback = true,
+            "--callback-path" => config.callback_path = value(&mut args),
+            _ => usage(),
+        }
+    }
+
+    (listen, config)
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let (listen, config) = parse_args();
+
+    flexi_logger::Logger::try_with_env_or_str("info")
+        .expect("logger")
+        .start()
+        .expect("logger");
+
+    let sim = Arc::new(Simulator::new(config));
+
+    log::info!("simulating gate controllers on {}", listen);
+
+    HttpServer::new(move || {
+        let sim = sim.clone();
+
+        App::new()
+            .wrap(Logger::default())
+            .configure(move |cfg| sim::configure(cfg, sim))
+    })
+    .bind(&listen)?
+    .run()
+    .await
+}
@@ -0,0 +1,244 @@
+//! Command line: `barrier-backend [CONFIG] [COMMAND]`, the server runs without a command.
+
+use crate::{
+    config::Config,
+    services::{
+        db::{Db, MongoDb, TotpItem},
+        revocation,
+        users::Users,
+    },
+};
+use chrono::Utc;
+use std::{path::Path, process};
+
+const USAGE: &str = "usage: barrier-backend [CONFIG] [COMMAND]
+
+Runs the server unless a command is given.
+
+commands:
+    user add NAME [GROUP...]   add a local user
+    user remove NAME           remove a local user
+    user passwd NAME           set the password of a local user
+    session revoke NAME        log a user out of every device
+    totp allow NAME            let a user enroll a new authenticator on their next login
+
+Passwords are read twice from the terminal, or from stdin without one. Removing a user or
+changing their password logs them out as well.";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    AddUser { name: String, groups: Vec<String> },
+    RemoveUser { name: String },
+    SetPassword { name: String },
+    RevokeSessions { name: String },
+    AllowTotp { name: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub config_file: Option<String>,
+    pub command: Option<Command>,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn parse_args(args: Vec<String>) -> Option<Args> {
+    let mut args = args.into_iter().peekable();
+    let config_file = match args.peek().map(String::as_str) {
+        Some("-h") | Some("--help") => return None,
+        Some("user") | Some("session") | Some("totp") | None => None,
+        Some(_) => args.next(),
+    };
+    let args: Vec<String> = args.collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let command = match args.as_slice() {
+        [] => None,
+        ["user", "add", name, groups @ ..] => Some(Command::AddUser {
+            name: name.to_string(),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+        }),
+        ["user", "remove", name] => Some(Command::RemoveUser {
+            name: name.to_string(),
+        }),
+        ["user", "passwd", name] => Some(Command::SetPassword {
+            name: name.to_string(),
+        }),
+        ["session", "revoke", name] => Some(Command::RevokeSessions {
+            name: name.to_string(),
+        }),
+        ["totp", "allow", name] => Some(Command::AllowTotp {
+            name: name.to_string(),
+        }),
+        _ => return None,
+    };
+
+    Some(Args {
+        config_file,
+        command,
+    })
+}
+
+pub fn parse() -> Args {
+    parse_args(std::env::args().skip(1).collect()).unwrap_or_else(|| usage())
+}
+
+fn read_password() -> Result<String, String> {
+    let read = |prompt| {
+        eprint!("{}", prompt);
+        rpassword::read_password()
+            // no terminal, e.g. in scripts
+            .or_else(|_| {
+                let mut line = String::new();
+
+                std::io::stdin()
+                    .read_line(&mut line)
+                    .map(|_| line.trim_end_matches(&['\r', '\n'][..]).to_string())
+            })
+            .map_err(|e| format!("failed to read the password: {}", e))
+    };
+
+    let password = read("Password: ")?;
+
+    if read("Repeat password: ")? != password {
+        return Err("the passwords do not match".to_string());
+    }
+
+    Ok(password)
+}
+
+/// Changes the local users file.
+fn update_users(
+    config: &Config,
+    change: impl FnOnce(&mut Users) -> Result<(), String>,
+) -> Result<(), String> {
+    let file = match &config.local_users {
+        Some(local_users) => Path::new(&local_users.file),
+        None => return Err("[local_users] is not configured".to_string()),
+    };
+    let mut users = Users::load(file)?;
+
+    change(&mut users)?;
+    users.save(file)
+}
+
+pub async fn run(config: &Config, command: Command) -> Result<(), String> {
+    let name = match command {
+        Command::AddUser { name, groups } => {
+            return update_users(config, |users| {
+                users.add(&name, &read_password()?, groups)?;
+                eprintln!("added user {}", name);
+                Ok(())
+            })
+        }
+        Command::RemoveUser { name } => {
+            update_users(config, |users| {
+                users.remove(&name)?;
+                eprintln!("removed user {}", name);
+                Ok(())
+            })?;
+            name
+        }
+        Command::SetPassword { name } => {
+            update_users(config, |users| {
+                users.set_password(&name, &read_password()?)?;
+                eprintln!("changed the password of {}", name);
+                Ok(())
+            })?;
+            name
+        }
+        Command::RevokeSessions { name } => name,
+        Command::AllowTotp { name } => {
+            // drops the current secret, a lost authenticator is replaced the same way
+            MongoDb::new(&config.mongo_uri)
+                .await
+                .store_totp(&TotpItem {
+                    username: name.clone(),
+                    secret: String::new(),
+                    confirmed: false,
+                    last_step: None,
+                    enroll_allowed: true,
+                })
+                .await;
+            eprintln!("{} enrolls a new authenticator on the next login", name);
+            return Ok(());
+        }
+    };
+
+    // the access tokens are refused by every instance, the refresh tokens are gone
+    let db = MongoDb::new(&config.mongo_uri).await;
+    let revoked = revocation::end_sessions(&db, &name, Utc::now().timestamp()).await;
+
+    eprintln!("revoked {} sessions of {}", revoked.len(), name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn parse(args: &[&str]) -> Option<Args> {
+        parse_args(args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse(&[]),
+            Some(Args {
+                config_file: None,
+                command: None
+            })
+        );
+        assert_eq!(
+            parse(&["config.toml"]),
+            Some(Args {
+                config_file: Some("config.toml".to_string()),
+                command: None
+            })
+        );
+        assert_eq!(
+            parse(&["config.toml", "user", "add", "guest", "guests", "guards"]),
+            Some(Args {
+                config_file: Some("config.toml".to_string()),
+                command: Some(Command::AddUser {
+                    name: "guest".to_string(),
+                    groups: vec!["guests".to_string(), "guards".to_string()],
+                }),
+            })
+        );
+        assert_eq!(
+            parse(&["user", "passwd", "guest"]),
+            Some(Args {
+                config_file: None,
+                command: Some(Command::SetPassword {
+                    name: "guest".to_string()
+                }),
+            })
+        );
+        assert_eq!(
+            parse(&["config.toml", "session", "revoke", "guest"]),
+            Some(Args {
+                config_file: Some("config.toml".to_string()),
+                command: Some(Command::RevokeSessions {
+                    name: "guest".to_string()
+                }),
+            })
+        );
+        assert_eq!(
+            parse(&["totp", "allow", "guest"]),
+            Some(Args {
+                config_file: None,
+                command: Some(Command::AllowTotp {
+                    name: "guest".to_string()
+                }),
+            })
+        );
+        assert_eq!(parse(&["user", "remove"]), None);
+        assert_eq!(parse(&["config.toml", "serve"]), None);
+    }
+}
@@ -1,15 +1,133 @@
-use crate::structs::Gate;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, process};
+use crate::{
+    services::gate::{retry::RetryPolicy, xmlrpc::XmlRpcParams, DRIVERS},
+    structs::Gate,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::HashMap, fs, path::Path, process};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Ldap {
     pub server: String,
+    /// Where the groups are searched.
     pub base: String,
-    pub bind: String,
+    /// DN of the user with `%(username)`, used unless `search` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind: Option<String>,
+    /// Group filter with `%(username)` or, for `groups = "member"`, `%(dn)` of the user.
     pub filter: Option<String>,
+    #[serde(default)]
+    pub groups: GroupStrategy,
+    /// Group attribute matched against `[gates]`.
+    #[serde(default = "default_group_attribute")]
+    pub group_attribute: String,
+    /// How many levels of groups in groups are followed, 0 disables nesting.
+    #[serde(default)]
+    pub nested_depth: u32,
+    /// Search-bind mode: the user entry is looked up with a service account first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search: Option<LdapSearch>,
+    /// Upgrade `ldap://` connections with StartTLS.
+    #[serde(default)]
+    pub starttls: bool,
+    /// CA bundle of a private CA.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    /// Client certificate and its key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<String>,
+    #[serde(default)]
+    pub require_cert: RequireCert,
+    /// Allows `ldap://` without StartTLS, passwords then travel in the clear.
+    #[serde(default)]
+    pub allow_plaintext: bool,
+    /// How long the groups of the last login are trusted while the directory is down,
+    /// 0 disables the offline login cache.
+    #[serde(default)]
+    pub offline_max_age_secs: u64,
+}
+
+/// How the groups of a user are found.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupStrategy {
+    /// Groups listing the login in `memberUid`, found with `filter`.
+    #[default]
+    MemberUid,
+    /// Groups listing the user DN in `member`, `filter` defaults to `(member=%(dn))`.
+    Member,
+    /// Groups from the `memberOf` attribute of the user entry.
+    MemberOf,
+}
+
+/// Checks of the server certificate, as `TLS_REQCERT` of `ldap.conf`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequireCert {
+    Never,
+    Allow,
+    Try,
+    #[default]
+    Demand,
+    Hard,
+}
+
+/// Where the logins are checked, see `services::auth::ChainAuth`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+    #[default]
+    Ldap,
+    Local,
+}
+
+/// Users outside the directory, managed with `barrier-backend user`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LocalUsers {
+    pub file: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LdapSearch {
+    pub bind_dn: String,
+    pub bind_password: String,
+    /// Where the users are searched, `ldap.base` when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// User filter with `%(username)`, e.g. `(sAMAccountName=%(username))`.
+    pub filter: String,
+}
+
+fn default_auth() -> Vec<AuthBackend> {
+    vec![AuthBackend::Ldap]
+}
+
+/// Accepts `auth = "ldap"` as well as `auth = ["ldap", "local"]`.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<AuthBackend>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(AuthBackend),
+        Many(Vec<AuthBackend>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(backend) => vec![backend],
+        OneOrMany::Many(backends) => backends,
+    })
 }
 
+fn default_group_attribute() -> String {
+    "cn".to_string()
+}
+
+/// Deeper nesting is most likely a loop in the directory.
+pub const MAX_NESTED_DEPTH: u32 = 10;
+
 fn default_dry_run() -> bool {
     false
 }
@@ -26,12 +144,289 @@ fn default_retries() -> i32 {
     1
 }
 
+fn default_driver() -> String {
+    "xmlrpc".to_string()
+}
+
+/// Members of the XML-RPC `ControlAccess` request. Each one is optional: unset members of a
+/// gate are taken from the global `[xmlrpc]` section and then from the built-in defaults.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct XmlRpcOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub com_port: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pku_address: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_address: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregate_address: Option<i32>,
+    /// `Command` of the open request, `command` is the old name of the option.
+    #[serde(alias = "command", skip_serializing_if = "Option::is_none")]
+    pub open_command: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub close_command: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hold_command: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_command: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method_name_for_answer: Option<String>,
+    /// Method queried for the gate state, not part of `ControlAccess`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_server: Option<i32>,
+}
+
+/// Retry policy of the gate commands, per-gate values win over the global `[retry]` section.
+/// The number of attempts is the `retries` of the gate.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct RetryOverrides {
+    /// Delay before the second attempt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_backoff_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_backoff_ms: Option<u64>,
+    /// Growth of the delay after every failed attempt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff_multiplier: Option<f64>,
+    /// Part of the delay randomly taken off, from 0 to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempt_timeout_ms: Option<u64>,
+    /// Whether connection failures and timeouts are retried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_transport_errors: Option<bool>,
+}
+
+/// Gate controller server, gates choose one with `controller = "name"`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Controller {
+    pub url: String,
+    /// HTTP basic authentication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u64>,
+    /// Limit of a whole request, the retry `attempt_timeout_ms` applies as well.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Addresses the controller calls back from, the host of `url` when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub callback_from: Vec<String>,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_probe_interval_secs() -> u64 {
+    10
+}
+
+/// Fails the commands of a gate fast once its controller stops answering.
+#[derive(Serialize, Deserialize)]
+pub struct Breaker {
+    /// Consecutive transport failures opening the circuit, 0 disables the breaker.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How often the controllers of open circuits are queried for recovery.
+    #[serde(default = "default_probe_interval_secs")]
+    pub probe_interval_secs: u64,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_failure_threshold(),
+            probe_interval_secs: default_probe_interval_secs(),
+        }
+    }
+}
+
+fn default_callback_path() -> String {
+    "/".to_string()
+}
+
+fn default_callback_timeout_ms() -> u64 {
+    5000
+}
+
+/// Endpoint receiving the `MethodNameForAnswer` calls of the controllers.
+#[derive(Serialize, Deserialize)]
+pub struct Callback {
+    #[serde(default = "default_callback_path")]
+    pub path: String,
+    /// How long `open` waits for the physical confirmation.
+    #[serde(default = "default_callback_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Address of `PORTSERVER`, served apart from `listen_addr` with the callback alone.
+    pub listen_addr: String,
+    /// Addresses `gate_server` calls back from, its host when empty. The controllers of
+    /// `[controllers]` set their own `callback_from`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+}
+
+/// OpenID Connect login, see `services::oidc`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Oidc {
+    /// The endpoints are read from `{issuer}/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    /// Where the IdP sends the browser back with `code` and `state`, which go on to
+    /// `/auth/oidc/callback`.
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Claim with the groups matched against `[gates]`.
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "profile".to_string()]
+}
+
+fn default_groups_claim() -> String {
+    "groups".to_string()
+}
+
+fn default_window_secs() -> u64 {
+    15 * 60
+}
+
+fn default_max_user_failures() -> u32 {
+    5
+}
+
+fn default_max_ip_failures() -> u32 {
+    50
+}
+
+fn default_lockout_secs() -> u64 {
+    15 * 60
+}
+
+fn default_delay_ms() -> u64 {
+    500
+}
+
+fn default_max_delay_ms() -> u64 {
+    10_000
+}
+
+/// Failed logins are kept this long, `window_secs` and `lockout_secs` can't be longer.
+pub const MAX_THROTTLE_SECS: u64 = 24 * 60 * 60;
+
+/// Brute-force protection of the logins, see `services::throttle`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Throttle {
+    /// Failures older than this are forgotten.
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// Failures of a user within the window locking them out, 0 disables the limit.
+    #[serde(default = "default_max_user_failures")]
+    pub max_user_failures: u32,
+    /// Failures from an address within the window locking it out, 0 disables the limit.
+    #[serde(default = "default_max_ip_failures")]
+    pub max_ip_failures: u32,
+    /// How long a lockout lasts after the last failure.
+    #[serde(default = "default_lockout_secs")]
+    pub lockout_secs: u64,
+    /// Delay of the next login after a failure of the user, doubled by every further one.
+    #[serde(default = "default_delay_ms")]
+    pub delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            window_secs: default_window_secs(),
+            max_user_failures: default_max_user_failures(),
+            max_ip_failures: default_max_ip_failures(),
+            lockout_secs: default_lockout_secs(),
+            delay_ms: default_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+        }
+    }
+}
+
+fn default_totp_issuer() -> String {
+    "Barrier".to_string()
+}
+
+/// TOTP second factor, see `services::totp`. Any user may enroll, the users matching
+/// `groups` or `gates` must.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Totp {
+    /// Name of the account in the authenticator app.
+    #[serde(default = "default_totp_issuer")]
+    pub issuer: String,
+    /// Groups whose members need a second factor.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Gates which need a second factor from everyone granted them.
+    #[serde(default)]
+    pub gates: Vec<String>,
+}
+
+impl Default for Totp {
+    fn default() -> Self {
+        Self {
+            issuer: default_totp_issuer(),
+            groups: Vec::new(),
+            gates: Vec::new(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ConfigGate {
     pub id: i32,
     pub description: String,
     #[serde(default = "default_retries")]
     pub retries: i32,
+    #[serde(default = "default_driver")]
+    pub driver: String,
+    /// Name in `[controllers]`, `gate_server` when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controller: Option<String>,
+    #[serde(default)]
+    pub xmlrpc: XmlRpcOverrides,
+    #[serde(default)]
+    pub retry: RetryOverrides,
+}
+
+/// Algorithm of an asymmetric token key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JwtAlgorithm {
+    /// Ed25519.
+    EdDSA,
+    RS256,
+}
+
+/// Key pair signing the tokens, see `services::jwt`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtKey {
+    /// `kid` header of the tokens signed with the key.
+    pub kid: String,
+    pub algorithm: JwtAlgorithm,
+    /// PEM file of the private key, needed by the signing key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    /// PEM file of the public key, enough for a retired key still accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -39,8 +434,20 @@ pub struct Config {
     #[serde(default = "default_listen_addr")]
     pub listen_addr: String,
 
+    /// HS256 secret, used unless `jwt_keys` is set.
+    #[serde(default)]
     pub jwt_key: String,
 
+    /// Asymmetric keys, the first signs the tokens and the others are accepted during a
+    /// rotation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jwt_keys: Vec<JwtKey>,
+
+    /// HMAC secret of the stored refresh tokens, apart from the signing keys so they rotate
+    /// without logging anyone out.
+    #[serde(default)]
+    pub refresh_token_key: String,
+
     pub mongo_uri: String,
 
     #[serde(default = "default_dry_run")]
@@ -49,10 +456,48 @@ pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: String,
 
+    /// Controller of the gates without `controller`.
     pub gate_server: String,
+    #[serde(default)]
+    pub controllers: HashMap<String, Controller>,
     pub gates: HashMap<String, Vec<String>>,
+    /// Groups allowed to close, hold and release the gates.
+    #[serde(default)]
+    pub gate_control: HashMap<String, Vec<String>>,
     pub gate_mapping: HashMap<String, ConfigGate>,
     pub ldap: Ldap,
+
+    /// Providers asked in this order.
+    #[serde(default = "default_auth", deserialize_with = "one_or_many")]
+    pub auth: Vec<AuthBackend>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_users: Option<LocalUsers>,
+
+    #[serde(default)]
+    pub xmlrpc: XmlRpcOverrides,
+
+    #[serde(default)]
+    pub retry: RetryOverrides,
+
+    #[serde(default)]
+    pub breaker: Breaker,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback: Option<Callback>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oidc: Option<Oidc>,
+
+    #[serde(default)]
+    pub totp: Totp,
+
+    #[serde(default)]
+    pub throttle: Throttle,
+
+    /// How often the gate status is polled, 0 disables polling. Needs `status_method`.
+    #[serde(default)]
+    pub status_poll_interval_secs: u64,
 }
 
 impl Default for Config {
@@ -60,15 +505,19 @@ impl Default for Config {
         Self {
             listen_addr: "127.0.0.1:7000".to_string(),
             jwt_key: "PLEASE FILL JWT KEY".to_string(),
+            jwt_keys: Vec::new(),
+            refresh_token_key: "PLEASE FILL REFRESH TOKEN KEY".to_string(),
             mongo_uri: "mongo://please-fill-uri/".to_string(),
             dry_run: false,
             log_level: "warn".to_string(),
             gate_server: "PLEASE FILL GATE SERVER ADDRESS".to_string(),
+            controllers: HashMap::new(),
             gates: {
                 let mut example = HashMap::new();
                 example.insert("group".to_string(), vec!["Gate".to_string()]);
                 example
             },
+            gate_control: HashMap::new(),
             gate_mapping: {
                 let mut example = HashMap::new();
                 example.insert(
@@ -77,6 +526,10 @@ impl Default for Config {
                         id: 1,
                         description: "Example gate".to_string(),
                         retries: 1,
+                        driver: default_driver(),
+                        controller: None,
+                        xmlrpc: XmlRpcOverrides::default(),
+                        retry: RetryOverrides::default(),
                     },
                 );
                 example
@@ -84,17 +537,50 @@ impl Default for Config {
             ldap: Ldap {
                 server: "PLEASE FILL LDAP SERVER ADDRESS".to_string(),
                 base: "PLEASE FILL LDAP BASE".to_string(),
-                bind: "PLEASE FILL LDAP BIND".to_string(),
+                bind: Some("PLEASE FILL LDAP BIND".to_string()),
                 filter: Some("PLEASE FILL LDAP FILTER OR DELETE THIS LINE".to_string()),
+                groups: GroupStrategy::default(),
+                group_attribute: default_group_attribute(),
+                nested_depth: 0,
+                search: None,
+                starttls: false,
+                ca_file: None,
+                cert_file: None,
+                key_file: None,
+                require_cert: RequireCert::default(),
+                allow_plaintext: false,
+                offline_max_age_secs: 0,
             },
+            auth: default_auth(),
+            local_users: None,
+            xmlrpc: XmlRpcOverrides::default(),
+            retry: RetryOverrides::default(),
+            breaker: Breaker::default(),
+            callback: None,
+            oidc: None,
+            totp: Totp::default(),
+            throttle: Throttle::default(),
+            status_poll_interval_secs: 0,
         }
     }
 }
 
 impl Config {
-    pub fn new() -> Self {
-        if let Some(config_file) = std::env::args().nth(1) {
-            let s = fs::read_to_string(&config_file).expect("config.toml");
+    /// Reads `config_file`, the per-user config when unset.
+    pub fn new(config_file: Option<&str>) -> Self {
+        let config = Self::load(config_file);
+
+        if let Err(e) = config.validate() {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+
+        config
+    }
+
+    fn load(config_file: Option<&str>) -> Self {
+        if let Some(config_file) = config_file {
+            let s = fs::read_to_string(config_file).expect("config.toml");
 
             return toml::from_str(&s).expect("true toml file");
         }
@@ -127,26 +613,578 @@ impl Config {
         toml::from_str(&s).expect("true toml file")
     }
 
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_jwt_keys()?;
+
+        if self.auth.is_empty() {
+            return Err("auth must list at least one provider".to_string());
+        }
+
+        for (i, backend) in self.auth.iter().enumerate() {
+            if self.auth[..i].contains(backend) {
+                return Err(format!("auth lists {:?} twice", backend));
+            }
+
+            match backend {
+                AuthBackend::Ldap => self.validate_ldap()?,
+                AuthBackend::Local if self.local_users.is_none() => {
+                    return Err("auth provider \"local\" needs [local_users]".to_string())
+                }
+                AuthBackend::Local => {}
+            }
+        }
+
+        for (name, gate) in self
+            .gate_mapping
+            .iter()
+            .filter(|(_, g)| g.driver == "xmlrpc")
+        {
+            let params = XmlRpcParams::resolve(gate.id, &self.xmlrpc, &gate.xmlrpc);
+
+            params
+                .validate()
+                .map_err(|e| format!("gate_mapping.{}.xmlrpc: {}", name, e))?;
+
+            // the controllers don't document a status query, it must be named explicitly
+            if self.status_poll_interval_secs > 0 && params.status_method.is_none() {
+                return Err(format!(
+                    "status_poll_interval_secs needs the status_method of gate {}",
+                    name
+                ));
+            }
+        }
+
+        for (name, controller) in &self.controllers {
+            if reqwest::Url::parse(&controller.url).is_err() {
+                return Err(format!(
+                    "controllers.{}.url {:?} is not a valid URL",
+                    name, controller.url
+                ));
+            }
+
+            if controller.password.is_some() && controller.username.is_none() {
+                return Err(format!("controllers.{}.password needs a username", name));
+            }
+
+            if controller.connect_timeout_ms == Some(0) || controller.timeout_ms == Some(0) {
+                return Err(format!("controllers.{}: timeouts must be positive", name));
+            }
+
+            if let Some(ip) = controller
+                .callback_from
+                .iter()
+                .find(|ip| ip.parse::<std::net::IpAddr>().is_err())
+            {
+                return Err(format!(
+                    "controllers.{}.callback_from: {:?} is not an IP address",
+                    name, ip
+                ));
+            }
+        }
+
+        for (name, gate) in &self.gate_mapping {
+            if !DRIVERS.contains(&gate.driver.as_str()) {
+                return Err(format!(
+                    "gate_mapping.{}.driver: unknown driver {:?}, expected one of {:?}",
+                    name, gate.driver, DRIVERS
+                ));
+            }
+
+            if let Some(controller) = &gate.controller {
+                if !self.controllers.contains_key(controller) {
+                    return Err(format!(
+                        "gate_mapping.{}.controller: unknown controller {:?}",
+                        name, controller
+                    ));
+                }
+            }
+
+            RetryPolicy::resolve(gate.retries, &self.retry, &gate.retry)
+                .validate()
+                .map_err(|e| format!("gate_mapping.{}.retry: {}", name, e))?;
+        }
+
+        if self.breaker.failure_threshold > 0 && self.breaker.probe_interval_secs == 0 {
+            return Err("breaker.probe_interval_secs must be positive".to_string());
+        }
+
+        if let Some(callback) = &self.callback {
+            if !callback.path.starts_with('/') {
+                return Err(format!(
+                    "callback.path must start with '/', got {:?}",
+                    callback.path
+                ));
+            }
+
+            if callback.timeout_ms == 0 {
+                return Err("callback.timeout_ms must be positive".to_string());
+            }
+
+            if callback.listen_addr == self.listen_addr {
+                return Err("callback.listen_addr must differ from listen_addr".to_string());
+            }
+
+            if let Some(ip) = callback
+                .allow
+                .iter()
+                .find(|ip| ip.parse::<std::net::IpAddr>().is_err())
+            {
+                return Err(format!("callback.allow: {:?} is not an IP address", ip));
+            }
+        }
+
+        if let Some(oidc) = &self.oidc {
+            for (name, url) in [
+                ("issuer", &oidc.issuer),
+                ("redirect_uri", &oidc.redirect_uri),
+            ] {
+                if reqwest::Url::parse(url).is_err() {
+                    return Err(format!("oidc.{} {:?} is not a valid URL", name, url));
+                }
+            }
+
+            if oidc.client_id.is_empty() {
+                return Err("oidc.client_id must not be empty".to_string());
+            }
+
+            if !oidc.scopes.iter().any(|scope| scope == "openid") {
+                return Err("oidc.scopes must include openid".to_string());
+            }
+        }
+
+        if let Some(gate) = self
+            .totp
+            .gates
+            .iter()
+            .find(|gate| !self.gate_mapping.contains_key(*gate))
+        {
+            return Err(format!("totp.gates: unknown gate {:?}", gate));
+        }
+
+        let throttle = &self.throttle;
+
+        if throttle.window_secs == 0 || throttle.window_secs > MAX_THROTTLE_SECS {
+            return Err(format!(
+                "throttle.window_secs must be between 1 and {}",
+                MAX_THROTTLE_SECS
+            ));
+        }
+
+        if throttle.lockout_secs > MAX_THROTTLE_SECS {
+            return Err(format!(
+                "throttle.lockout_secs must not exceed {}",
+                MAX_THROTTLE_SECS
+            ));
+        }
+
+        if throttle.max_delay_ms < throttle.delay_ms {
+            return Err("throttle.max_delay_ms must not be below delay_ms".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn validate_jwt_keys(&self) -> Result<(), String> {
+        let keys = &self.jwt_keys;
+
+        if keys.is_empty() && self.jwt_key.is_empty() {
+            return Err("either jwt_key or jwt_keys must be set".to_string());
+        }
+
+        if self.refresh_token_key.is_empty() {
+            return Err("refresh_token_key must be set".to_string());
+        }
+
+        // rotating jwt_key would void the stored refresh tokens
+        if self.refresh_token_key == self.jwt_key {
+            return Err("refresh_token_key must differ from jwt_key".to_string());
+        }
+
+        if let Some(key) = keys.first() {
+            if key.private_key.is_none() {
+                return Err(format!(
+                    "jwt_keys: the signing key {:?} needs private_key",
+                    key.kid
+                ));
+            }
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            if key.kid.is_empty() {
+                return Err("jwt_keys: kid must not be empty".to_string());
+            }
+
+            if keys[..i].iter().any(|other| other.kid == key.kid) {
+                return Err(format!("jwt_keys: kid {:?} is used twice", key.kid));
+            }
+
+            if key.private_key.is_none() && key.public_key.is_none() {
+                return Err(format!(
+                    "jwt_keys: key {:?} needs private_key or public_key",
+                    key.kid
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_ldap(&self) -> Result<(), String> {
+        match &self.ldap.search {
+            Some(search) if !search.filter.contains("%(username)") => {
+                return Err("ldap.search.filter must contain %(username)".to_string())
+            }
+            None if self.ldap.bind.is_none() => {
+                return Err("either ldap.bind or ldap.search must be set".to_string())
+            }
+            _ => {}
+        }
+
+        self.validate_ldap_groups()?;
+        self.validate_ldap_tls()
+    }
+
+    fn validate_ldap_groups(&self) -> Result<(), String> {
+        let ldap = &self.ldap;
+
+        if ldap.group_attribute.is_empty() {
+            return Err("ldap.group_attribute must not be empty".to_string());
+        }
+
+        if ldap.nested_depth > MAX_NESTED_DEPTH {
+            return Err(format!(
+                "ldap.nested_depth must not exceed {}",
+                MAX_NESTED_DEPTH
+            ));
+        }
+
+        match &ldap.filter {
+            Some(filter) if ldap.groups == GroupStrategy::Member && !filter.contains("%(dn)") => {
+                Err("ldap.filter must contain %(dn) with groups = \"member\"".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_ldap_tls(&self) -> Result<(), String> {
+        let ldap = &self.ldap;
+        let server = ldap.server.to_ascii_lowercase();
+
+        if server.starts_with("ldap://") && !ldap.starttls && !ldap.allow_plaintext {
+            return Err(format!(
+                "ldap.server {:?} is plaintext, enable ldap.starttls or set ldap.allow_plaintext",
+                ldap.server
+            ));
+        }
+
+        if server.starts_with("ldaps://") && ldap.starttls {
+            return Err("ldap.starttls can't be used with ldaps://".to_string());
+        }
+
+        if ldap.cert_file.is_some() != ldap.key_file.is_some() {
+            return Err("ldap.cert_file and ldap.key_file must be set together".to_string());
+        }
+
+        let files = [
+            ("ca_file", &ldap.ca_file),
+            ("cert_file", &ldap.cert_file),
+            ("key_file", &ldap.key_file),
+        ];
+
+        for (name, file) in files {
+            if let Some(file) = file {
+                if !Path::new(file).is_file() {
+                    return Err(format!("ldap.{}: {:?} is not a file", name, file));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gates available to each group, `gate_control` grants include plain open as well.
     pub fn get_mappings(&self) -> HashMap<String, Vec<Gate>> {
-        self.gates
-            .clone()
-            .into_iter()
-            .map(|(group, gates)| {
-                (
-                    group,
-                    gates
-                        .into_iter()
-                        .filter_map(|gate_name| {
-                            self.gate_mapping.get(&gate_name).map(|gate| Gate {
-                                id: gate.id,
-                                name: gate_name,
-                                description: gate.description.clone(),
-                                retries: gate.retries,
-                            })
-                        })
-                        .collect(),
-                )
-            })
-            .collect()
+        let mut mappings: HashMap<String, Vec<Gate>> = HashMap::new();
+
+        let grants = self
+            .gates
+            .iter()
+            .map(|(group, gates)| (group, gates, false))
+            .chain(
+                self.gate_control
+                    .iter()
+                    .map(|(group, gates)| (group, gates, true)),
+            );
+
+        for (group, gates, control) in grants {
+            let available = mappings.entry(group.clone()).or_default();
+
+            for gate_name in gates {
+                let gate = match self.gate_mapping.get(gate_name) {
+                    Some(gate) => gate,
+                    None => continue,
+                };
+
+                match available.iter_mut().find(|g| &g.name == gate_name) {
+                    Some(existing) => existing.control |= control,
+                    None => available.push(Gate {
+                        id: gate.id,
+                        name: gate_name.clone(),
+                        description: gate.description.clone(),
+                        retries: gate.retries,
+                        control,
+                    }),
+                }
+            }
+        }
+
+        mappings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn ldap_tls() {
+        let mut config = Config::default();
+
+        config.ldap.server = "ldap://127.0.0.1".to_string();
+        assert!(config.validate().is_err());
+
+        config.ldap.starttls = true;
+        assert_eq!(config.validate(), Ok(()));
+
+        config.ldap.server = "ldaps://127.0.0.1".to_string();
+        assert!(config.validate().is_err());
+
+        config.ldap.starttls = false;
+        config.ldap.cert_file = Some("Cargo.toml".to_string());
+        assert!(config.validate().is_err());
+
+        config.ldap.key_file = Some("/nonexistent".to_string());
+        assert!(config.validate().is_err());
+
+        config.ldap.key_file = Some("Cargo.toml".to_string());
+        assert_eq!(config.validate(), Ok(()));
+
+        config.ldap.server = "ldap://127.0.0.1".to_string();
+        config.ldap.allow_plaintext = true;
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn auth_providers() {
+        let parse = |auth: &str| -> Result<Vec<AuthBackend>, toml::de::Error> {
+            #[derive(Deserialize)]
+            struct Auth {
+                #[serde(deserialize_with = "one_or_many")]
+                auth: Vec<AuthBackend>,
+            }
+
+            toml::from_str::<Auth>(&format!("auth = {}", auth)).map(|a| a.auth)
+        };
+
+        assert_eq!(parse("\"local\"").unwrap(), vec![AuthBackend::Local]);
+        assert_eq!(
+            parse("[\"ldap\", \"local\"]").unwrap(),
+            vec![AuthBackend::Ldap, AuthBackend::Local]
+        );
+        assert!(parse("\"kerberos\"").is_err());
+
+        let mut config = Config {
+            auth: vec![AuthBackend::Ldap, AuthBackend::Local],
+            ..Config::default()
+        };
+
+        assert!(config.validate().is_err());
+
+        config.local_users = Some(LocalUsers {
+            file: "users.toml".to_string(),
+        });
+        assert_eq!(config.validate(), Ok(()));
+
+        config.auth = vec![AuthBackend::Local, AuthBackend::Local];
+        assert!(config.validate().is_err());
+
+        config.auth = Vec::new();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn jwt_keys() {
+        let key = |kid: &str, private_key: Option<&str>, public_key: Option<&str>| JwtKey {
+            kid: kid.to_string(),
+            algorithm: JwtAlgorithm::EdDSA,
+            private_key: private_key.map(str::to_string),
+            public_key: public_key.map(str::to_string),
+        };
+        let mut config = Config {
+            jwt_key: String::new(),
+            ..Config::default()
+        };
+
+        assert!(config.validate().is_err());
+
+        config.jwt_keys = vec![
+            key("2024-06", Some("new.pem"), None),
+            key("2024-01", None, Some("old.pub.pem")),
+        ];
+        assert_eq!(config.validate(), Ok(()));
+
+        // nothing to hash the refresh tokens with
+        config.refresh_token_key = String::new();
+        assert!(config.validate().is_err());
+
+        config.refresh_token_key = "secret".to_string();
+
+        // a retired key can't sign
+        config.jwt_keys.reverse();
+        assert!(config.validate().is_err());
+
+        config.jwt_keys = vec![
+            key("2024-06", Some("new.pem"), None),
+            key("2024-06", None, Some("old.pub.pem")),
+        ];
+        assert!(config.validate().is_err());
+
+        config.jwt_keys = vec![key("2024-06", Some("new.pem"), None), key("", None, None)];
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn refresh_token_key() {
+        let mut config = Config {
+            jwt_key: "secret".to_string(),
+            refresh_token_key: String::new(),
+            ..Config::default()
+        };
+
+        assert!(config.validate().is_err());
+
+        config.refresh_token_key = "secret".to_string();
+        assert!(config.validate().is_err());
+
+        config.refresh_token_key = "refresh secret".to_string();
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn callback() {
+        let mut config = Config {
+            callback: Some(Callback {
+                path: "/".to_string(),
+                timeout_ms: 5000,
+                listen_addr: "0.0.0.0:8080".to_string(),
+                allow: vec!["10.0.1.10".to_string()],
+            }),
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate(), Ok(()));
+
+        // not with the public API
+        config.callback.as_mut().unwrap().listen_addr = config.listen_addr.clone();
+        assert!(config.validate().is_err());
+
+        let callback = config.callback.as_mut().unwrap();
+
+        callback.listen_addr = "0.0.0.0:8080".to_string();
+        callback.allow = vec!["controller.local".to_string()];
+        assert!(config.validate().is_err());
+
+        config.callback.as_mut().unwrap().allow = Vec::new();
+        config.controllers.insert(
+            "north".to_string(),
+            Controller {
+                url: "http://10.0.2.10/RPC2".to_string(),
+                callback_from: vec!["north.local".to_string()],
+                ..Controller::default()
+            },
+        );
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn status_polling() {
+        let mut config = Config {
+            status_poll_interval_secs: 30,
+            ..Config::default()
+        };
+
+        assert!(config.validate().is_err());
+
+        config.xmlrpc.status_method = Some("GetStatus".to_string());
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn gate_driver() {
+        let mut config = Config::default();
+
+        assert_eq!(config.validate(), Ok(()));
+
+        for gate in config.gate_mapping.values_mut() {
+            gate.driver = "xmlprc".to_string();
+        }
+        assert!(config.validate().is_err());
+
+        for gate in config.gate_mapping.values_mut() {
+            gate.driver = "dry_run".to_string();
+        }
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn oidc() {
+        let oidc: Oidc = toml::from_str(
+            r#"
+            issuer = "https://sso.org.ru/realms/org"
+            client_id = "barrier"
+            redirect_uri = "https://barrier.org.ru/auth/oidc/callback"
+            "#,
+        )
+        .unwrap();
+        let mut config = Config {
+            oidc: Some(oidc),
+            ..Config::default()
+        };
+
+        assert_eq!(config.validate(), Ok(()));
+
+        let oidc = config.oidc.as_mut().unwrap();
+
+        assert_eq!(oidc.scopes, vec!["openid", "profile"]);
+        assert_eq!(oidc.groups_claim, "groups");
+
+        oidc.scopes = vec!["profile".to_string()];
+        assert!(config.validate().is_err());
+
+        let oidc = config.oidc.as_mut().unwrap();
+
+        oidc.scopes = vec!["openid".to_string()];
+        oidc.redirect_uri = "/auth/oidc/callback".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn ldap_groups() {
+        let mut config = Config::default();
+
+        config.ldap.groups = GroupStrategy::Member;
+        config.ldap.filter = Some("(memberUid=%(username))".to_string());
+        assert!(config.validate().is_err());
+
+        config.ldap.filter = Some("(&(objectClass=group)(member=%(dn)))".to_string());
+        assert_eq!(config.validate(), Ok(()));
+
+        config.ldap.nested_depth = MAX_NESTED_DEPTH + 1;
+        assert!(config.validate().is_err());
+
+        config.ldap.nested_depth = 3;
+        config.ldap.group_attribute = "".to_string();
+        assert!(config.validate().is_err());
     }
 }
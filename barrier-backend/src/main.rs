@@ -1,58 +1,141 @@
-use actix_web::{error, get, middleware::Logger, post, web, App, HttpRequest, HttpServer};
-use chrono::Local;
-use config::Config;
+use actix_web::{
+    cookie::Cookie, delete, error, get, http::header, middleware::Logger, post, rt, web, App,
+    HttpRequest, HttpResponse, HttpServer,
+};
+use chrono::{Local, Utc};
+use config::{AuthBackend, Config};
 use jwt_simple::prelude::Duration;
-use log::{debug, error, info};
+use log::{error, info, warn};
+use mongodb::bson::DateTime;
 use services::{
-    auth::{Auth, LDAPAuth},
-    db::{Db, MongoDb},
-    jwt::{JWTToken, Jwt},
+    auth::{Auth, AuthError, CachedAuth, ChainAuth, Identity, LDAPAuth, LocalAuth},
+    db::{Db, MongoDb, RefreshTokenItem, SessionItem, TotpItem},
+    gate::{
+        breaker::CircuitBreaker,
+        callback::PendingResults,
+        rpc::{self, Value},
+        status::GateMonitor,
+        Command, GateDriver, GateDrivers,
+    },
+    jwt::{JWTToken, Jwt, ACCESS_TOKEN_SECS, USED_TOKENS_KEPT},
+    oidc::{Oidc, OidcError, BROWSER_COOKIE},
+    revocation::Revocations,
+    throttle::Throttle,
+    totp::{self, Totp},
 };
 use std::sync::Arc;
-use structs::{gates, login, logout, open, Errors, Gate};
+use structs::{gates, health, login, logout, open, sessions, status, Errors, Gate};
 use tokio::sync::Mutex;
 
 use crate::services::db::EventType;
 
+mod cli;
 mod config;
 mod middleware;
 mod services;
 mod structs;
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn configure_app(
     cfg: &mut web::ServiceConfig,
-    auth: Arc<Mutex<Box<dyn Auth + Send>>>,
+    auth: Arc<dyn Auth + Send + Sync>,
     db: Arc<Mutex<Box<dyn Db + Send>>>,
     config: Arc<Mutex<Config>>,
     jwt: Arc<Mutex<Jwt>>,
+    gate_driver: Arc<dyn GateDriver + Send + Sync>,
+    oidc: Option<Arc<Oidc>>,
+    totp: Arc<Totp>,
+    throttle: Arc<Throttle>,
+    revocations: Arc<Revocations>,
+    monitor: Arc<GateMonitor>,
+    breaker: Arc<CircuitBreaker>,
 ) {
+    let mut auth_scope = web::scope("/auth")
+        .service(login_handler)
+        .service(verify_handler)
+        .service(enroll_handler)
+        .service(confirm_handler)
+        .service(logout_handler)
+        .service(refresh_handler)
+        .service(sessions_handler)
+        .service(revoke_session_handler);
+
+    if let Some(oidc) = oidc {
+        auth_scope = auth_scope
+            .app_data(web::Data::new(oidc))
+            .service(oidc_start_handler)
+            .service(oidc_callback_handler);
+    }
+
     cfg.app_data(web::Data::new(auth))
         .app_data(web::Data::new(jwt))
         .app_data(web::Data::new(db))
         .app_data(web::Data::new(config))
-        .service(
-            web::scope("/auth/")
-                .service(login_handler)
-                .service(logout_handler)
-                .service(refresh_handler),
-        )
+        .app_data(web::Data::new(gate_driver))
+        .app_data(web::Data::new(monitor))
+        .app_data(web::Data::new(breaker))
+        .app_data(web::Data::new(totp))
+        .app_data(web::Data::new(throttle))
+        .app_data(web::Data::new(revocations))
+        .service(health_handler)
+        .service(jwks_handler)
+        .service(auth_scope)
         .service(
             web::scope("/gates")
                 .service(open_handler)
-                .service(gates_handler),
+                .service(close_handler)
+                .service(hold_handler)
+                .service(release_handler)
+                .service(status_handler)
+                .service(gates_handler)
+                .service(gates_health_handler),
         );
 }
 
+/// The controller callback, served on `callback.listen_addr` only.
+pub(crate) fn configure_callbacks(cfg: &mut web::ServiceConfig, callbacks: Arc<PendingResults>) {
+    cfg.service(
+        web::resource(callbacks.path())
+            .app_data(web::Data::new(callbacks.clone()))
+            .route(web::post().to(callback_handler)),
+    );
+}
+
+/// Issues the tokens of the session, a new one when `session_id` is `None`.
 async fn issue_token(
+    session_id: Option<&str>,
     username: &str,
     rooms: Vec<Gate>,
+    degraded: bool,
     jwt: &Jwt,
     db: &dyn Db,
 ) -> (web::Json<login::Response>, String) {
-    let (access_token, refresh_token, session_id) =
-        jwt.issue_token(username.to_string(), rooms.clone(), Duration::from_mins(5));
+    let expired_in = Duration::from_secs(ACCESS_TOKEN_SECS);
+    let (access_token, refresh_token, session_id) = match session_id {
+        Some(session_id) => {
+            let (access_token, refresh_token) = jwt.issue_session_token(
+                session_id.to_string(),
+                username.to_string(),
+                rooms.clone(),
+                expired_in,
+                degraded,
+            );
 
-    db.store_refresh(username, &refresh_token, &rooms).await;
+            (access_token, refresh_token, session_id.to_string())
+        }
+        None => jwt.issue_token(username.to_string(), rooms.clone(), expired_in, degraded),
+    };
+
+    db.store_refresh(&RefreshTokenItem {
+        username: username.to_string(),
+        session_id: session_id.clone(),
+        token_hash: jwt.refresh_token_hash(&refresh_token),
+        issued: DateTime::now(),
+        rooms,
+        used: false,
+        degraded,
+    })
+    .await;
 
     (
         web::Json(login::Response {
@@ -63,19 +146,70 @@ async fn issue_token(
     )
 }
 
+fn user_agent(req: &HttpRequest) -> String {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Issues the tokens of a completed login.
+async fn start_session(
+    req: &HttpRequest,
+    ip: &str,
+    username: &str,
+    identity: Identity,
+    jwt: &Jwt,
+    db: &dyn Db,
+) -> (web::Json<login::Response>, String) {
+    let (token, session_id) =
+        issue_token(None, username, identity.gates, identity.degraded, jwt, db).await;
+    let now = DateTime::now();
+
+    db.store_session(&SessionItem {
+        session_id: session_id.clone(),
+        username: username.to_string(),
+        user_agent: user_agent(req),
+        ip: ip.to_string(),
+        created: now,
+        last_refresh: now,
+    })
+    .await;
+
+    info!(
+        "Successful {}authentication for {:?} by {} from {} at {}",
+        if identity.degraded { "degraded " } else { "" },
+        username,
+        identity.provider,
+        ip,
+        Local::now()
+    );
+    db.log_event(
+        ip,
+        username,
+        &session_id,
+        EventType::SuccessfulLogin {
+            provider: identity.provider,
+            degraded: identity.degraded,
+        },
+    )
+    .await;
+
+    (token, session_id)
+}
+
 #[post("/login")]
 async fn login_handler(
     req: HttpRequest,
     data: web::Json<login::LoginRequest>,
     jwt: web::Data<Arc<Mutex<Jwt>>>,
     db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
-    auth: web::Data<Arc<Mutex<Box<dyn Auth + Send>>>>,
-) -> Result<web::Json<login::Response>, Errors> {
+    auth: web::Data<Arc<dyn Auth + Send + Sync>>,
+    totp: web::Data<Arc<Totp>>,
+    throttle: web::Data<Arc<Throttle>>,
+) -> Result<web::Json<login::LoginResponse>, Errors> {
     info!("Authentication request for user {:?}", data.login);
-    let auth = auth.lock().await;
-    let jwt = jwt.lock().await;
-    let db = db.lock().await;
-    let rooms = auth.get_available_rooms(&data.login, &data.password);
 
     let ip = req
         .connection_info()
@@ -83,39 +217,421 @@ async fn login_handler(
         .unwrap_or("0.0.0.0")
         .to_string();
 
-    match rooms {
-        Some(rooms) => {
-            let (token, session_id) = issue_token(&data.login, rooms, &jwt, db.as_ref()).await;
-            info!(
-                "Successful authentication for {:?} from {} at {}",
+    let allowed = throttle
+        .check(
+            db.lock().await.as_ref(),
+            &data.login,
+            &ip,
+            Utc::now().timestamp(),
+        )
+        .await;
+
+    match allowed {
+        Ok(delay) if !delay.is_zero() => rt::time::sleep(delay).await,
+        Ok(_) => {}
+        Err(retry_after) => {
+            error!(
+                "Locked out login for {:?} from {} at {}",
                 data.login,
                 ip,
                 Local::now()
             );
-            db.log_event(&ip, &data.login, &session_id, EventType::SuccessfulLogin)
+            db.lock()
+                .await
+                .log_event(
+                    &ip,
+                    &data.login,
+                    "",
+                    EventType::FailedLogin {
+                        reason: "locked out".to_string(),
+                    },
+                )
                 .await;
-            Ok(token)
+            return Err(Errors::TooManyAttempts { retry_after });
         }
-        None => {
+    }
+
+    let identity = auth.authenticate(&data.login, &data.password).await;
+    let jwt = jwt.lock().await;
+    let db = db.lock().await;
+
+    let identity = match identity {
+        Ok(identity) => identity,
+        Err(e) => {
             error!(
-                "Failed login for {:?} from {} at {}",
+                "Failed login for {:?} from {} at {}: {}",
                 data.login,
                 ip,
+                Local::now(),
+                e
+            );
+
+            // an unavailable directory is nobody's fault
+            if !matches!(e, AuthError::Unavailable(_)) {
+                throttle
+                    .failed(db.as_ref(), &data.login, &ip, Utc::now().timestamp())
+                    .await;
+            }
+
+            db.log_event(
+                &ip,
+                &data.login,
+                "",
+                EventType::FailedLogin {
+                    reason: e.to_string(),
+                },
+            )
+            .await;
+            return Err(e.into());
+        }
+    };
+
+    let response = finish_login(&req, &ip, &data.login, identity, &jwt, db.as_ref(), &totp).await;
+
+    if let Ok(login::LoginResponse::Tokens(_)) = &response {
+        throttle.succeeded(db.as_ref(), &data.login).await;
+    }
+
+    response.map(web::Json)
+}
+
+/// Issues the tokens of an authenticated user, or stops the login at a TOTP challenge when the
+/// user has a second factor or their groups and gates call for one, whatever the first factor.
+async fn finish_login(
+    req: &HttpRequest,
+    ip: &str,
+    username: &str,
+    identity: Identity,
+    jwt: &Jwt,
+    db: &dyn Db,
+    totp: &Totp,
+) -> Result<login::LoginResponse, Errors> {
+    let item = db.load_totp(username).await;
+    let enrolled = item.as_ref().filter(|item| item.confirmed).is_some();
+
+    if !enrolled && !totp.required(&identity) {
+        let (token, _) = start_session(req, ip, username, identity, jwt, db).await;
+
+        return Ok(login::LoginResponse::Tokens(token.into_inner()));
+    }
+
+    info!(
+        "Second factor requested from {:?} at {}",
+        username,
+        Local::now()
+    );
+
+    // the first factor alone does not bind an authenticator: an administrator allows it, and a
+    // login from the offline cache can't be checked against the directory
+    let enroll_allowed = item.map(|item| item.enroll_allowed).unwrap_or(false);
+
+    if !enrolled && (!enroll_allowed || identity.degraded) {
+        error!(
+            "Login for {:?} from {} refused, the second factor is not enrolled",
+            username, ip
+        );
+        db.log_event(
+            ip,
+            username,
+            "",
+            EventType::FailedLogin {
+                reason: "TOTP enrollment not allowed".to_string(),
+            },
+        )
+        .await;
+        return Err(Errors::TotpNotEnrolled);
+    }
+
+    // users allowed to enroll do so on this login
+    let enroll = (!enrolled).then(totp::generate_secret);
+    let enrollment = enroll.as_ref().map(|secret| login::Enrollment {
+        secret: secret.clone(),
+        uri: totp::uri(totp.issuer(), username, secret),
+    });
+
+    Ok(login::LoginResponse::Challenge(login::Challenge {
+        challenge: totp.challenge(username, identity, enroll),
+        enroll: enrollment,
+    }))
+}
+
+#[post("/login/verify")]
+async fn verify_handler(
+    req: HttpRequest,
+    data: web::Json<login::VerifyRequest>,
+    jwt: web::Data<Arc<Mutex<Jwt>>>,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    totp: web::Data<Arc<Totp>>,
+    throttle: web::Data<Arc<Throttle>>,
+) -> Result<web::Json<login::Response>, Errors> {
+    let jwt = jwt.lock().await;
+    let db = db.lock().await;
+
+    let ip = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("0.0.0.0")
+        .to_string();
+
+    let challenge = match totp.take(&data.challenge) {
+        Some(challenge) => challenge,
+        None => {
+            error!("Unknown TOTP challenge from {} at {}", ip, Local::now());
+            db.log_event(
+                &ip,
+                "",
+                "",
+                EventType::FailedTotp {
+                    reason: "unknown or expired challenge".to_string(),
+                },
+            )
+            .await;
+            return Err(Errors::InvalidLogin);
+        }
+    };
+
+    let mut item = match (&challenge.enroll, db.load_totp(&challenge.username).await) {
+        (Some(secret), _) => TotpItem {
+            username: challenge.username.clone(),
+            secret: secret.clone(),
+            confirmed: false,
+            last_step: None,
+            enroll_allowed: false,
+        },
+        (None, Some(item)) => item,
+        (None, None) => return Err(Errors::InvalidLogin),
+    };
+
+    // the codes are guessed as well as the passwords
+    let now = Utc::now().timestamp();
+
+    if let Err(retry_after) = throttle
+        .check(db.as_ref(), &challenge.username, &ip, now)
+        .await
+    {
+        return Err(Errors::TooManyAttempts { retry_after });
+    }
+
+    let step = match totp::verify(&item.secret, &data.code, totp::now_step(), item.last_step) {
+        Some(step) => step,
+        None => {
+            error!(
+                "Wrong TOTP code for {:?} from {} at {}",
+                challenge.username,
+                ip,
                 Local::now()
             );
-            db.log_event(&ip, &data.login, "", EventType::FailedLogin)
+            db.log_event(
+                &ip,
+                &challenge.username,
+                "",
+                EventType::FailedTotp {
+                    reason: "wrong code".to_string(),
+                },
+            )
+            .await;
+            throttle
+                .failed(db.as_ref(), &challenge.username, &ip, now)
+                .await;
+            totp.retry(&data.challenge, challenge);
+            return Err(Errors::InvalidLogin);
+        }
+    };
+
+    item.last_step = Some(step);
+
+    if !item.confirmed {
+        item.confirmed = true;
+        item.enroll_allowed = false;
+        db.log_event(&ip, &challenge.username, "", EventType::TotpEnrolled)
+            .await;
+    }
+
+    db.store_totp(&item).await;
+    throttle.succeeded(db.as_ref(), &challenge.username).await;
+
+    let (token, session_id) = start_session(
+        &req,
+        &ip,
+        &challenge.username,
+        challenge.identity,
+        &jwt,
+        db.as_ref(),
+    )
+    .await;
+
+    db.log_event(
+        &ip,
+        &challenge.username,
+        &session_id,
+        EventType::SuccessfulTotp,
+    )
+    .await;
+
+    Ok(token)
+}
+
+/// Starts the enrollment of a new secret, the second factor is asked for once it is confirmed.
+#[post("/totp/enroll")]
+async fn enroll_handler(
+    jwt: JWTToken,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    totp: web::Data<Arc<Totp>>,
+) -> Result<web::Json<login::Enrollment>, Errors> {
+    let db = db.lock().await;
+
+    // the offline cache only vouches for an older password
+    if jwt.degraded {
+        return Err(Errors::Unauthorized);
+    }
+
+    // a confirmed secret is only replaced through `totp allow`
+    let enroll_allowed = match db.load_totp(&jwt.username).await {
+        Some(item) if item.confirmed => return Err(Errors::Unauthorized),
+        Some(item) => item.enroll_allowed,
+        None => false,
+    };
+
+    let secret = totp::generate_secret();
+
+    // the `totp allow` mark stays until a secret is confirmed
+    db.store_totp(&TotpItem {
+        username: jwt.username.clone(),
+        secret: secret.clone(),
+        confirmed: false,
+        last_step: None,
+        enroll_allowed,
+    })
+    .await;
+
+    Ok(web::Json(login::Enrollment {
+        uri: totp::uri(totp.issuer(), &jwt.username, &secret),
+        secret,
+    }))
+}
+
+#[post("/totp/confirm")]
+async fn confirm_handler(
+    req: HttpRequest,
+    data: web::Json<login::ConfirmRequest>,
+    jwt: JWTToken,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+) -> Result<web::Json<login::ConfirmResponse>, Errors> {
+    let db = db.lock().await;
+
+    let ip = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("0.0.0.0")
+        .to_string();
+
+    let mut item = match db.load_totp(&jwt.username).await {
+        // an empty secret is the `totp allow` mark, its codes are known to anyone
+        Some(item) if !item.confirmed && !item.secret.is_empty() => item,
+        _ => return Err(Errors::Unauthorized),
+    };
+
+    match totp::verify(&item.secret, &data.code, totp::now_step(), None) {
+        Some(step) => {
+            item.confirmed = true;
+            item.enroll_allowed = false;
+            item.last_step = Some(step);
+            db.store_totp(&item).await;
+
+            info!("TOTP enrolled for {:?} from {}", jwt.username, ip);
+            db.log_event(&ip, &jwt.username, &jwt.session_id, EventType::TotpEnrolled)
                 .await;
+            Ok(web::Json(login::ConfirmResponse { success: true }))
+        }
+        None => {
+            db.log_event(
+                &ip,
+                &jwt.username,
+                &jwt.session_id,
+                EventType::FailedTotp {
+                    reason: "wrong enrollment code".to_string(),
+                },
+            )
+            .await;
             Err(Errors::InvalidLogin)
         }
     }
 }
 
+#[get("/oidc/start")]
+async fn oidc_start_handler(oidc: web::Data<Arc<Oidc>>) -> Result<HttpResponse, Errors> {
+    match oidc.start().await {
+        Ok((url, cookie)) => Ok(HttpResponse::Found()
+            .insert_header((header::LOCATION, url))
+            .cookie(cookie)
+            .finish()),
+        Err(e) => {
+            error!("Failed to start an OIDC login: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+#[get("/oidc/callback")]
+async fn oidc_callback_handler(
+    req: HttpRequest,
+    query: web::Query<login::OidcCallback>,
+    jwt: web::Data<Arc<Mutex<Jwt>>>,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    oidc: web::Data<Arc<Oidc>>,
+    totp: web::Data<Arc<Totp>>,
+) -> Result<HttpResponse, Errors> {
+    let ip = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("0.0.0.0")
+        .to_string();
+    let browser = req.cookie(BROWSER_COOKIE);
+
+    let result = match (&query.code, &query.error) {
+        (Some(code), None) => {
+            oidc.callback(&query.state, code, browser.as_ref().map(Cookie::value))
+                .await
+        }
+        (_, error) => Err(OidcError::Denied(
+            error.clone().unwrap_or_else(|| "no code".to_string()),
+        )),
+    };
+    let jwt = jwt.lock().await;
+    let db = db.lock().await;
+
+    match result {
+        Ok((username, identity)) => {
+            let mut used = Cookie::named(BROWSER_COOKIE);
+            let response =
+                finish_login(&req, &ip, &username, identity, &jwt, db.as_ref(), &totp).await?;
+
+            used.make_removal();
+            Ok(HttpResponse::Ok().cookie(used).json(response))
+        }
+        Err(e) => {
+            error!("Failed OIDC login from {} at {}: {}", ip, Local::now(), e);
+            db.log_event(
+                &ip,
+                "",
+                "",
+                EventType::FailedLogin {
+                    reason: format!("oidc: {}", e),
+                },
+            )
+            .await;
+            Err(e.into())
+        }
+    }
+}
+
 #[post("/refresh")]
 async fn refresh_handler(
     req: HttpRequest,
     data: web::Json<login::RefreshRequest>,
     jwt: web::Data<Arc<Mutex<Jwt>>>,
     db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    revocations: web::Data<Arc<Revocations>>,
 ) -> actix_web::Result<web::Json<login::Response>> {
     let jwt = jwt.lock().await;
     let db = db.lock().await;
@@ -126,17 +642,98 @@ async fn refresh_handler(
         .unwrap_or("0.0.0.0")
         .to_string();
 
-    let user = match db.remove_by_refresh_token(&data.refresh_token).await {
+    let token_hash = jwt.refresh_token_hash(&data.refresh_token);
+    let user = match db.use_refresh_token(&token_hash).await {
         Some(user) => user,
         None => {
-            error!("user with token {:?} not found", data.refresh_token);
-            db.log_event(&ip, "", &data.refresh_token, EventType::FailedRefresh)
+            error!("user with token {:?} not found", token_hash);
+            db.log_event(&ip, "", &token_hash, EventType::FailedRefresh)
                 .await;
             return Err(error::ErrorNotFound("Not Found"));
         }
     };
 
-    let (token, session_id) = issue_token(&user.username, user.rooms, &jwt, db.as_ref()).await;
+    // a rotated token is replayed, either by its owner or by whoever stole it: the session
+    // (the token family) ends for both
+    if user.used {
+        error!(
+            "reuse of a rotated refresh token of {:?} from {}, session {:?} revoked",
+            user.username, ip, user.session_id
+        );
+        if user.session_id.is_empty() {
+            // rotated before its session was recorded on it: every session of the user may
+            // descend from it
+            revocations.revoke_user(db.as_ref(), &user.username).await;
+        } else {
+            // the session record may be gone already, its tokens go regardless
+            db.remove_session(&user.username, &user.session_id).await;
+            db.remove_refresh_tokens(&user.session_id).await;
+            revocations.revoke(db.as_ref(), &user.session_id).await;
+        }
+        db.log_event(
+            &ip,
+            &user.username,
+            &user.session_id,
+            EventType::RefreshTokenReuse,
+        )
+        .await;
+        return Err(error::ErrorNotFound("Not Found"));
+    }
+
+    let session = db
+        .sessions(&user.username)
+        .await
+        .into_iter()
+        .find(|session| session.session_id == user.session_id);
+
+    // the session expired meanwhile: its token family goes with it
+    if session.is_none() && !user.session_id.is_empty() {
+        error!(
+            "refresh of the expired session {:?} of {:?} from {}",
+            user.session_id, user.username, ip
+        );
+        db.remove_refresh_tokens(&user.session_id).await;
+        db.log_event(
+            &ip,
+            &user.username,
+            &user.session_id,
+            EventType::FailedRefresh,
+        )
+        .await;
+        return Err(error::ErrorNotFound("Not Found"));
+    }
+
+    // tokens issued before the sessions were kept start one
+    let (token, session_id) = issue_token(
+        Some(user.session_id.as_str()).filter(|session_id| !session_id.is_empty()),
+        &user.username,
+        user.rooms,
+        user.degraded,
+        &jwt,
+        db.as_ref(),
+    )
+    .await;
+    if user.session_id.is_empty() {
+        db.attach_refresh_token(&token_hash, &session_id).await;
+    }
+    db.prune_used_tokens(&session_id, USED_TOKENS_KEPT).await;
+
+    let now = DateTime::now();
+    let session = session.unwrap_or_else(|| SessionItem {
+        session_id: session_id.clone(),
+        username: user.username.clone(),
+        user_agent: user_agent(&req),
+        ip: ip.clone(),
+        created: now,
+        last_refresh: now,
+    });
+
+    db.store_session(&SessionItem {
+        ip: ip.clone(),
+        last_refresh: now,
+        ..session
+    })
+    .await;
 
     info!(
         "Successful re-authentication for {:?} from {} at {}",
@@ -159,23 +756,48 @@ async fn refresh_handler(
 async fn logout_handler(
     jwt: JWTToken,
     db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    revocations: web::Data<Arc<Revocations>>,
 ) -> Result<web::Json<logout::Response>, Errors> {
     let db = db.lock().await;
 
-    db.remove_by_username(&jwt.username).await;
+    // every device is logged out, the current one may predate the sessions
+    revocations.revoke(db.as_ref(), &jwt.session_id).await;
+    revocations.revoke_user(db.as_ref(), &jwt.username).await;
     Ok(web::Json(logout::Response { success: true }))
 }
 
-#[post("/open/{gate}")]
-async fn open_handler(
-    req: HttpRequest,
-    gate: web::Path<(String,)>,
-    config: web::Data<Arc<Mutex<Config>>>,
+#[get("/sessions")]
+async fn sessions_handler(
+    jwt: JWTToken,
     db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+) -> Result<web::Json<sessions::Response>, Errors> {
+    let sessions = db.lock().await.sessions(&jwt.username).await;
+
+    Ok(web::Json(sessions::Response {
+        sessions: sessions
+            .into_iter()
+            .map(|session| sessions::Session {
+                current: session.session_id == jwt.session_id,
+                id: session.session_id,
+                user_agent: session.user_agent,
+                ip: session.ip,
+                created: session.created.timestamp_millis() / 1000,
+                last_refresh: session.last_refresh.timestamp_millis() / 1000,
+            })
+            .collect(),
+    }))
+}
+
+/// Ends a session of the user, the other devices stay logged in.
+#[delete("/sessions/{id}")]
+async fn revoke_session_handler(
+    req: HttpRequest,
+    id: web::Path<(String,)>,
     jwt: JWTToken,
-) -> Result<web::Json<open::Response>, Errors> {
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    revocations: web::Data<Arc<Revocations>>,
+) -> actix_web::Result<web::Json<logout::Response>> {
     let db = db.lock().await;
-    let config = config.lock().await;
 
     let ip = req
         .connection_info()
@@ -183,100 +805,458 @@ async fn open_handler(
         .unwrap_or("0.0.0.0")
         .to_string();
 
-    let current_gate = jwt.available_rooms.iter().find(|g| gate.0 == g.name);
+    if !db.remove_session(&jwt.username, &id.0).await {
+        return Err(error::ErrorNotFound("Not Found"));
+    }
+
+    revocations.revoke(db.as_ref(), &id.0).await;
+    info!(
+        "Session {} of {:?} revoked from {} at {}",
+        id.0,
+        jwt.username,
+        ip,
+        Local::now()
+    );
+    db.log_event(&ip, &jwt.username, &id.0, EventType::SessionRevoked)
+        .await;
+
+    Ok(web::Json(logout::Response { success: true }))
+}
+
+/// Runs the command on the gate if the token grants it, `control` commands need a
+/// `gate_control` grant.
+async fn execute_command(
+    req: HttpRequest,
+    gate: &str,
+    command: Command,
+    db: &Mutex<Box<dyn Db + Send>>,
+    gate_driver: &(dyn GateDriver + Send + Sync),
+    jwt: JWTToken,
+) -> Result<web::Json<open::Response>, Errors> {
+    let ip = req
+        .connection_info()
+        .realip_remote_addr()
+        .unwrap_or("0.0.0.0")
+        .to_string();
+
+    let current_gate = jwt
+        .available_rooms
+        .iter()
+        .find(|g| gate == g.name && (command == Command::Open || g.control));
 
     if let Some(current_gate) = current_gate {
-        let success = if config.dry_run {
-            debug!(
-                "emulate open gate {} with {} retries",
-                current_gate.id, current_gate.retries
-            );
-            true
-        } else {
-            services::gate::open(&config.gate_server, current_gate.id, current_gate.retries)
-                .await
-                .is_ok()
+        // the database stays unlocked while the controller confirms the command
+        let result = match gate_driver.execute(current_gate, command).await {
+            Ok(result) => result,
+            Err(e) => {
+                error!(
+                    "Failed to {} gate {} for {:?} from {} at {}: {}",
+                    command,
+                    gate,
+                    jwt.username,
+                    ip,
+                    Local::now(),
+                    e
+                );
+                db.lock()
+                    .await
+                    .log_event(
+                        &ip,
+                        &jwt.username,
+                        &jwt.session_id,
+                        EventType::FailedGateAccess {
+                            gate: gate.to_string(),
+                            reason: format!("{}: {}", command, e),
+                        },
+                    )
+                    .await;
+                return Err(e.into());
+            }
         };
         info!(
-            "Successful access to gate {} for {:?} from {} at {}",
-            gate.0,
+            "Successful {} of gate {} for {:?} from {} at {}",
+            command,
+            gate,
             jwt.username,
             ip,
             Local::now()
         );
-        db.log_event(
-            &ip,
-            &jwt.username,
-            &jwt.session_id,
-            EventType::SuccessfulGateAccess {
-                gate: gate.0.clone(),
-            },
-        )
-        .await;
-        Ok(web::Json(open::Response { success }))
+
+        let gate = gate.to_string();
+        let attempts = result.attempts;
+        let event = match command {
+            Command::Open => EventType::SuccessfulGateAccess { gate, attempts },
+            Command::Close => EventType::GateClose { gate, attempts },
+            Command::Hold => EventType::GateHold { gate, attempts },
+            Command::Release => EventType::GateRelease { gate, attempts },
+        };
+
+        db.lock()
+            .await
+            .log_event(&ip, &jwt.username, &jwt.session_id, event)
+            .await;
+        Ok(web::Json(open::Response {
+            success: true,
+            result,
+        }))
     } else {
         error!(
-            "Unauthorized access to gate {} for {:?} from {} at {}",
-            gate.0,
+            "Unauthorized {} of gate {} for {:?} from {} at {}",
+            command,
+            gate,
             jwt.username,
             ip,
             Local::now()
         );
-        db.log_event(
-            &ip,
-            &jwt.username,
-            &jwt.session_id,
-            EventType::UnauthorizedGateAccess {
-                gate: gate.0.clone(),
-            },
-        )
-        .await;
+
+        let gate = gate.to_string();
+        let event = match command {
+            Command::Open => EventType::UnauthorizedGateAccess { gate },
+            _ => EventType::UnauthorizedGateControl { gate },
+        };
+
+        db.lock()
+            .await
+            .log_event(&ip, &jwt.username, &jwt.session_id, event)
+            .await;
         Err(Errors::Unauthorized)
     }
 }
 
+#[post("/open/{gate}")]
+async fn open_handler(
+    req: HttpRequest,
+    gate: web::Path<(String,)>,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    gate_driver: web::Data<Arc<dyn GateDriver + Send + Sync>>,
+    jwt: JWTToken,
+) -> Result<web::Json<open::Response>, Errors> {
+    execute_command(
+        req,
+        &gate.0,
+        Command::Open,
+        &db,
+        gate_driver.as_ref().as_ref(),
+        jwt,
+    )
+    .await
+}
+
+#[post("/{gate}/close")]
+async fn close_handler(
+    req: HttpRequest,
+    gate: web::Path<(String,)>,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    gate_driver: web::Data<Arc<dyn GateDriver + Send + Sync>>,
+    jwt: JWTToken,
+) -> Result<web::Json<open::Response>, Errors> {
+    execute_command(
+        req,
+        &gate.0,
+        Command::Close,
+        &db,
+        gate_driver.as_ref().as_ref(),
+        jwt,
+    )
+    .await
+}
+
+#[post("/{gate}/hold")]
+async fn hold_handler(
+    req: HttpRequest,
+    gate: web::Path<(String,)>,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    gate_driver: web::Data<Arc<dyn GateDriver + Send + Sync>>,
+    jwt: JWTToken,
+) -> Result<web::Json<open::Response>, Errors> {
+    execute_command(
+        req,
+        &gate.0,
+        Command::Hold,
+        &db,
+        gate_driver.as_ref().as_ref(),
+        jwt,
+    )
+    .await
+}
+
+#[post("/{gate}/release")]
+async fn release_handler(
+    req: HttpRequest,
+    gate: web::Path<(String,)>,
+    db: web::Data<Arc<Mutex<Box<dyn Db + Send>>>>,
+    gate_driver: web::Data<Arc<dyn GateDriver + Send + Sync>>,
+    jwt: JWTToken,
+) -> Result<web::Json<open::Response>, Errors> {
+    execute_command(
+        req,
+        &gate.0,
+        Command::Release,
+        &db,
+        gate_driver.as_ref().as_ref(),
+        jwt,
+    )
+    .await
+}
+
+/// Receives the `MethodNameForAnswer` calls of the gate controllers.
+async fn callback_handler(
+    req: HttpRequest,
+    body: String,
+    callbacks: web::Data<Arc<PendingResults>>,
+) -> HttpResponse {
+    // the peer itself, a forwarded address is up to the caller
+    let ip = match req.peer_addr() {
+        Some(peer) if callbacks.allows(peer.ip()) => peer.ip(),
+        peer => {
+            warn!("Callback from {:?} refused, not a controller", peer);
+            return HttpResponse::Forbidden().finish();
+        }
+    };
+
+    let response = match rpc::parse_call(&body) {
+        Ok((method, params)) => match callbacks.accept(ip, &method, &params) {
+            Ok(()) => rpc::write_response(&Value::Boolean(true)),
+            Err((code, message)) => {
+                error!(
+                    "Invalid callback {:?} from the controller: {}",
+                    method, message
+                );
+                rpc::write_fault(code, &message)
+            }
+        },
+        Err(e) => {
+            error!("Malformed callback from the controller: {}", e);
+            rpc::write_fault(-32700, &e.to_string())
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/xml")
+        .body(response.to_string())
+}
+
+#[get("/{gate}/status")]
+async fn status_handler(
+    gate: web::Path<(String,)>,
+    jwt: JWTToken,
+    gate_driver: web::Data<Arc<dyn GateDriver + Send + Sync>>,
+    monitor: web::Data<Arc<GateMonitor>>,
+) -> Result<web::Json<status::Response>, Errors> {
+    let gate = jwt
+        .available_rooms
+        .into_iter()
+        .find(|g| g.name == gate.0)
+        .ok_or(Errors::Unauthorized)?;
+
+    let cached = match monitor.get(&gate.name) {
+        Some(cached) => cached,
+        None => monitor.check(gate_driver.as_ref().as_ref(), &gate).await,
+    };
+
+    Ok(web::Json(status::Response {
+        gate: gate.name,
+        status: cached.status,
+        checked_at: cached.checked_at,
+    }))
+}
+
 #[get("/list")]
-async fn gates_handler(jwt: JWTToken) -> Result<web::Json<gates::Response>, Errors> {
+async fn gates_handler(
+    jwt: JWTToken,
+    monitor: web::Data<Arc<GateMonitor>>,
+) -> Result<web::Json<gates::Response>, Errors> {
     Ok(web::Json(gates::Response {
-        gates: jwt.available_rooms,
+        gates: jwt
+            .available_rooms
+            .into_iter()
+            .map(|gate| gates::GateInfo {
+                online: monitor.is_online(&gate.name),
+                gate,
+            })
+            .collect(),
     }))
 }
 
+#[get("/health")]
+async fn health_handler(breaker: web::Data<Arc<CircuitBreaker>>) -> web::Json<health::Response> {
+    web::Json(health::Response {
+        healthy: breaker.healthy(),
+    })
+}
+
+/// The circuits of the gates of the user, which the public `/health` leaves out.
+#[get("/health")]
+async fn gates_health_handler(
+    jwt: JWTToken,
+    breaker: web::Data<Arc<CircuitBreaker>>,
+) -> web::Json<health::Details> {
+    web::Json(breaker.health(&jwt.available_rooms))
+}
+
+/// Public keys of the tokens for other services.
+#[get("/.well-known/jwks.json")]
+async fn jwks_handler(jwt: web::Data<Arc<Mutex<Jwt>>>) -> web::Json<serde_json::Value> {
+    web::Json(jwt.lock().await.jwks())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let config = Config::new();
+    let args = cli::parse();
+    let config = Config::new(args.config_file.as_deref());
+
+    if let Some(command) = args.command {
+        if let Err(e) = cli::run(&config, command).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
     flexi_logger::Logger::try_with_env_or_str(&config.log_level)
         .expect("logger")
         .start()
         .expect("logger");
 
-    let jwt = Arc::new(Mutex::new(Jwt::new(config.jwt_key.clone())));
-    let mongo_db: Box<dyn Db + Send> = Box::new(MongoDb::new(&config.mongo_uri).await);
+    let jwt = match Jwt::from_config(&config) {
+        Ok(jwt) => jwt,
+        Err(e) => {
+            eprintln!("Invalid token keys: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mongo_db = MongoDb::new(&config.mongo_uri).await;
+
+    // the plain tokens are hashed once, with this key for good
+    if let Err(e) = mongo_db
+        .pin_refresh_token_key(&jwt.refresh_key_check())
+        .await
+    {
+        eprintln!(
+            "{}: put the old key back, or log everyone out by deleting the refresh_tokens \
+             collection and the refresh_token_key document of settings",
+            e
+        );
+        std::process::exit(1);
+    }
+
+    let hashed = mongo_db
+        .migrate_refresh_tokens(|refresh_token| jwt.refresh_token_hash(refresh_token))
+        .await;
+
+    if hashed > 0 {
+        info!("{} stored refresh tokens are hashed now", hashed);
+    }
+
+    let jwt = Arc::new(Mutex::new(jwt));
+    let mongo_db: Box<dyn Db + Send> = Box::new(mongo_db);
     let db = Arc::new(Mutex::new(mongo_db));
-    let auth = LDAPAuth::new(
-        config.ldap.server.clone(),
-        config.ldap.base.clone(),
-        config.ldap.bind.clone(),
-        config.ldap.filter.clone(),
-        config.get_mappings(),
-    );
-    let auth: Box<dyn Auth + Send> = Box::new(auth);
-    let auth = Arc::new(Mutex::new(auth));
+    let providers = config
+        .auth
+        .iter()
+        .map(|backend| -> Arc<dyn Auth + Send + Sync> {
+            match (backend, &config.local_users) {
+                (AuthBackend::Local, Some(local_users)) => {
+                    Arc::new(LocalAuth::new(local_users, config.get_mappings()))
+                }
+                _ if config.ldap.offline_max_age_secs > 0 => Arc::new(CachedAuth::new(
+                    Arc::new(LDAPAuth::new(&config.ldap, config.get_mappings())),
+                    db.clone(),
+                    config.get_mappings(),
+                    config.ldap.offline_max_age_secs,
+                )),
+                _ => Arc::new(LDAPAuth::new(&config.ldap, config.get_mappings())),
+            }
+        })
+        .collect();
+    let auth: Arc<dyn Auth + Send + Sync> = Arc::new(ChainAuth::new(providers));
+    let callbacks = PendingResults::from_config(&config).map(Arc::new);
+    let oidc = config
+        .oidc
+        .as_ref()
+        .map(|oidc| Arc::new(Oidc::new(oidc, config.get_mappings())));
+    let totp = Arc::new(Totp::new(&config.totp));
+    let throttle = Arc::new(Throttle::new(&config.throttle));
+    let revocations = Arc::new(Revocations::new());
+    let breaker = Arc::new(CircuitBreaker::new(
+        Arc::new(GateDrivers::from_config(&config, callbacks.clone())),
+        &config.breaker,
+        db.clone(),
+    ));
+
+    if config.breaker.failure_threshold > 0 {
+        breaker.clone().spawn(std::time::Duration::from_secs(
+            config.breaker.probe_interval_secs,
+        ));
+    }
+
+    let gate_driver: Arc<dyn GateDriver + Send + Sync> = breaker.clone();
+    let monitor = Arc::new(GateMonitor::from_config(&config));
+
+    if config.status_poll_interval_secs > 0 {
+        monitor.clone().spawn(
+            gate_driver.clone(),
+            std::time::Duration::from_secs(config.status_poll_interval_secs),
+        );
+    }
+
     let listen_addr = config.listen_addr.clone();
+    let callback_addr = config
+        .callback
+        .as_ref()
+        .map(|callback| callback.listen_addr.clone());
+    let callback_server = match (callbacks, callback_addr) {
+        (Some(callbacks), Some(callback_addr)) => Some(
+            HttpServer::new(move || {
+                let callbacks = callbacks.clone();
+                App::new()
+                    .wrap(Logger::default())
+                    .configure(move |cfg| configure_callbacks(cfg, callbacks))
+            })
+            .bind(&callback_addr)?
+            .run(),
+        ),
+        _ => None,
+    };
     let config = Arc::new(Mutex::new(config));
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let jwt = jwt.clone();
         let db = db.clone();
         let auth = auth.clone();
         let config = config.clone();
-        App::new()
-            .wrap(Logger::default())
-            .configure(move |cfg| configure_app(cfg, auth, db, config, jwt))
+        let gate_driver = gate_driver.clone();
+        let oidc = oidc.clone();
+        let totp = totp.clone();
+        let throttle = throttle.clone();
+        let revocations = revocations.clone();
+        let monitor = monitor.clone();
+        let breaker = breaker.clone();
+        App::new().wrap(Logger::default()).configure(move |cfg| {
+            configure_app(
+                cfg,
+                auth,
+                db,
+                config,
+                jwt,
+                gate_driver,
+                oidc,
+                totp,
+                throttle,
+                revocations,
+                monitor,
+                breaker,
+            )
+        })
     })
     .bind(&listen_addr)?
-    .run()
-    .await
+    .run();
+
+    match callback_server {
+        Some(callback_server) => futures::try_join!(server, callback_server).map(|_| ()),
+        None => server.await,
+    }
 }
 
 #[cfg(test)]
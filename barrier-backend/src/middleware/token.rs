@@ -6,7 +6,11 @@ use actix_web::{
 };
 use futures::{future, Future, FutureExt};
 
-use crate::services::jwt::{JWTToken, Jwt};
+use crate::services::{
+    db::Db,
+    jwt::{JWTToken, Jwt},
+    revocation::Revocations,
+};
 
 impl FromRequest for JWTToken {
     type Error = InternalError<&'static str>;
@@ -82,15 +86,37 @@ impl FromRequest for JWTToken {
             .left_future();
         }
 
+        // without the revocation list an ended session could not be told apart
+        let revocations = req.app_data::<web::Data<Arc<Revocations>>>();
+        let db = req.app_data::<web::Data<Arc<Mutex<Box<dyn Db + Send>>>>>();
+        if revocations.is_none() || db.is_none() {
+            return future::err(InternalError::new(
+                "internal error",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+            .left_future();
+        }
+
         // clone these so that we can return an impl Future + 'static
         let token_id = maybe_token_id.unwrap().to_owned();
         let jwt = jwt.unwrap().clone();
+        let revocations = revocations.unwrap().clone();
+        let db = db.unwrap().clone();
 
         async move {
-            jwt.lock()
-                .await
-                .verify_token(token_id)
-                .ok_or_else(|| InternalError::new("invalid Bearer token", StatusCode::UNAUTHORIZED))
+            let token = jwt.lock().await.verify_token(token_id).ok_or_else(|| {
+                InternalError::new("invalid Bearer token", StatusCode::UNAUTHORIZED)
+            })?;
+
+            // the session may have ended before the token expires
+            if revocations.is_revoked(&db, &token.session_id).await {
+                return Err(InternalError::new(
+                    "revoked Bearer token",
+                    StatusCode::UNAUTHORIZED,
+                ));
+            }
+
+            Ok(token)
         }
         .boxed_local()
         .right_future()
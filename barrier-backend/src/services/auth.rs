@@ -1,68 +1,366 @@
-use crate::structs::Gate;
-use std::{collections::HashMap, ptr};
+use crate::{
+    config::{GroupStrategy, Ldap, LdapSearch, LocalUsers, RequireCert},
+    services::{
+        db::{CachedLogin, Db},
+        users::{self, Users},
+    },
+    structs::{Errors, Gate},
+};
+use actix_web::rt::task;
+use chrono::Utc;
+use derive_more::Display;
+use log::{debug, error, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    ptr,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
 
+/// A successful login.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    /// Provider which accepted the login, recorded in the audit log.
+    pub provider: String,
+    pub gates: Vec<Gate>,
+    /// Groups of the user, checked against `totp.groups`.
+    pub groups: Vec<String>,
+    /// Accepted from the offline login cache while the provider was down.
+    pub degraded: bool,
+}
+
+impl Identity {
+    pub fn new(
+        provider: &str,
+        gate_mappings: &HashMap<String, Vec<Gate>>,
+        mut groups: Vec<String>,
+    ) -> Self {
+        groups.sort();
+        groups.dedup();
+
+        Identity {
+            provider: provider.to_string(),
+            gates: map_groups(gate_mappings, groups.iter().cloned()),
+            groups,
+            degraded: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Display, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AuthError {
+    #[display(fmt = "unknown user")]
+    UnknownUser,
+    #[display(fmt = "invalid credentials")]
+    InvalidCredentials,
+    #[display(fmt = "provider is unavailable: {}", _0)]
+    Unavailable(String),
+}
+
+impl From<AuthError> for Errors {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::Unavailable(_) => Errors::AuthUnavailable,
+            _ => Errors::InvalidLogin,
+        }
+    }
+}
+
+#[async_trait::async_trait]
 pub trait Auth {
-    fn get_available_rooms(&self, username: &str, password: &str) -> Option<Vec<Gate>>;
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Identity, AuthError>;
+}
+
+/// Runs a blocking lookup on the blocking pool, away from the actix workers.
+async fn blocking<F>(
+    provider: &str,
+    gate_mappings: &HashMap<String, Vec<Gate>>,
+    lookup: F,
+) -> Result<Identity, AuthError>
+where
+    F: FnOnce() -> Result<Vec<String>, AuthError> + Send + 'static,
+{
+    match task::spawn_blocking(lookup).await {
+        Ok(result) => result.map(|groups| Identity::new(provider, gate_mappings, groups)),
+        Err(e) => Err(AuthError::Unavailable(format!(
+            "{} lookup failed: {}",
+            provider, e
+        ))),
+    }
 }
 
+#[derive(Clone)]
 pub struct LDAPAuth {
-    ldap_server: String,
-    ldap_base: String,
-    ldap_bind: String,
-    ldap_filter: Option<String>,
-    gate_mappings: HashMap<String, Vec<Gate>>,
+    config: Arc<Ldap>,
+    gate_mappings: Arc<HashMap<String, Vec<Gate>>>,
+}
+
+/// Gates of the groups, a gate granted by several groups keeps the widest access.
+fn map_groups(
+    gate_mappings: &HashMap<String, Vec<Gate>>,
+    groups: impl Iterator<Item = String>,
+) -> Vec<Gate> {
+    merge_gates(
+        groups
+            .filter_map(|group| gate_mappings.get(&group).cloned())
+            .flat_map(|gates| gates.into_iter())
+            .collect(),
+    )
+}
+
+/// Sorts the gates by name, a gate listed several times keeps the widest access.
+fn merge_gates(mut gates: Vec<Gate>) -> Vec<Gate> {
+    gates.sort_by(|a, b| a.name.cmp(&b.name));
+    gates.dedup_by(|a, b| {
+        if a.name == b.name {
+            b.control |= a.control;
+        }
+
+        a.name == b.name
+    });
+
+    gates
+}
+
+const MEMBER_OF: &str = "memberOf";
+const LDAP_NO_SUCH_OBJECT: i32 = 32;
+const LDAP_INVALID_CREDENTIALS: i32 = 49;
+/// `ldap_err2string` of `LDAP_NO_SUCH_OBJECT`, the only way a failed search tells it.
+const NO_SUCH_OBJECT: &str = "No such object";
+
+#[derive(Clone, Debug, PartialEq)]
+struct Group {
+    dn: String,
+    /// Values of `group_attribute`, the keys of the gate mappings.
+    names: Vec<String>,
+    /// Parent groups, read only for the `memberOf` strategy.
+    member_of: Vec<String>,
+}
+
+/// Removes an attribute from a search entry, servers may change the case of its name.
+fn take(entry: &mut HashMap<String, Vec<String>>, attribute: &str) -> Vec<String> {
+    let key = entry
+        .keys()
+        .find(|key| key.eq_ignore_ascii_case(attribute))
+        .cloned();
+
+    key.and_then(|key| entry.remove(&key)).unwrap_or_default()
+}
+
+/// Adds the parent groups of `direct`, level by level, up to `depth` levels. Each group is
+/// taken once, so loops in the directory end early.
+fn nest<F>(direct: Vec<Group>, depth: u32, mut parents: F) -> Vec<Group>
+where
+    F: FnMut(&[Group]) -> Vec<Group>,
+{
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut level: Vec<Group> = direct
+        .into_iter()
+        .filter(|group| seen.insert(group.dn.to_lowercase()))
+        .collect();
+    let mut groups = level.clone();
+
+    for _ in 0..depth {
+        level = parents(&level)
+            .into_iter()
+            .filter(|group| seen.insert(group.dn.to_lowercase()))
+            .collect();
+
+        if level.is_empty() {
+            break;
+        }
+
+        groups.extend(level.iter().cloned());
+    }
+
+    groups
+}
+
+/// Escapes a value substituted into a search filter (RFC 4515).
+fn escape_filter(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '\\' => "\\5c".to_string(),
+            '*' => "\\2a".to_string(),
+            '(' => "\\28".to_string(),
+            ')' => "\\29".to_string(),
+            '\0' => "\\00".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Escapes a value substituted into a DN (RFC 4514).
+fn escape_dn(value: &str) -> String {
+    let mut escaped = String::new();
+    let last = value.chars().count().saturating_sub(1);
+
+    for (i, c) in value.chars().enumerate() {
+        let edge = i == 0 || i == last;
+
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if edge => escaped.push('\\'),
+            _ => {}
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
 }
 
 impl LDAPAuth {
-    pub fn new(
-        ldap_server: String,
-        ldap_base: String,
-        ldap_bind: String,
-        ldap_filter: Option<String>,
-        gate_mappings: HashMap<String, Vec<Gate>>,
-    ) -> Self {
+    pub fn new(config: &Ldap, gate_mappings: HashMap<String, Vec<Gate>>) -> Self {
         LDAPAuth {
-            ldap_server,
-            ldap_base,
-            ldap_bind,
-            ldap_filter,
-            gate_mappings,
+            config: Arc::new(config.clone()),
+            gate_mappings: Arc::new(gate_mappings),
         }
     }
-}
 
-impl Auth for LDAPAuth {
-    fn get_available_rooms(&self, username: &str, password: &str) -> Option<Vec<Gate>> {
-        let ldap = openldap::RustLDAP::new(&self.ldap_server).ok()?;
+    fn connect(&self) -> Result<openldap::RustLDAP, AuthError> {
+        use openldap::codes::options::*;
+
+        let config = &self.config;
+        let ldap = openldap::RustLDAP::new(&config.server).map_err(|e| {
+            AuthError::Unavailable(format!("can't connect to {}: {:?}", config.server, e))
+        })?;
 
         ldap.set_option(
-            openldap::codes::options::LDAP_OPT_PROTOCOL_VERSION,
+            LDAP_OPT_PROTOCOL_VERSION,
             &openldap::codes::versions::LDAP_VERSION3,
         );
 
-        ldap.set_option(
-            openldap::codes::options::LDAP_OPT_X_TLS_REQUIRE_CERT,
-            &openldap::codes::options::LDAP_OPT_X_TLS_DEMAND,
-        );
+        let require_cert = match config.require_cert {
+            RequireCert::Never => LDAP_OPT_X_TLS_NEVER,
+            RequireCert::Allow => LDAP_OPT_X_TLS_ALLOW,
+            RequireCert::Try => LDAP_OPT_X_TLS_TRY,
+            RequireCert::Demand => LDAP_OPT_X_TLS_DEMAND,
+            RequireCert::Hard => LDAP_OPT_X_TLS_HARD,
+        };
+        let files = [
+            (LDAP_OPT_X_TLS_CACERTFILE, &config.ca_file),
+            (LDAP_OPT_X_TLS_CERTFILE, &config.cert_file),
+            (LDAP_OPT_X_TLS_KEYFILE, &config.key_file),
+        ];
 
-        let bind_dn = self.ldap_bind.replace("%(username)", username);
+        ldap.set_option(LDAP_OPT_X_TLS_REQUIRE_CERT, &require_cert);
 
-        if ldap.simple_bind(&bind_dn, password).ok()? != 0 {
-            return None;
+        for (option, file) in files {
+            if let Some(file) = file {
+                if !ldap.set_option(option, file.as_str()) {
+                    return Err(AuthError::Unavailable(format!(
+                        "failed to set TLS file {:?}",
+                        file
+                    )));
+                }
+            }
         }
 
-        let ldap_filter = self
-            .ldap_filter
-            .clone()
-            .map(|f| f.replace("%(username)", username));
+        // apply the TLS options above to this connection
+        ldap.set_option(LDAP_OPT_X_TLS_NEWCTX, &0);
 
-        // Returns a LDAPResponse, a.k.a. Vec<HashMap<String,Vec<String>>>.
-        let responses = ldap
+        if config.starttls {
+            match ldap.start_tls(None, None) {
+                Ok(0) => {}
+                result => {
+                    return Err(AuthError::Unavailable(format!(
+                        "StartTLS failed: {:?}",
+                        result
+                    )))
+                }
+            }
+        }
+
+        Ok(ldap)
+    }
+
+    /// Finds the entry of the user with the service account, the user is unknown unless exactly
+    /// one entry matches.
+    fn find_user(
+        &self,
+        ldap: &openldap::RustLDAP,
+        search: &LdapSearch,
+        username: &str,
+    ) -> Result<String, AuthError> {
+        match ldap.simple_bind(&search.bind_dn, &search.bind_password) {
+            Ok(0) => {}
+            result => {
+                error!("LDAP service account {:?} failed to bind", search.bind_dn);
+                return Err(AuthError::Unavailable(format!(
+                    "service account bind failed: {:?}",
+                    result
+                )));
+            }
+        }
+
+        let filter = search
+            .filter
+            .replace("%(username)", &escape_filter(username));
+        let mut responses = ldap
             .ldap_search(
-                &self.ldap_base,
+                search.base.as_deref().unwrap_or(&self.config.base),
                 openldap::codes::scopes::LDAP_SCOPE_SUBTREE,
-                ldap_filter.as_deref(),
+                Some(&filter),
+                // no attributes, only the DN
+                Some(vec!["1.1"]),
+                true,
                 None,
+                None,
+                ptr::null_mut(),
+                2,
+            )
+            .map_err(|e| AuthError::Unavailable(format!("user search failed: {:?}", e)))?;
+
+        if responses.len() != 1 {
+            debug!("{} LDAP entries match {:?}", responses.len(), filter);
+            return Err(AuthError::UnknownUser);
+        }
+
+        responses
+            .remove(0)
+            .remove("dn")
+            .and_then(|dn| dn.into_iter().next())
+            .ok_or(AuthError::UnknownUser)
+    }
+
+    /// Whether the entry of `dn` exists, asked with the bind the connection has left. An entry
+    /// the server doesn't show is taken as existing: only `noSuchObject` means unknown.
+    fn entry_exists(ldap: &openldap::RustLDAP, dn: &str) -> bool {
+        match ldap.ldap_search(
+            dn,
+            openldap::codes::scopes::LDAP_SCOPE_BASE,
+            Some("(objectClass=*)"),
+            Some(vec!["1.1"]),
+            true,
+            None,
+            None,
+            ptr::null_mut(),
+            1,
+        ) {
+            Err(openldap::errors::LDAPError::NativeError(e)) => e != NO_SUCH_OBJECT,
+            Ok(_) => true,
+        }
+    }
+
+    /// Searches group entries, `None` when the search itself fails.
+    fn search_groups(
+        &self,
+        ldap: &openldap::RustLDAP,
+        base: &str,
+        scope: i32,
+        filter: Option<&str>,
+    ) -> Option<Vec<Group>> {
+        let attribute = self.config.group_attribute.as_str();
+        let responses = ldap
+            .ldap_search(
+                base,
+                scope,
+                filter,
+                Some(vec![attribute, MEMBER_OF]),
                 false,
                 None,
                 None,
@@ -71,22 +369,354 @@ impl Auth for LDAPAuth {
             )
             .ok()?;
 
-        if responses.is_empty() {
-            return None;
+        Some(
+            responses
+                .into_iter()
+                .filter_map(|mut entry| {
+                    Some(Group {
+                        dn: entry.remove("dn")?.into_iter().next()?,
+                        names: take(&mut entry, attribute),
+                        member_of: take(&mut entry, MEMBER_OF),
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Reads the group entries by their DNs, skipping the unreadable ones.
+    fn read_groups(&self, ldap: &openldap::RustLDAP, dns: &[String]) -> Vec<Group> {
+        dns.iter()
+            .filter_map(|dn| {
+                self.search_groups(
+                    ldap,
+                    dn,
+                    openldap::codes::scopes::LDAP_SCOPE_BASE,
+                    Some("(objectClass=*)"),
+                )
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// Groups of the user, the nested ones included up to `nested_depth` levels.
+    fn find_groups(
+        &self,
+        ldap: &openldap::RustLDAP,
+        user_dn: &str,
+        username: &str,
+    ) -> Result<Vec<Group>, AuthError> {
+        let subtree = openldap::codes::scopes::LDAP_SCOPE_SUBTREE;
+        let config = &self.config;
+        let filter = match (&config.filter, config.groups) {
+            (Some(filter), _) => Some(filter.clone()),
+            (None, GroupStrategy::Member) => Some("(member=%(dn))".to_string()),
+            (None, _) => None,
+        }
+        .map(|f| {
+            f.replace("%(username)", &escape_filter(username))
+                .replace("%(dn)", &escape_filter(user_dn))
+        });
+
+        let direct = match config.groups {
+            GroupStrategy::MemberUid | GroupStrategy::Member => self
+                .search_groups(ldap, &config.base, subtree, filter.as_deref())
+                .ok_or_else(|| AuthError::Unavailable("group search failed".to_string()))?,
+            GroupStrategy::MemberOf => {
+                let user = ldap
+                    .ldap_search(
+                        user_dn,
+                        openldap::codes::scopes::LDAP_SCOPE_BASE,
+                        Some("(objectClass=*)"),
+                        Some(vec![MEMBER_OF]),
+                        false,
+                        None,
+                        None,
+                        ptr::null_mut(),
+                        1,
+                    )
+                    .map_err(|e| {
+                        AuthError::Unavailable(format!("memberOf lookup failed: {:?}", e))
+                    })?
+                    .into_iter()
+                    .next()
+                    .map(|mut entry| take(&mut entry, MEMBER_OF))
+                    .unwrap_or_default();
+
+                self.read_groups(ldap, &user)
+            }
+        };
+
+        Ok(nest(direct, config.nested_depth, |level| {
+            match config.groups {
+                GroupStrategy::MemberOf => {
+                    let dns: Vec<String> = level
+                        .iter()
+                        .flat_map(|group| group.member_of.iter().cloned())
+                        .collect();
+
+                    self.read_groups(ldap, &dns)
+                }
+                _ => level
+                    .iter()
+                    .filter_map(|group| {
+                        let filter = format!("(member={})", escape_filter(&group.dn));
+
+                        self.search_groups(ldap, &config.base, subtree, Some(&filter))
+                    })
+                    .flatten()
+                    .collect(),
+            }
+        }))
+    }
+
+    /// Binds and searches with the blocking `openldap` client.
+    /// Checks the password, returns the groups of the user.
+    fn login_groups(&self, username: &str, password: &str) -> Result<Vec<String>, AuthError> {
+        // an empty password is an anonymous bind which most servers accept
+        if username.is_empty() || password.is_empty() || password.contains('\0') {
+            return Err(AuthError::InvalidCredentials);
+        }
+
+        let ldap = self.connect()?;
+
+        let bind_dn = match (&self.config.search, &self.config.bind) {
+            (Some(search), _) => self.find_user(&ldap, search, username)?,
+            (None, Some(bind)) => bind.replace("%(username)", &escape_dn(username)),
+            (None, None) => return Err(AuthError::UnknownUser),
+        };
+
+        match ldap.simple_bind(&bind_dn, password) {
+            Ok(0) => {}
+            Ok(LDAP_NO_SUCH_OBJECT) => return Err(AuthError::UnknownUser),
+            // most servers refuse a missing DN as a wrong password, a user found by the search
+            // exists already
+            Ok(LDAP_INVALID_CREDENTIALS)
+                if self.config.search.is_none() && !Self::entry_exists(&ldap, &bind_dn) =>
+            {
+                return Err(AuthError::UnknownUser)
+            }
+            Ok(LDAP_INVALID_CREDENTIALS) => return Err(AuthError::InvalidCredentials),
+            result => return Err(AuthError::Unavailable(format!("bind failed: {:?}", result))),
         }
 
-        let mut gates: Vec<Gate> = responses
+        let groups = self.find_groups(&ldap, &bind_dn, username)?;
+
+        Ok(groups
             .into_iter()
-            .filter_map(|mut response| response.remove("cn"))
-            .flat_map(|groups| groups.into_iter())
-            .filter_map(|group| self.gate_mappings.get(&group).cloned())
-            .flat_map(|groups| groups.into_iter())
-            .collect();
+            .flat_map(|group| group.names.into_iter())
+            .collect())
+    }
+}
+
+#[async_trait::async_trait]
+impl Auth for LDAPAuth {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Identity, AuthError> {
+        let auth = self.clone();
+        let username = username.to_string();
+        let password = password.to_string();
+
+        // keep the slow directory off the actix worker
+        blocking("ldap", &self.gate_mappings, move || {
+            auth.login_groups(&username, &password)
+        })
+        .await
+    }
+}
+
+/// Remembers the groups of the logins a provider accepts, with a salted verifier of the
+/// password, and lets the users in from them while the provider is unavailable. The cached
+/// logins older than `max_age_secs` are not trusted. A login the provider refuses forgets the
+/// user: they were removed, or the password changed and the cached one must stop working.
+pub struct CachedAuth {
+    inner: Arc<dyn Auth + Send + Sync>,
+    db: Arc<Mutex<Box<dyn Db + Send>>>,
+    gate_mappings: Arc<HashMap<String, Vec<Gate>>>,
+    max_age_secs: u64,
+}
+
+impl CachedAuth {
+    pub fn new(
+        inner: Arc<dyn Auth + Send + Sync>,
+        db: Arc<Mutex<Box<dyn Db + Send>>>,
+        gate_mappings: HashMap<String, Vec<Gate>>,
+        max_age_secs: u64,
+    ) -> Self {
+        CachedAuth {
+            inner,
+            db,
+            gate_mappings: Arc::new(gate_mappings),
+            max_age_secs,
+        }
+    }
+
+    async fn remember(&self, username: &str, password: &str, identity: &Identity) {
+        let password = password.to_string();
+        let verifier = task::spawn_blocking(move || users::hash_password(&password))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|verifier| verifier);
+        let verifier = match verifier {
+            Ok(verifier) => verifier,
+            Err(e) => {
+                error!("Failed to cache the login of {:?}: {}", username, e);
+                return;
+            }
+        };
+
+        self.db
+            .lock()
+            .await
+            .store_cached_login(&CachedLogin {
+                username: username.to_string(),
+                provider: identity.provider.clone(),
+                verifier,
+                groups: identity.groups.clone(),
+                at: Utc::now().timestamp(),
+            })
+            .await;
+    }
+
+    /// The cached login of the user, `None` when there is no fresh one.
+    async fn offline(&self, username: &str, password: &str) -> Option<Result<Identity, AuthError>> {
+        let cached = self.db.lock().await.load_cached_login(username).await?;
+
+        if Utc::now().timestamp() - cached.at > self.max_age_secs as i64 {
+            debug!("cached login of {:?} is too old", username);
+            return None;
+        }
+
+        let password = password.to_string();
+        let verifier = cached.verifier.clone();
+        let valid = task::spawn_blocking(move || users::verify_password(&password, &verifier))
+            .await
+            .ok()?;
+
+        if !valid {
+            return Some(Err(AuthError::InvalidCredentials));
+        }
 
-        gates.sort_by(|a, b| a.name.cmp(&b.name));
-        gates.dedup_by(|a, b| a.name == b.name);
+        let mut identity = Identity::new(&cached.provider, &self.gate_mappings, cached.groups);
 
-        Some(gates)
+        identity.degraded = true;
+        Some(Ok(identity))
+    }
+}
+
+#[async_trait::async_trait]
+impl Auth for CachedAuth {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Identity, AuthError> {
+        match self.inner.authenticate(username, password).await {
+            Ok(identity) => {
+                self.remember(username, password, &identity).await;
+                Ok(identity)
+            }
+            Err(AuthError::Unavailable(reason)) => match self.offline(username, password).await {
+                Some(result) => {
+                    warn!(
+                        "provider is unavailable, {:?} logs in from the cache: {}",
+                        username, reason
+                    );
+                    result
+                }
+                None => Err(AuthError::Unavailable(reason)),
+            },
+            Err(e) => {
+                self.db.lock().await.remove_cached_login(username).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Users of the local file, see `services::users`.
+#[derive(Clone)]
+pub struct LocalAuth {
+    file: Arc<PathBuf>,
+    gate_mappings: Arc<HashMap<String, Vec<Gate>>>,
+}
+
+impl LocalAuth {
+    pub fn new(config: &LocalUsers, gate_mappings: HashMap<String, Vec<Gate>>) -> Self {
+        LocalAuth {
+            file: Arc::new(PathBuf::from(&config.file)),
+            gate_mappings: Arc::new(gate_mappings),
+        }
+    }
+
+    /// Reads the file on every login, so `barrier-backend user` needs no restart.
+    fn login_groups(&self, username: &str, password: &str) -> Result<Vec<String>, AuthError> {
+        let users = Users::load(&self.file).map_err(AuthError::Unavailable)?;
+
+        Ok(users.verify(username, password)?.groups.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl Auth for LocalAuth {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Identity, AuthError> {
+        let auth = self.clone();
+        let username = username.to_string();
+        let password = password.to_string();
+
+        // argon2 is slow on purpose
+        blocking("local", &self.gate_mappings, move || {
+            auth.login_groups(&username, &password)
+        })
+        .await
+    }
+}
+
+/// Providers asked in the configured order, an unavailable provider or one not knowing the user
+/// passes the login on. The first one accepting it is recorded as the provider, the later ones
+/// accepting it as well add their groups, a gate granted twice keeps the widest access. A
+/// provider refusing the password ends the chain: the same name further on may be someone else.
+pub struct ChainAuth {
+    providers: Vec<Arc<dyn Auth + Send + Sync>>,
+}
+
+impl ChainAuth {
+    pub fn new(providers: Vec<Arc<dyn Auth + Send + Sync>>) -> Self {
+        ChainAuth { providers }
+    }
+}
+
+#[async_trait::async_trait]
+impl Auth for ChainAuth {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Identity, AuthError> {
+        let mut identity: Option<Identity> = None;
+        let mut failure: Option<AuthError> = None;
+
+        for provider in &self.providers {
+            match provider.authenticate(username, password).await {
+                Ok(found) => match &mut identity {
+                    Some(identity) => {
+                        let mut gates = std::mem::take(&mut identity.gates);
+
+                        gates.extend(found.gates);
+                        identity.gates = merge_gates(gates);
+                        identity.groups.extend(found.groups);
+                        identity.groups.sort();
+                        identity.groups.dedup();
+                        identity.degraded |= found.degraded;
+                    }
+                    None => identity = Some(found),
+                },
+                Err(e) => {
+                    if let AuthError::Unavailable(reason) = &e {
+                        warn!("authentication provider is unavailable: {}", reason);
+                    }
+
+                    let refused = e == AuthError::InvalidCredentials;
+
+                    failure.get_or_insert(e);
+                    if refused {
+                        break;
+                    }
+                }
+            }
+        }
+
+        identity.ok_or_else(|| failure.unwrap_or(AuthError::UnknownUser))
     }
 }
 
@@ -110,10 +740,432 @@ impl FakeAuth {
 }
 
 #[cfg(test)]
+#[async_trait::async_trait]
 impl Auth for FakeAuth {
-    fn get_available_rooms(&self, username: &str, password: &str) -> Option<Vec<Gate>> {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Identity, AuthError> {
         let key = format!("{}-{}", username, password);
 
-        self.users.get(&key).map(|rooms| rooms.to_owned())
+        self.users
+            .get(&key)
+            .map(|rooms| Identity {
+                provider: "fake".to_string(),
+                gates: rooms.to_owned(),
+                // every fake user is in `users`
+                groups: vec!["users".to_string()],
+                degraded: false,
+            })
+            .ok_or(AuthError::InvalidCredentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn escape_values() {
+        assert_eq!(escape_filter("john"), "john");
+        assert_eq!(escape_filter("*)(uid=*"), "\\2a\\29\\28uid=\\2a");
+        assert_eq!(escape_filter("a\\b"), "a\\5cb");
+        assert_eq!(escape_dn("Doe, John"), "Doe\\, John");
+        assert_eq!(escape_dn("#admin "), "\\#admin\\ ");
+        assert_eq!(escape_dn("a=b+c"), "a\\=b\\+c");
+    }
+
+    fn group(name: &str, member_of: &[&str]) -> Group {
+        Group {
+            dn: format!("cn={},ou=Groups", name),
+            names: vec![name.to_string()],
+            member_of: member_of
+                .iter()
+                .map(|parent| format!("cn={},ou=Groups", parent))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn nested_groups() {
+        // developers -> staff -> employees -> staff, a loop
+        let directory: HashMap<String, Group> = vec![
+            group("developers", &["staff"]),
+            group("staff", &["employees"]),
+            group("employees", &["staff"]),
+        ]
+        .into_iter()
+        .map(|group| (group.dn.clone(), group))
+        .collect();
+        let parents = |level: &[Group]| -> Vec<Group> {
+            level
+                .iter()
+                .flat_map(|group| group.member_of.iter())
+                .filter_map(|dn| directory.get(dn).cloned())
+                .collect()
+        };
+        let names = |groups: Vec<Group>| -> Vec<String> {
+            groups
+                .into_iter()
+                .flat_map(|group| group.names.into_iter())
+                .collect()
+        };
+
+        assert_eq!(
+            names(nest(vec![group("developers", &["staff"])], 0, parents)),
+            vec!["developers"]
+        );
+        assert_eq!(
+            names(nest(vec![group("developers", &["staff"])], 1, parents)),
+            vec!["developers", "staff"]
+        );
+        assert_eq!(
+            names(nest(vec![group("developers", &["staff"])], 10, parents)),
+            vec!["developers", "staff", "employees"]
+        );
+    }
+
+    fn gate(name: &str, control: bool) -> Gate {
+        Gate {
+            id: 1,
+            retries: 1,
+            name: name.to_string(),
+            description: "".to_string(),
+            control,
+        }
+    }
+
+    #[tokio::test]
+    async fn local_users() {
+        let file =
+            std::env::temp_dir().join(format!("barrier-users-{}.toml", uuid::Uuid::new_v4()));
+        let mut mappings = HashMap::new();
+        let mut users = Users::default();
+
+        mappings.insert("guests".to_string(), vec![gate("barrier", false)]);
+        mappings.insert("guards".to_string(), vec![gate("barrier", true)]);
+        users
+            .add(
+                "contractor",
+                "secret",
+                vec!["guests".to_string(), "guards".to_string()],
+            )
+            .unwrap();
+        users.save(&file).unwrap();
+
+        let auth = LocalAuth::new(
+            &LocalUsers {
+                file: file.to_string_lossy().to_string(),
+            },
+            mappings,
+        );
+
+        assert_eq!(
+            auth.authenticate("contractor", "secret").await,
+            Ok(Identity {
+                provider: "local".to_string(),
+                gates: vec![gate("barrier", true)],
+                groups: vec!["guards".to_string(), "guests".to_string()],
+                degraded: false,
+            })
+        );
+        assert_eq!(
+            auth.authenticate("contractor", "wrong").await,
+            Err(AuthError::InvalidCredentials)
+        );
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    /// Provider answering the same for every login.
+    struct Fixed(Result<Identity, AuthError>);
+
+    #[async_trait::async_trait]
+    impl Auth for Fixed {
+        async fn authenticate(&self, _: &str, _: &str) -> Result<Identity, AuthError> {
+            self.0.clone()
+        }
+    }
+
+    fn fixed(result: Result<(&str, Vec<Gate>), AuthError>) -> Arc<dyn Auth + Send + Sync> {
+        Arc::new(Fixed(result.map(|(provider, gates)| Identity {
+            provider: provider.to_string(),
+            gates,
+            groups: vec![format!("{}-users", provider)],
+            degraded: false,
+        })))
+    }
+
+    #[tokio::test]
+    async fn chain_providers() {
+        let down = || fixed(Err(AuthError::Unavailable("timeout".to_string())));
+        let unknown = || fixed(Err(AuthError::UnknownUser));
+        let invalid = || fixed(Err(AuthError::InvalidCredentials));
+
+        // the directory is down, the break-glass account still works
+        assert_eq!(
+            ChainAuth::new(vec![
+                down(),
+                fixed(Ok(("local", vec![gate("barrier", true)])))
+            ])
+            .authenticate("admin", "secret")
+            .await,
+            Ok(Identity {
+                provider: "local".to_string(),
+                gates: vec![gate("barrier", true)],
+                groups: vec!["local-users".to_string()],
+                degraded: false,
+            })
+        );
+
+        // the first provider wins, the gates of the others are merged
+        assert_eq!(
+            ChainAuth::new(vec![
+                unknown(),
+                fixed(Ok((
+                    "ldap",
+                    vec![gate("gate", false), gate("barrier", false)]
+                ))),
+                fixed(Ok(("local", vec![gate("barrier", true)]))),
+            ])
+            .authenticate("john", "secret")
+            .await,
+            Ok(Identity {
+                provider: "ldap".to_string(),
+                gates: vec![gate("barrier", true), gate("gate", false)],
+                groups: vec!["ldap-users".to_string(), "local-users".to_string()],
+                degraded: false,
+            })
+        );
+
+        // a refused password is not handed on
+        assert_eq!(
+            ChainAuth::new(vec![
+                invalid(),
+                fixed(Ok(("local", vec![gate("barrier", true)])))
+            ])
+            .authenticate("john", "wrong")
+            .await,
+            Err(AuthError::InvalidCredentials)
+        );
+        // nobody accepts: the first failure
+        assert_eq!(
+            ChainAuth::new(vec![down(), unknown(), invalid()])
+                .authenticate("john", "wrong")
+                .await,
+            Err(AuthError::Unavailable("timeout".to_string()))
+        );
+        assert_eq!(
+            ChainAuth::new(vec![unknown()])
+                .authenticate("john", "wrong")
+                .await,
+            Err(AuthError::UnknownUser)
+        );
+    }
+
+    /// Provider of a single user and password, counting the logins it checks.
+    struct Account {
+        provider: &'static str,
+        password: &'static str,
+        asked: std::sync::atomic::AtomicU32,
+    }
+
+    #[async_trait::async_trait]
+    impl Auth for Account {
+        async fn authenticate(
+            &self,
+            username: &str,
+            password: &str,
+        ) -> Result<Identity, AuthError> {
+            self.asked.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+            match (username, password) {
+                ("john", password) if password == self.password => Ok(Identity {
+                    provider: self.provider.to_string(),
+                    gates: vec![gate(self.provider, false)],
+                    groups: Vec::new(),
+                    degraded: false,
+                }),
+                ("john", _) => Err(AuthError::InvalidCredentials),
+                _ => Err(AuthError::UnknownUser),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn same_name_in_two_providers() {
+        let account = |provider, password| {
+            Arc::new(Account {
+                provider,
+                password,
+                asked: std::sync::atomic::AtomicU32::new(0),
+            })
+        };
+        let ldap = account("ldap", "directory password");
+        let local = account("local", "local password");
+        let auth = ChainAuth::new(vec![ldap.clone(), local.clone()]);
+
+        // another john of the local accounts can't log in as the directory one
+        assert_eq!(
+            auth.authenticate("john", "local password").await,
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            auth.authenticate("john", "directory password")
+                .await
+                .map(|identity| identity.provider),
+            Ok("ldap".to_string())
+        );
+        // asked for its groups only once the directory accepted the login
+        assert_eq!(local.asked.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(ldap.asked.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn directory_down_break_glass_logs_in() {
+        let local = Arc::new(Account {
+            provider: "local",
+            password: "break glass",
+            asked: std::sync::atomic::AtomicU32::new(0),
+        });
+        let auth = ChainAuth::new(vec![
+            fixed(Err(AuthError::Unavailable("timeout".to_string()))),
+            local,
+        ]);
+
+        assert_eq!(
+            auth.authenticate("john", "break glass")
+                .await
+                .map(|identity| identity.provider),
+            Ok("local".to_string())
+        );
+        assert_eq!(
+            auth.authenticate("john", "wrong").await,
+            Err(AuthError::Unavailable("timeout".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn user_in_both_providers() {
+        let account = |provider| {
+            Arc::new(Account {
+                provider,
+                password: "secret",
+                asked: std::sync::atomic::AtomicU32::new(0),
+            })
+        };
+        let auth = ChainAuth::new(vec![account("ldap"), account("local")]);
+
+        assert_eq!(
+            auth.authenticate("john", "secret").await,
+            Ok(Identity {
+                provider: "ldap".to_string(),
+                gates: vec![gate("ldap", false), gate("local", false)],
+                groups: Vec::new(),
+                degraded: false,
+            })
+        );
+    }
+
+    /// Provider whose answer the test changes.
+    struct Switch(std::sync::Mutex<Result<Identity, AuthError>>);
+
+    #[async_trait::async_trait]
+    impl Auth for Switch {
+        async fn authenticate(&self, _: &str, _: &str) -> Result<Identity, AuthError> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn offline_login_cache() {
+        let mut mappings = HashMap::new();
+
+        mappings.insert("guards".to_string(), vec![gate("barrier", false)]);
+
+        let online = Identity::new("ldap", &mappings, vec!["guards".to_string()]);
+        let ldap = Arc::new(Switch(std::sync::Mutex::new(Ok(online.clone()))));
+        let db: Box<dyn Db + Send> = Box::new(crate::services::db::Cache::new().await);
+        let db = Arc::new(Mutex::new(db));
+        let auth = CachedAuth::new(ldap.clone(), db.clone(), mappings, 60);
+        let set = |result| *ldap.0.lock().unwrap() = result;
+
+        assert_eq!(auth.authenticate("john", "secret").await, Ok(online));
+
+        // the directory is down, the cached login still opens the barrier
+        set(Err(AuthError::Unavailable("timeout".to_string())));
+        assert_eq!(
+            auth.authenticate("john", "secret").await,
+            Ok(Identity {
+                provider: "ldap".to_string(),
+                gates: vec![gate("barrier", false)],
+                groups: vec!["guards".to_string()],
+                degraded: true,
+            })
+        );
+        assert_eq!(
+            auth.authenticate("john", "wrong").await,
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            auth.authenticate("jane", "secret").await,
+            Err(AuthError::Unavailable("timeout".to_string()))
+        );
+
+        // too old to be trusted
+        let mut cached = db.lock().await.load_cached_login("john").await.unwrap();
+
+        cached.at -= 61;
+        db.lock().await.store_cached_login(&cached).await;
+        assert_eq!(
+            auth.authenticate("john", "secret").await,
+            Err(AuthError::Unavailable("timeout".to_string()))
+        );
+
+        // removed from the directory, forgotten
+        set(Err(AuthError::UnknownUser));
+        assert_eq!(
+            auth.authenticate("john", "secret").await,
+            Err(AuthError::UnknownUser)
+        );
+        assert_eq!(db.lock().await.load_cached_login("john").await, None);
+    }
+
+    #[tokio::test]
+    async fn password_change_forgets_cached_login() {
+        let mut mappings = HashMap::new();
+
+        mappings.insert("guards".to_string(), vec![gate("barrier", false)]);
+
+        let online = Identity::new("ldap", &mappings, vec!["guards".to_string()]);
+        let ldap = Arc::new(Switch(std::sync::Mutex::new(Ok(online))));
+        let db: Box<dyn Db + Send> = Box::new(crate::services::db::Cache::new().await);
+        let db = Arc::new(Mutex::new(db));
+        let auth = CachedAuth::new(ldap.clone(), db.clone(), mappings, 60);
+        let set = |result| *ldap.0.lock().unwrap() = result;
+
+        assert!(auth.authenticate("john", "old").await.is_ok());
+
+        // the password changed, the directory refuses the old one
+        set(Err(AuthError::InvalidCredentials));
+        assert_eq!(
+            auth.authenticate("john", "old").await,
+            Err(AuthError::InvalidCredentials)
+        );
+
+        // then it is down, the old password doesn't come back through the cache
+        set(Err(AuthError::Unavailable("timeout".to_string())));
+        assert_eq!(
+            auth.authenticate("john", "old").await,
+            Err(AuthError::Unavailable("timeout".to_string()))
+        );
+        assert_eq!(db.lock().await.load_cached_login("john").await, None);
+    }
+
+    #[test]
+    fn attribute_case() {
+        let mut entry = HashMap::new();
+        entry.insert("memberof".to_string(), vec!["cn=staff".to_string()]);
+
+        assert_eq!(take(&mut entry, MEMBER_OF), vec!["cn=staff"]);
+        assert!(take(&mut entry, "cn").is_empty());
     }
 }
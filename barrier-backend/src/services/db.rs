@@ -1,17 +1,86 @@
-use mongodb::{bson::doc, Client, Database};
+use futures::StreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReplaceOptions, ReturnDocument},
+    Client, Database,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::structs::Gate;
+use crate::{config::MAX_THROTTLE_SECS, services::jwt::REFRESH_TOKEN_SECS, structs::Gate};
 
 pub struct MongoDb {
     db: Database,
 }
 
-#[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
 pub struct RefreshTokenItem {
     pub username: String,
-    pub refresh_token: String,
+    /// Session the token refreshes, empty for the tokens issued before the sessions were kept.
+    #[serde(default)]
+    pub session_id: String,
+    /// Keyed hash of the token, see `Jwt::refresh_token_hash`.
+    pub token_hash: String,
+    /// The token expires `REFRESH_TOKEN_SECS` later.
+    pub issued: mongodb::bson::DateTime,
     pub rooms: Vec<Gate>,
+    /// Rotated already, the latest `USED_TOKENS_KEPT` are kept to notice a replay.
+    #[serde(default)]
+    pub used: bool,
+    /// Session started from the offline login cache.
+    #[serde(default)]
+    pub degraded: bool,
+}
+
+/// Session of a device, from the login to its last refresh.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct SessionItem {
+    pub session_id: String,
+    pub username: String,
+    pub user_agent: String,
+    /// Address of the last login or refresh.
+    pub ip: String,
+    pub created: mongodb::bson::DateTime,
+    pub last_refresh: mongodb::bson::DateTime,
+}
+
+/// TOTP secret of a user.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct TotpItem {
+    pub username: String,
+    pub secret: String,
+    /// Set by the first code, unconfirmed secrets are not asked for at login.
+    pub confirmed: bool,
+    /// Step of the last accepted code, codes are not taken twice.
+    pub last_step: Option<u64>,
+    /// Set by `totp allow`, the user enrolls a new secret on their next login.
+    #[serde(default)]
+    pub enroll_allowed: bool,
+}
+
+/// Last directory login of a user, lets them in while the directory is down.
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct CachedLogin {
+    pub username: String,
+    pub provider: String,
+    /// Salted argon2id hash of the password.
+    pub verifier: String,
+    pub groups: Vec<String>,
+    /// Unix timestamp of the login.
+    pub at: i64,
+}
+
+/// Session whose access tokens are refused until they expire, see `services::revocation`.
+#[derive(Serialize, Deserialize)]
+struct RevokedSession {
+    session_id: String,
+    until: mongodb::bson::DateTime,
+}
+
+/// Failed login of a user or from an address, see `services::throttle`.
+#[derive(Serialize, Deserialize)]
+struct LoginFailure {
+    key: String,
+    at: mongodb::bson::DateTime,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -23,15 +92,37 @@ struct EventLog<'a> {
     session_id: &'a str,
     #[serde(skip_serializing_if = "Option::is_none")]
     gate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<String>,
+    /// Authentication provider of a login.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provider: Option<String>,
 }
 
 pub enum EventType {
-    SuccessfulLogin,
-    FailedLogin,
+    SuccessfulLogin { provider: String, degraded: bool },
+    FailedLogin { reason: String },
     SuccessfulRefresh,
     FailedRefresh,
-    SuccessfulGateAccess { gate: String },
+    RefreshTokenReuse,
+    SuccessfulGateAccess { gate: String, attempts: u32 },
     UnauthorizedGateAccess { gate: String },
+    FailedGateAccess { gate: String, reason: String },
+    GateClose { gate: String, attempts: u32 },
+    GateHold { gate: String, attempts: u32 },
+    GateRelease { gate: String, attempts: u32 },
+    UnauthorizedGateControl { gate: String },
+    CircuitOpened { gate: String, reason: String },
+    CircuitClosed { gate: String },
+    TotpEnrolled,
+    SessionRevoked,
+    SuccessfulTotp,
+    FailedTotp { reason: String },
+}
+
+/// Notes the retries of a gate command which eventually succeeded.
+fn retried(attempts: u32) -> Option<String> {
+    (attempts > 1).then(|| format!("succeeded on attempt {}", attempts))
 }
 
 fn event_to_log<'a>(
@@ -41,21 +132,25 @@ fn event_to_log<'a>(
     event: EventType,
 ) -> EventLog<'a> {
     match event {
-        EventType::SuccessfulLogin => EventLog {
+        EventType::SuccessfulLogin { provider, degraded } => EventLog {
             ip,
             username,
             event_type: "Successful login",
             date: mongodb::bson::DateTime::now(),
             session_id,
             gate: None,
+            details: degraded.then(|| "degraded: offline login cache".to_string()),
+            provider: Some(provider),
         },
-        EventType::FailedLogin => EventLog {
+        EventType::FailedLogin { reason } => EventLog {
             ip,
             username,
             event_type: "Failed login",
             date: mongodb::bson::DateTime::now(),
             session_id,
             gate: None,
+            details: Some(reason),
+            provider: None,
         },
         EventType::SuccessfulRefresh => EventLog {
             ip,
@@ -64,6 +159,8 @@ fn event_to_log<'a>(
             date: mongodb::bson::DateTime::now(),
             session_id,
             gate: None,
+            details: None,
+            provider: None,
         },
         EventType::FailedRefresh => EventLog {
             ip,
@@ -72,14 +169,28 @@ fn event_to_log<'a>(
             date: mongodb::bson::DateTime::now(),
             session_id,
             gate: None,
+            details: None,
+            provider: None,
+        },
+        EventType::RefreshTokenReuse => EventLog {
+            ip,
+            username,
+            event_type: "Refresh token reuse",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: None,
+            details: Some("the session is revoked".to_string()),
+            provider: None,
         },
-        EventType::SuccessfulGateAccess { gate } => EventLog {
+        EventType::SuccessfulGateAccess { gate, attempts } => EventLog {
             ip,
             username,
             event_type: "Successful access to gate",
             date: mongodb::bson::DateTime::now(),
             session_id,
             gate: Some(gate),
+            details: retried(attempts),
+            provider: None,
         },
         EventType::UnauthorizedGateAccess { gate } => EventLog {
             ip,
@@ -88,15 +199,157 @@ fn event_to_log<'a>(
             date: mongodb::bson::DateTime::now(),
             session_id,
             gate: Some(gate),
+            details: None,
+            provider: None,
+        },
+        EventType::FailedGateAccess { gate, reason } => EventLog {
+            ip,
+            username,
+            event_type: "Failed gate access",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: Some(gate),
+            details: Some(reason),
+            provider: None,
+        },
+        EventType::GateClose { gate, attempts } => EventLog {
+            ip,
+            username,
+            event_type: "Gate closed",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: Some(gate),
+            details: retried(attempts),
+            provider: None,
+        },
+        EventType::GateHold { gate, attempts } => EventLog {
+            ip,
+            username,
+            event_type: "Gate held open",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: Some(gate),
+            details: retried(attempts),
+            provider: None,
+        },
+        EventType::GateRelease { gate, attempts } => EventLog {
+            ip,
+            username,
+            event_type: "Gate released",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: Some(gate),
+            details: retried(attempts),
+            provider: None,
+        },
+        EventType::UnauthorizedGateControl { gate } => EventLog {
+            ip,
+            username,
+            event_type: "Unauthorized gate control",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: Some(gate),
+            details: None,
+            provider: None,
+        },
+        EventType::CircuitOpened { gate, reason } => EventLog {
+            ip,
+            username,
+            event_type: "Gate circuit opened",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: Some(gate),
+            details: Some(reason),
+            provider: None,
+        },
+        EventType::CircuitClosed { gate } => EventLog {
+            ip,
+            username,
+            event_type: "Gate circuit closed",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: Some(gate),
+            details: None,
+            provider: None,
+        },
+        EventType::TotpEnrolled => EventLog {
+            ip,
+            username,
+            event_type: "TOTP enrolled",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: None,
+            details: None,
+            provider: None,
+        },
+        EventType::SessionRevoked => EventLog {
+            ip,
+            username,
+            event_type: "Session revoked",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: None,
+            details: None,
+            provider: None,
+        },
+        EventType::SuccessfulTotp => EventLog {
+            ip,
+            username,
+            event_type: "Successful TOTP",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: None,
+            details: None,
+            provider: None,
+        },
+        EventType::FailedTotp { reason } => EventLog {
+            ip,
+            username,
+            event_type: "Failed TOTP",
+            date: mongodb::bson::DateTime::now(),
+            session_id,
+            gate: None,
+            details: Some(reason),
+            provider: None,
         },
     }
 }
 #[async_trait::async_trait]
 pub trait Db {
     async fn log_event(&self, ip: &str, username: &str, session_id: &str, event: EventType);
-    async fn store_refresh(&self, username: &str, refresh_token: &str, rooms: &[Gate]);
-    async fn remove_by_refresh_token(&self, refresh_token: &str) -> Option<RefreshTokenItem>;
+    async fn store_refresh(&self, item: &RefreshTokenItem);
+    /// Marks the token used, returns it as it was before.
+    async fn use_refresh_token(&self, token_hash: &str) -> Option<RefreshTokenItem>;
+    /// Drops the used tokens of the session but the `keep` latest issued.
+    async fn prune_used_tokens(&self, session_id: &str, keep: usize);
+    /// Drops every refresh token of the session.
+    async fn remove_refresh_tokens(&self, session_id: &str);
+    /// Moves a token issued before the sessions were kept into the session it started, so its
+    /// replay ends that session.
+    async fn attach_refresh_token(&self, token_hash: &str, session_id: &str);
+    /// Ends every session of the user.
     async fn remove_by_username(&self, username: &str);
+    /// Replaces the session of `item.session_id`.
+    async fn store_session(&self, item: &SessionItem);
+    async fn sessions(&self, username: &str) -> Vec<SessionItem>;
+    /// Ends a session of the user with its refresh tokens, false when the user has no such
+    /// session.
+    async fn remove_session(&self, username: &str, session_id: &str) -> bool;
+    /// Refuses the access tokens of the session until the Unix timestamp `until`.
+    async fn revoke_session(&self, session_id: &str, until: i64);
+    /// Revoked sessions with their `until`, the expired ones left out.
+    async fn revoked_sessions(&self, now: i64) -> Vec<(String, i64)>;
+    async fn load_totp(&self, username: &str) -> Option<TotpItem>;
+    /// Replaces the TOTP secret of `item.username`.
+    async fn store_totp(&self, item: &TotpItem);
+    /// Unix timestamps of the failed logins of `key` since `since`, oldest first.
+    async fn login_failures(&self, key: &str, since: i64) -> Vec<i64>;
+    async fn add_login_failure(&self, key: &str, at: i64);
+    async fn clear_login_failures(&self, key: &str);
+    async fn load_cached_login(&self, username: &str) -> Option<CachedLogin>;
+    /// Replaces the cached login of `item.username`.
+    async fn store_cached_login(&self, item: &CachedLogin);
+    async fn remove_cached_login(&self, username: &str);
 }
 
 impl MongoDb {
@@ -106,12 +359,93 @@ impl MongoDb {
 
         db.run_command(
             doc! {
-                "createIndexes": "refresh_tokens",
+                "createIndexes": "sessions",
                 "indexes": [
                     {
-                        "key": { "refresh_token": 1 },
-                        "name": "refresh_token_index",
-                        "expireAfterSeconds": 1209600, // 2 weeks
+                        "key": { "session_id": 1 },
+                        "name": "session_id_index",
+                        "unique": true
+                    },
+                    {
+                        "key": { "username": 1 },
+                        "name": "username_index",
+                    },
+                    {
+                        "key": { "last_refresh": 1 },
+                        "name": "last_refresh_index",
+                        "expireAfterSeconds": REFRESH_TOKEN_SECS as i64,
+                    },
+                ]
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        db.run_command(
+            doc! {
+                "createIndexes": "revoked_sessions",
+                "indexes": [
+                    {
+                        "key": { "session_id": 1 },
+                        "name": "session_id_index",
+                        "unique": true
+                    },
+                    {
+                        "key": { "until": 1 },
+                        "name": "until_index",
+                        "expireAfterSeconds": 0,
+                    },
+                ]
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        db.run_command(
+            doc! {
+                "createIndexes": "login_failures",
+                "indexes": [
+                    {
+                        "key": { "key": 1, "at": 1 },
+                        "name": "key_index",
+                    },
+                    {
+                        "key": { "at": 1 },
+                        "name": "at_index",
+                        "expireAfterSeconds": MAX_THROTTLE_SECS as i64,
+                    },
+                ]
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        db.run_command(
+            doc! {
+                "createIndexes": "login_cache",
+                "indexes": [
+                    {
+                        "key": { "username": 1 },
+                        "name": "username_index",
+                        "unique": true
+                    },
+                ]
+            },
+            None,
+        )
+        .await
+        .unwrap();
+
+        db.run_command(
+            doc! {
+                "createIndexes": "totp",
+                "indexes": [
+                    {
+                        "key": { "username": 1 },
+                        "name": "username_index",
                         "unique": true
                     },
                 ]
@@ -123,6 +457,116 @@ impl MongoDb {
 
         Self { db }
     }
+
+    /// Replaces the plain refresh tokens stored before they were hashed, returns how many. The
+    /// tokens stored before they were dated expire from now on. Creates the indexes of the
+    /// refresh tokens, which need both.
+    /// Records the `refresh_token_key` the stored refresh tokens are hashed with by its
+    /// `check`, refuses another key: the tokens hashed so far would be void.
+    pub async fn pin_refresh_token_key(&self, check: &str) -> Result<(), String> {
+        let pinned = self
+            .db
+            .collection::<Document>("settings")
+            .find_one_and_update(
+                doc! { "_id": "refresh_token_key" },
+                doc! { "$setOnInsert": { "check": check } },
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+            .expect("Normal db connection");
+
+        match pinned
+            .as_ref()
+            .and_then(|pinned| pinned.get_str("check").ok())
+        {
+            Some(pinned) if pinned != check => Err(
+                "refresh_token_key is not the one the stored refresh tokens are hashed with"
+                    .to_string(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn migrate_refresh_tokens(&self, hash: impl Fn(&str) -> String) -> u64 {
+        let refresh_tokens = self.db.collection::<Document>("refresh_tokens");
+        let now = mongodb::bson::DateTime::now();
+
+        // the unique index of the plain tokens, missing once dropped
+        self.db
+            .run_command(
+                doc! {
+                    "dropIndexes": "refresh_tokens",
+                    "index": "refresh_token_index"
+                },
+                None,
+            )
+            .await
+            .ok();
+
+        let mut plain = refresh_tokens
+            .find(doc! { "refresh_token": { "$exists": true } }, None)
+            .await
+            .expect("Normal db connection");
+        let mut hashed = 0;
+
+        while let Some(item) = plain.next().await {
+            let item = item.expect("Normal db connection");
+            let (id, token) = match (item.get("_id"), item.get_str("refresh_token")) {
+                (Some(id), Ok(token)) => (id.clone(), token),
+                _ => continue,
+            };
+
+            // another instance may be migrating it as well
+            hashed += refresh_tokens
+                .update_one(
+                    doc! { "_id": id, "refresh_token": token },
+                    doc! {
+                        "$set": { "token_hash": hash(token), "issued": now },
+                        "$unset": { "refresh_token": "" }
+                    },
+                    None,
+                )
+                .await
+                .expect("Normal db connection")
+                .modified_count;
+        }
+
+        refresh_tokens
+            .update_many(
+                doc! { "issued": { "$exists": false } },
+                doc! { "$set": { "issued": now } },
+                None,
+            )
+            .await
+            .expect("Normal db connection");
+
+        self.db
+            .run_command(
+                doc! {
+                    "createIndexes": "refresh_tokens",
+                    "indexes": [
+                        {
+                            "key": { "token_hash": 1 },
+                            "name": "token_hash_index",
+                            "unique": true
+                        },
+                        {
+                            "key": { "issued": 1 },
+                            "name": "issued_index",
+                            "expireAfterSeconds": REFRESH_TOKEN_SECS as i64,
+                        },
+                    ]
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        hashed
+    }
 }
 
 #[async_trait::async_trait]
@@ -135,35 +579,71 @@ impl Db for MongoDb {
             .await
             .expect("insert log");
     }
-    async fn store_refresh(&self, username: &str, refresh_token: &str, rooms: &[Gate]) {
+    async fn store_refresh(&self, item: &RefreshTokenItem) {
+        let refresh_tokens = self.db.collection::<RefreshTokenItem>("refresh_tokens");
+
+        refresh_tokens.insert_one(item, None).await.expect("Insert");
+    }
+
+    async fn use_refresh_token(&self, token_hash: &str) -> Option<RefreshTokenItem> {
         let refresh_tokens = self.db.collection::<RefreshTokenItem>("refresh_tokens");
 
+        // the update returns the document before it by default
         refresh_tokens
-            .insert_one(
-                RefreshTokenItem {
-                    username: username.to_string(),
-                    refresh_token: refresh_token.to_string(),
-                    rooms: rooms.to_vec(),
+            .find_one_and_update(
+                doc! {
+                    "token_hash": token_hash
                 },
+                doc! { "$set": { "used": true } },
                 None,
             )
             .await
-            .expect("Insert");
+            .expect("Normal db connection")
     }
 
-    async fn remove_by_refresh_token(&self, refresh_token: &str) -> Option<RefreshTokenItem> {
+    async fn prune_used_tokens(&self, session_id: &str, keep: usize) {
         let refresh_tokens = self.db.collection::<RefreshTokenItem>("refresh_tokens");
 
-        refresh_tokens
-            .find_one_and_delete(
-                doc! {
-                            "refresh_token": refresh_token
+        let stale: Vec<String> = refresh_tokens
+            .find(
+                doc! { "session_id": session_id, "used": true },
+                FindOptions::builder()
+                    .sort(doc! { "issued": -1 })
+                    .skip(keep as u64)
+                    .build(),
+            )
+            .await
+            .expect("Normal db connection")
+            .map(|item| item.expect("refresh token").token_hash)
+            .collect()
+            .await;
 
-                },
+        if !stale.is_empty() {
+            refresh_tokens
+                .delete_many(doc! { "token_hash": { "$in": stale } }, None)
+                .await
+                .expect("Normal delete");
+        }
+    }
+
+    async fn remove_refresh_tokens(&self, session_id: &str) {
+        self.db
+            .collection::<RefreshTokenItem>("refresh_tokens")
+            .delete_many(doc! { "session_id": session_id }, None)
+            .await
+            .expect("Normal delete");
+    }
+
+    async fn attach_refresh_token(&self, token_hash: &str, session_id: &str) {
+        self.db
+            .collection::<RefreshTokenItem>("refresh_tokens")
+            .update_one(
+                doc! { "token_hash": token_hash },
+                doc! { "$set": { "session_id": session_id } },
                 None,
             )
             .await
-            .expect("Normal db connection")
+            .expect("Normal db connection");
     }
 
     async fn remove_by_username(&self, username: &str) {
@@ -178,6 +658,190 @@ impl Db for MongoDb {
             )
             .await
             .expect("Normal delete one");
+
+        self.db
+            .collection::<SessionItem>("sessions")
+            .delete_many(doc! { "username": username }, None)
+            .await
+            .expect("Normal delete");
+    }
+
+    async fn store_session(&self, item: &SessionItem) {
+        let sessions = self.db.collection::<SessionItem>("sessions");
+
+        sessions
+            .replace_one(
+                doc! { "session_id": &item.session_id },
+                item,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+            .expect("Replace");
+    }
+
+    async fn sessions(&self, username: &str) -> Vec<SessionItem> {
+        let sessions = self.db.collection::<SessionItem>("sessions");
+
+        sessions
+            .find(
+                doc! { "username": username },
+                FindOptions::builder().sort(doc! { "created": 1 }).build(),
+            )
+            .await
+            .expect("Normal db connection")
+            .map(|session| session.expect("session"))
+            .collect()
+            .await
+    }
+
+    async fn remove_session(&self, username: &str, session_id: &str) -> bool {
+        let sessions = self.db.collection::<SessionItem>("sessions");
+        let filter = doc! { "username": username, "session_id": session_id };
+        let removed = sessions
+            .delete_one(filter.clone(), None)
+            .await
+            .expect("Normal delete one")
+            .deleted_count
+            > 0;
+
+        if removed {
+            self.db
+                .collection::<RefreshTokenItem>("refresh_tokens")
+                .delete_many(filter, None)
+                .await
+                .expect("Normal delete");
+        }
+
+        removed
+    }
+
+    async fn revoke_session(&self, session_id: &str, until: i64) {
+        let revoked = self.db.collection::<RevokedSession>("revoked_sessions");
+
+        revoked
+            .replace_one(
+                doc! { "session_id": session_id },
+                RevokedSession {
+                    session_id: session_id.to_string(),
+                    until: mongodb::bson::DateTime::from_millis(until * 1000),
+                },
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+            .expect("Replace");
+    }
+
+    async fn revoked_sessions(&self, now: i64) -> Vec<(String, i64)> {
+        let revoked = self.db.collection::<RevokedSession>("revoked_sessions");
+
+        // the TTL monitor runs once a minute, the expired ones may still be there
+        revoked
+            .find(
+                doc! { "until": { "$gt": mongodb::bson::DateTime::from_millis(now * 1000) } },
+                None,
+            )
+            .await
+            .expect("Normal db connection")
+            .map(|revoked| {
+                let revoked = revoked.expect("revoked session");
+
+                (revoked.session_id, revoked.until.timestamp_millis() / 1000)
+            })
+            .collect()
+            .await
+    }
+
+    async fn load_totp(&self, username: &str) -> Option<TotpItem> {
+        let totp = self.db.collection::<TotpItem>("totp");
+
+        totp.find_one(doc! { "username": username }, None)
+            .await
+            .expect("Normal db connection")
+    }
+
+    async fn store_totp(&self, item: &TotpItem) {
+        let totp = self.db.collection::<TotpItem>("totp");
+
+        totp.replace_one(
+            doc! { "username": &item.username },
+            item,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await
+        .expect("Replace");
+    }
+
+    async fn login_failures(&self, key: &str, since: i64) -> Vec<i64> {
+        let failures = self.db.collection::<LoginFailure>("login_failures");
+
+        failures
+            .find(
+                doc! {
+                    "key": key,
+                    "at": { "$gte": mongodb::bson::DateTime::from_millis(since * 1000) },
+                },
+                FindOptions::builder().sort(doc! { "at": 1 }).build(),
+            )
+            .await
+            .expect("Normal db connection")
+            .map(|failure| failure.expect("failure").at.timestamp_millis() / 1000)
+            .collect()
+            .await
+    }
+
+    async fn add_login_failure(&self, key: &str, at: i64) {
+        let failures = self.db.collection::<LoginFailure>("login_failures");
+
+        failures
+            .insert_one(
+                LoginFailure {
+                    key: key.to_string(),
+                    at: mongodb::bson::DateTime::from_millis(at * 1000),
+                },
+                None,
+            )
+            .await
+            .expect("Insert");
+    }
+
+    async fn clear_login_failures(&self, key: &str) {
+        let failures = self.db.collection::<LoginFailure>("login_failures");
+
+        failures
+            .delete_many(doc! { "key": key }, None)
+            .await
+            .expect("Normal delete");
+    }
+
+    async fn load_cached_login(&self, username: &str) -> Option<CachedLogin> {
+        let cache = self.db.collection::<CachedLogin>("login_cache");
+
+        cache
+            .find_one(doc! { "username": username }, None)
+            .await
+            .expect("Normal db connection")
+    }
+
+    async fn store_cached_login(&self, item: &CachedLogin) {
+        let cache = self.db.collection::<CachedLogin>("login_cache");
+
+        cache
+            .replace_one(
+                doc! { "username": &item.username },
+                item,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+            .expect("Replace");
+    }
+
+    async fn remove_cached_login(&self, username: &str) {
+        let cache = self.db.collection::<CachedLogin>("login_cache");
+
+        cache
+            .delete_one(doc! { "username": username }, None)
+            .await
+            .expect("Normal delete one");
     }
 }
 
@@ -189,6 +853,11 @@ use tokio::sync::Mutex;
 #[cfg(test)]
 pub struct Cache {
     cache: Mutex<HashMap<String, RefreshTokenItem>>,
+    totp: Mutex<HashMap<String, TotpItem>>,
+    failures: Mutex<HashMap<String, Vec<i64>>>,
+    logins: Mutex<HashMap<String, CachedLogin>>,
+    sessions: Mutex<HashMap<String, SessionItem>>,
+    revoked: Mutex<HashMap<String, i64>>,
 }
 
 #[cfg(test)]
@@ -196,6 +865,11 @@ impl Cache {
     pub async fn new() -> Self {
         Self {
             cache: Mutex::new(HashMap::new()),
+            totp: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            logins: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            revoked: Mutex::new(HashMap::new()),
         }
     }
 }
@@ -205,26 +879,162 @@ impl Cache {
 impl Db for Cache {
     async fn log_event(&self, _: &str, _: &str, _: &str, _: EventType) {}
 
-    async fn store_refresh(&self, username: &str, refresh_token: &str, rooms: &[Gate]) {
-        self.cache.lock().await.insert(
-            refresh_token.to_string(),
-            RefreshTokenItem {
-                username: username.to_string(),
-                refresh_token: refresh_token.to_string(),
-                rooms: rooms.to_vec(),
-            },
-        );
+    async fn store_refresh(&self, item: &RefreshTokenItem) {
+        self.cache
+            .lock()
+            .await
+            .insert(item.token_hash.clone(), item.clone());
     }
 
-    async fn remove_by_refresh_token(&self, refresh_token: &str) -> Option<RefreshTokenItem> {
-        self.cache.lock().await.remove(refresh_token)
+    async fn use_refresh_token(&self, token_hash: &str) -> Option<RefreshTokenItem> {
+        self.cache
+            .lock()
+            .await
+            .get_mut(token_hash)
+            .map(|item| RefreshTokenItem {
+                used: std::mem::replace(&mut item.used, true),
+                ..item.clone()
+            })
+    }
+
+    async fn prune_used_tokens(&self, session_id: &str, keep: usize) {
+        let mut cache = self.cache.lock().await;
+        let mut used: Vec<(mongodb::bson::DateTime, String)> = cache
+            .values()
+            .filter(|item| item.session_id == session_id && item.used)
+            .map(|item| (item.issued, item.token_hash.clone()))
+            .collect();
+
+        used.sort_by_key(|(issued, _)| std::cmp::Reverse(*issued));
+        for (_, token_hash) in used.into_iter().skip(keep) {
+            cache.remove(&token_hash);
+        }
+    }
+
+    async fn remove_refresh_tokens(&self, session_id: &str) {
+        self.cache
+            .lock()
+            .await
+            .retain(|_, item| item.session_id != session_id);
+    }
+
+    async fn attach_refresh_token(&self, token_hash: &str, session_id: &str) {
+        if let Some(item) = self.cache.lock().await.get_mut(token_hash) {
+            item.session_id = session_id.to_string();
+        }
     }
 
     async fn remove_by_username(&self, username: &str) {
         self.cache
             .lock()
             .await
-            .retain(|_, u| u.username != username)
+            .retain(|_, u| u.username != username);
+        self.sessions
+            .lock()
+            .await
+            .retain(|_, session| session.username != username);
+    }
+
+    async fn store_session(&self, item: &SessionItem) {
+        self.sessions
+            .lock()
+            .await
+            .insert(item.session_id.clone(), item.clone());
+    }
+
+    async fn sessions(&self, username: &str) -> Vec<SessionItem> {
+        let mut sessions: Vec<SessionItem> = self
+            .sessions
+            .lock()
+            .await
+            .values()
+            .filter(|session| session.username == username)
+            .cloned()
+            .collect();
+
+        sessions.sort_by_key(|session| session.created);
+        sessions
+    }
+
+    async fn remove_session(&self, username: &str, session_id: &str) -> bool {
+        let mut sessions = self.sessions.lock().await;
+
+        match sessions.get(session_id) {
+            Some(session) if session.username == username => {
+                sessions.remove(session_id);
+                self.cache
+                    .lock()
+                    .await
+                    .retain(|_, u| u.session_id != session_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn revoke_session(&self, session_id: &str, until: i64) {
+        self.revoked
+            .lock()
+            .await
+            .insert(session_id.to_string(), until);
+    }
+
+    async fn revoked_sessions(&self, now: i64) -> Vec<(String, i64)> {
+        self.revoked
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(session_id, until)| (session_id.clone(), *until))
+            .collect()
+    }
+
+    async fn load_totp(&self, username: &str) -> Option<TotpItem> {
+        self.totp.lock().await.get(username).cloned()
+    }
+
+    async fn store_totp(&self, item: &TotpItem) {
+        self.totp
+            .lock()
+            .await
+            .insert(item.username.clone(), item.clone());
+    }
+
+    async fn login_failures(&self, key: &str, since: i64) -> Vec<i64> {
+        self.failures
+            .lock()
+            .await
+            .get(key)
+            .map(|failures| failures.iter().copied().filter(|at| *at >= since).collect())
+            .unwrap_or_default()
+    }
+
+    async fn add_login_failure(&self, key: &str, at: i64) {
+        self.failures
+            .lock()
+            .await
+            .entry(key.to_string())
+            .or_default()
+            .push(at);
+    }
+
+    async fn clear_login_failures(&self, key: &str) {
+        self.failures.lock().await.remove(key);
+    }
+
+    async fn load_cached_login(&self, username: &str) -> Option<CachedLogin> {
+        self.logins.lock().await.get(username).cloned()
+    }
+
+    async fn store_cached_login(&self, item: &CachedLogin) {
+        self.logins
+            .lock()
+            .await
+            .insert(item.username.clone(), item.clone());
+    }
+
+    async fn remove_cached_login(&self, username: &str) {
+        self.logins.lock().await.remove(username);
     }
 }
 
@@ -233,74 +1043,123 @@ mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    fn refresh_item(session_id: &str, token_hash: &str) -> RefreshTokenItem {
+        RefreshTokenItem {
+            username: "admin".to_string(),
+            session_id: session_id.to_string(),
+            token_hash: token_hash.to_string(),
+            issued: mongodb::bson::DateTime::from_millis(0),
+            rooms: vec![Gate {
+                id: 1,
+                retries: 1,
+                name: "room".to_string(),
+                description: "".to_string(),
+                control: false,
+            }],
+            used: false,
+            degraded: false,
+        }
+    }
+
     #[tokio::test]
     async fn cache_test() {
         let cache = Cache::new().await;
 
         assert_eq!(cache.cache.lock().await.len(), 0);
 
-        assert_eq!(
-            cache.remove_by_refresh_token("INVALID_REFRESH_TOKEN").await,
-            None
-        );
+        assert_eq!(cache.use_refresh_token("INVALID_REFRESH_TOKEN").await, None);
 
         cache
-            .store_refresh(
-                "admin",
-                "VALID_REFRESH_TOKEN",
-                &[Gate {
-                    id: 1,
-                    retries: 1,
-                    name: "room".to_string(),
-                    description: "".to_string(),
-                }],
-            )
+            .store_refresh(&refresh_item("SESSION", "VALID_REFRESH_TOKEN"))
             .await;
 
         assert_eq!(cache.cache.lock().await.len(), 1);
 
         assert_eq!(
-            cache.remove_by_refresh_token("VALID_REFRESH_TOKEN").await,
+            cache.use_refresh_token("VALID_REFRESH_TOKEN").await,
+            Some(refresh_item("SESSION", "VALID_REFRESH_TOKEN"))
+        );
+        // kept to notice a replay
+        assert_eq!(
+            cache.use_refresh_token("VALID_REFRESH_TOKEN").await,
             Some(RefreshTokenItem {
-                username: "admin".to_string(),
-                refresh_token: "VALID_REFRESH_TOKEN".to_string(),
-                rooms: vec![Gate {
-                    id: 1,
-                    retries: 1,
-                    name: "room".to_string(),
-                    description: "".to_string(),
-                }],
+                used: true,
+                ..refresh_item("SESSION", "VALID_REFRESH_TOKEN")
             })
         );
 
+        cache.remove_by_username("admin").await;
         assert_eq!(cache.cache.lock().await.len(), 0);
 
         cache
-            .store_refresh(
-                "admin",
-                "VALID_REFRESH_TOKEN1",
-                &[Gate {
-                    id: 1,
-                    retries: 1,
-                    name: "room".to_string(),
-                    description: "".to_string(),
-                }],
-            )
+            .store_refresh(&refresh_item("SESSION1", "VALID_REFRESH_TOKEN1"))
             .await;
         cache
-            .store_refresh(
-                "admin",
-                "VALID_REFRESH_TOKEN2",
-                &[Gate {
-                    id: 1,
-                    retries: 1,
-                    name: "room".to_string(),
-                    description: "".to_string(),
-                }],
-            )
+            .store_refresh(&refresh_item("SESSION2", "VALID_REFRESH_TOKEN2"))
             .await;
         assert_eq!(cache.cache.lock().await.len(), 2);
         cache.remove_by_username("admin").await;
         assert_eq!(cache.cache.lock().await.len(), 0);
     }
+
+    #[tokio::test]
+    async fn prune_used_tokens() {
+        let cache = Cache::new().await;
+        let used = |session_id: &str, token_hash: &str, issued: i64| RefreshTokenItem {
+            issued: mongodb::bson::DateTime::from_millis(issued),
+            used: true,
+            ..refresh_item(session_id, token_hash)
+        };
+
+        for issued in 0..4 {
+            cache
+                .store_refresh(&used("PHONE", &format!("PHONE{}", issued), issued))
+                .await;
+        }
+        cache.store_refresh(&used("LAPTOP", "LAPTOP0", 0)).await;
+        cache.store_refresh(&refresh_item("PHONE", "CURRENT")).await;
+
+        cache.prune_used_tokens("PHONE", 2).await;
+
+        let mut left: Vec<String> = cache.cache.lock().await.keys().cloned().collect();
+
+        left.sort();
+        assert_eq!(left, vec!["CURRENT", "LAPTOP0", "PHONE2", "PHONE3"]);
+
+        cache.remove_refresh_tokens("PHONE").await;
+
+        let left: Vec<String> = cache.cache.lock().await.keys().cloned().collect();
+
+        assert_eq!(left, vec!["LAPTOP0"]);
+    }
+
+    #[tokio::test]
+    async fn remove_one_session() {
+        let cache = Cache::new().await;
+        let session = |session_id: &str, created: i64| SessionItem {
+            session_id: session_id.to_string(),
+            username: "admin".to_string(),
+            user_agent: "curl".to_string(),
+            ip: "10.0.0.1".to_string(),
+            created: mongodb::bson::DateTime::from_millis(created),
+            last_refresh: mongodb::bson::DateTime::from_millis(created),
+        };
+
+        cache.store_session(&session("PHONE", 2)).await;
+        cache.store_session(&session("LAPTOP", 1)).await;
+        cache.store_refresh(&refresh_item("PHONE", "TOKEN1")).await;
+        cache.store_refresh(&refresh_item("LAPTOP", "TOKEN2")).await;
+
+        assert_eq!(
+            cache.sessions("admin").await,
+            vec![session("LAPTOP", 1), session("PHONE", 2)]
+        );
+        assert!(!cache.remove_session("john", "PHONE").await);
+        assert!(cache.remove_session("admin", "PHONE").await);
+        assert!(!cache.remove_session("admin", "PHONE").await);
+        assert_eq!(cache.sessions("admin").await, vec![session("LAPTOP", 1)]);
+        // the other device stays logged in
+        assert_eq!(cache.use_refresh_token("TOKEN1").await, None);
+        assert!(cache.use_refresh_token("TOKEN2").await.is_some());
+    }
 }
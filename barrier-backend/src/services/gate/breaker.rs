@@ -0,0 +1,392 @@
+//! Per-controller circuit breaker.
+//!
+//! After `failure_threshold` consecutive transport failures the circuit of a controller opens and
+//! the commands of all its gates fail immediately instead of waiting through all the retries. A
+//! background probe queries the status of a gate of each open circuit and closes the circuit
+//! once the controller answers. A gate without a status query is closed again by the first
+//! probe, so its commands are retried after `probe_interval_secs`.
+
+use super::{Command, GateDriver, GateError, GateStatus};
+use crate::{
+    config::Breaker,
+    services::db::{Db, EventType},
+    structs::{health, ControllerResult, Gate},
+};
+use actix_web::rt;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CircuitState {
+    Closed,
+    Open,
+}
+
+struct Circuit {
+    /// Gates of the controller seen so far by name, probed for its recovery.
+    gates: BTreeMap<String, Gate>,
+    failures: u32,
+    opened_at: Option<i64>,
+}
+
+impl Circuit {
+    fn state(&self) -> CircuitState {
+        match self.opened_at {
+            Some(_) => CircuitState::Open,
+            None => CircuitState::Closed,
+        }
+    }
+}
+
+pub struct CircuitBreaker {
+    inner: Arc<dyn GateDriver + Send + Sync>,
+    failure_threshold: u32,
+    /// Circuits by `GateDriver::endpoint`.
+    circuits: std::sync::Mutex<HashMap<String, Circuit>>,
+    db: Arc<Mutex<Box<dyn Db + Send>>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        inner: Arc<dyn GateDriver + Send + Sync>,
+        config: &Breaker,
+        db: Arc<Mutex<Box<dyn Db + Send>>>,
+    ) -> Self {
+        Self {
+            inner,
+            failure_threshold: config.failure_threshold,
+            circuits: std::sync::Mutex::new(HashMap::new()),
+            db,
+        }
+    }
+
+    fn is_open(&self, gate: &Gate) -> bool {
+        self.circuits
+            .lock()
+            .unwrap()
+            .get(&self.inner.endpoint(gate))
+            .and_then(|circuit| circuit.opened_at)
+            .is_some()
+    }
+
+    /// Counts the outcome of a command, returns the audit event when the circuit changes state.
+    fn record<T>(&self, gate: &Gate, result: &Result<T, GateError>) -> Option<EventType> {
+        let endpoint = self.inner.endpoint(gate);
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(endpoint.clone()).or_insert_with(|| Circuit {
+            gates: BTreeMap::new(),
+            failures: 0,
+            opened_at: None,
+        });
+
+        circuit
+            .gates
+            .entry(gate.name.clone())
+            .or_insert_with(|| gate.clone());
+
+        match result {
+            Err(e) if e.is_transport() => {
+                circuit.failures += 1;
+
+                if circuit.opened_at.is_none() && circuit.failures >= self.failure_threshold {
+                    warn!(
+                        "opening the circuit of controller {} after {} failures: {}",
+                        endpoint, circuit.failures, e
+                    );
+                    circuit.opened_at = Some(Utc::now().timestamp());
+
+                    return Some(EventType::CircuitOpened {
+                        gate: gate.name.clone(),
+                        reason: format!("{} failures, last: {}", circuit.failures, e),
+                    });
+                }
+
+                None
+            }
+            // any answer means that the controller is alive
+            _ => {
+                circuit.failures = 0;
+                circuit.opened_at.take().map(|_| {
+                    info!("closing the circuit of controller {}", endpoint);
+                    EventType::CircuitClosed {
+                        gate: gate.name.clone(),
+                    }
+                })
+            }
+        }
+    }
+
+    async fn audit(&self, event: Option<EventType>) {
+        if let Some(event) = event {
+            self.db.lock().await.log_event("", "", "", event).await;
+        }
+    }
+
+    /// Queries the controllers of the open circuits once, through one of their gates.
+    pub async fn probe(&self) {
+        let open: Vec<Gate> = self
+            .circuits
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|circuit| circuit.opened_at.is_some())
+            .filter_map(|circuit| circuit.gates.values().next().cloned())
+            .collect();
+
+        for gate in open {
+            let result = self.inner.status(&gate).await;
+
+            self.audit(self.record(&gate, &result)).await;
+        }
+    }
+
+    /// Probes the open circuits each `interval` until the server stops.
+    pub fn spawn(self: Arc<Self>, interval: Duration) {
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+                self.probe().await;
+            }
+        });
+    }
+
+    /// False while any circuit is open.
+    pub fn healthy(&self) -> bool {
+        self.circuits
+            .lock()
+            .unwrap()
+            .values()
+            .all(|circuit| circuit.state() == CircuitState::Closed)
+    }
+
+    /// State of the circuits of `gates`, as far as their controllers were used.
+    pub fn health(&self, gates: &[Gate]) -> health::Details {
+        let circuits = self.circuits.lock().unwrap();
+        let mut details: Vec<_> = gates
+            .iter()
+            .filter_map(|gate| {
+                let circuit = circuits.get(&self.inner.endpoint(gate))?;
+
+                Some(health::Circuit {
+                    id: gate.id,
+                    gate: gate.name.clone(),
+                    state: circuit.state(),
+                    failures: circuit.failures,
+                    opened_at: circuit.opened_at,
+                })
+            })
+            .collect();
+
+        details.sort_by(|a, b| a.gate.cmp(&b.gate));
+
+        health::Details {
+            healthy: details
+                .iter()
+                .all(|circuit| circuit.state == CircuitState::Closed),
+            circuits: details,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl GateDriver for CircuitBreaker {
+    async fn execute(&self, gate: &Gate, command: Command) -> Result<ControllerResult, GateError> {
+        if self.failure_threshold == 0 {
+            return self.inner.execute(gate, command).await;
+        }
+
+        if self.is_open(gate) {
+            return Err(GateError::CircuitOpen(gate.name.clone()));
+        }
+
+        let result = self.inner.execute(gate, command).await;
+
+        self.audit(self.record(gate, &result)).await;
+
+        result
+    }
+
+    async fn status(&self, gate: &Gate) -> Result<GateStatus, GateError> {
+        self.inner.status(gate).await
+    }
+
+    fn endpoint(&self, gate: &Gate) -> String {
+        self.inner.endpoint(gate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::db::Cache;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+    /// Controller which can be switched off and on.
+    struct Flaky {
+        down: AtomicBool,
+        calls: AtomicU32,
+    }
+
+    fn flaky() -> Arc<Flaky> {
+        Arc::new(Flaky {
+            down: AtomicBool::new(true),
+            calls: AtomicU32::new(0),
+        })
+    }
+
+    #[async_trait::async_trait]
+    impl GateDriver for Flaky {
+        async fn execute(&self, _: &Gate, _: Command) -> Result<ControllerResult, GateError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if self.down.load(Ordering::SeqCst) {
+                return Err(GateError::Unreachable("connection refused".to_string()));
+            }
+
+            Ok(ControllerResult {
+                code: 0,
+                confirmed: None,
+                attempts: 1,
+            })
+        }
+
+        async fn status(&self, _: &Gate) -> Result<GateStatus, GateError> {
+            match self.down.load(Ordering::SeqCst) {
+                true => Err(GateError::Unreachable("connection refused".to_string())),
+                false => Ok(GateStatus::Closed),
+            }
+        }
+
+        /// The door has a controller of its own.
+        fn endpoint(&self, gate: &Gate) -> String {
+            match gate.name.as_str() {
+                "door" => "south".to_string(),
+                _ => "north".to_string(),
+            }
+        }
+    }
+
+    fn gate() -> Gate {
+        Gate {
+            id: 1,
+            retries: 1,
+            name: "barrier".to_string(),
+            description: "".to_string(),
+            control: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn open_and_recover() {
+        let flaky = flaky();
+        let db: Box<dyn Db + Send> = Box::new(Cache::new().await);
+        let breaker = CircuitBreaker::new(
+            flaky.clone(),
+            &Breaker {
+                failure_threshold: 2,
+                probe_interval_secs: 1,
+            },
+            Arc::new(Mutex::new(db)),
+        );
+
+        for _ in 0..2 {
+            assert!(breaker.execute(&gate(), Command::Open).await.is_err());
+        }
+
+        assert_eq!(
+            breaker.execute(&gate(), Command::Open).await,
+            Err(GateError::CircuitOpen("barrier".to_string()))
+        );
+        assert_eq!(flaky.calls.load(Ordering::SeqCst), 2);
+        assert!(!breaker.healthy());
+        assert_eq!(
+            breaker.health(&[gate()]).circuits[0].state,
+            CircuitState::Open
+        );
+
+        // still down, the probe keeps the circuit open
+        breaker.probe().await;
+        assert!(!breaker.healthy());
+
+        flaky.down.store(false, Ordering::SeqCst);
+        breaker.probe().await;
+
+        assert!(breaker.healthy());
+        assert_eq!(
+            breaker.health(&[gate()]).circuits,
+            vec![health::Circuit {
+                id: 1,
+                gate: "barrier".to_string(),
+                state: CircuitState::Closed,
+                failures: 0,
+                opened_at: None,
+            }]
+        );
+        assert!(breaker.execute(&gate(), Command::Open).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn circuit_per_controller() {
+        let db: Box<dyn Db + Send> = Box::new(Cache::new().await);
+        let breaker = CircuitBreaker::new(
+            flaky(),
+            &Breaker {
+                failure_threshold: 2,
+                probe_interval_secs: 1,
+            },
+            Arc::new(Mutex::new(db)),
+        );
+        let gate = |id, name: &str| Gate {
+            id,
+            name: name.to_string(),
+            ..gate()
+        };
+
+        // a failure of each gate, both on the same controller
+        assert!(breaker
+            .execute(&gate(1, "barrier"), Command::Open)
+            .await
+            .is_err());
+        assert!(breaker
+            .execute(&gate(2, "gate"), Command::Open)
+            .await
+            .is_err());
+        assert_eq!(
+            breaker.execute(&gate(1, "barrier"), Command::Open).await,
+            Err(GateError::CircuitOpen("barrier".to_string()))
+        );
+        assert_eq!(
+            breaker.execute(&gate(3, "garage"), Command::Open).await,
+            Err(GateError::CircuitOpen("garage".to_string()))
+        );
+
+        // the same device number on another controller
+        assert_eq!(
+            breaker.execute(&gate(1, "door"), Command::Open).await,
+            Err(GateError::Unreachable("connection refused".to_string()))
+        );
+        assert_eq!(
+            breaker
+                .health(&[gate(1, "door"), gate(3, "garage")])
+                .circuits
+                .iter()
+                .map(|circuit| (circuit.gate.as_str(), circuit.state))
+                .collect::<Vec<_>>(),
+            vec![
+                ("door", CircuitState::Closed),
+                ("garage", CircuitState::Open)
+            ]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! Asynchronous results of `ControlAccess`.
+//!
+//! The controller reports the physical outcome of a command by calling the method named in
+//! `MethodNameForAnswer` on `IPSERVER:PORTSERVER`. The call carries a struct with the same
+//! addressing members as the request (`ComPort`, `PKUAddress`, `DeviceAddress`,
+//! `AggregateAddress`) and the outcome in `Result`; a negative `Result` means failure.
+//!
+//! Nothing else in the call identifies the command, so a device has at most one command
+//! waiting for its result. The controller is told by the address calling: a result is taken
+//! only from the addresses of the controller the command went to.
+
+use super::{rpc::Value, xmlrpc::XmlRpcParams};
+use crate::config::Config;
+use log::{debug, error, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Mutex,
+};
+use tokio::sync::oneshot;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceKey {
+    /// Name in `[controllers]`, empty for `gate_server`.
+    pub controller: String,
+    pub com_port: i32,
+    pub pku_address: i32,
+    pub device_address: i32,
+    pub aggregate_address: i32,
+}
+
+impl DeviceKey {
+    pub fn new(controller: &str, params: &XmlRpcParams) -> Self {
+        Self {
+            controller: controller.to_string(),
+            com_port: params.com_port,
+            pku_address: params.pku_address,
+            device_address: params.device_address,
+            aggregate_address: params.aggregate_address,
+        }
+    }
+
+    fn from_value(controller: &str, value: &Value) -> Option<Self> {
+        let member = |name| value.member(name).and_then(Value::as_int);
+
+        Some(Self {
+            controller: controller.to_string(),
+            com_port: member("ComPort")?,
+            pku_address: member("PKUAddress")?,
+            device_address: member("DeviceAddress")?,
+            aggregate_address: member("AggregateAddress")?,
+        })
+    }
+}
+
+/// Open requests waiting for the controller to call back.
+pub struct PendingResults {
+    path: String,
+    methods: HashSet<String>,
+    /// Addresses of each controller, the only ones allowed to call.
+    controllers: HashMap<String, HashSet<IpAddr>>,
+    waiters: Mutex<HashMap<DeviceKey, oneshot::Sender<i32>>>,
+}
+
+impl PendingResults {
+    pub fn new(
+        path: String,
+        methods: HashSet<String>,
+        controllers: HashMap<String, HashSet<IpAddr>>,
+    ) -> Self {
+        Self {
+            path,
+            methods,
+            controllers,
+            waiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `None` when the `[callback]` section is not configured. The controllers without
+    /// `callback_from` (`allow` of `gate_server`) are resolved once, here.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let callback = config.callback.as_ref()?;
+        let methods = config
+            .gate_mapping
+            .values()
+            .map(|gate| XmlRpcParams::resolve(gate.id, &config.xmlrpc, &gate.xmlrpc))
+            .map(|params| params.method_name_for_answer)
+            .collect();
+        let controllers = std::iter::once((String::new(), &config.gate_server, &callback.allow))
+            .chain(config.controllers.iter().map(|(name, controller)| {
+                (name.clone(), &controller.url, &controller.callback_from)
+            }))
+            .map(|(name, url, from)| {
+                let addresses = if from.is_empty() {
+                    resolve(url)
+                } else {
+                    from.iter().filter_map(|ip| ip.parse().ok()).collect()
+                };
+
+                (name, addresses)
+            })
+            .collect();
+
+        Some(Self::new(callback.path.clone(), methods, controllers))
+    }
+
+    /// Path of the callback endpoint in the actix app.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether `ip` is one of the controllers.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        self.controllers
+            .values()
+            .any(|addresses| addresses.contains(&ip))
+    }
+
+    /// Returns `None` while another command of the device waits for its result.
+    pub fn register(&self, key: DeviceKey) -> Option<oneshot::Receiver<i32>> {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+
+        // forget the requests which gave up waiting
+        waiters.retain(|_, sender| !sender.is_closed());
+
+        if waiters.contains_key(&key) {
+            return None;
+        }
+
+        waiters.insert(key, tx);
+        Some(rx)
+    }
+
+    /// Handles a callback from the controller at `ip`, returns the XML-RPC fault for malformed
+    /// calls.
+    pub fn accept(&self, ip: IpAddr, method: &str, params: &[Value]) -> Result<(), (i32, String)> {
+        if !self.methods.contains(method) {
+            return Err((-32601, format!("unknown method {}", method)));
+        }
+
+        let value = params
+            .first()
+            .ok_or_else(|| (-32602, "missing result struct".to_string()))?;
+        let code = value
+            .member("Result")
+            .and_then(Value::as_int)
+            .ok_or_else(|| (-32602, "missing Result".to_string()))?;
+        let mut keys = Vec::new();
+
+        for (controller, addresses) in &self.controllers {
+            if addresses.contains(&ip) {
+                keys.push(
+                    DeviceKey::from_value(controller, value)
+                        .ok_or_else(|| (-32602, "missing device address".to_string()))?,
+                );
+            }
+        }
+
+        let mut waiters = self.waiters.lock().unwrap();
+
+        keys.retain(|key| waiters.contains_key(key));
+
+        match keys.as_slice() {
+            [key] => {
+                debug!("result {} for device {:?}", code, key);
+                if let Some(sender) = waiters.remove(key) {
+                    sender.send(code).ok();
+                }
+            }
+            [] => warn!("unexpected result {} from {}", code, ip),
+            // controllers sharing an address can't be told apart, let the commands time out
+            keys => warn!("result {} from {} matches {:?}, dropped", code, ip, keys),
+        }
+
+        Ok(())
+    }
+}
+
+/// Addresses of the host of `url`.
+fn resolve(url: &str) -> HashSet<IpAddr> {
+    match reqwest::Url::parse(url).map(|url| url.socket_addrs(|| None)) {
+        Ok(Ok(addrs)) => addrs.into_iter().map(|addr| addr.ip()).collect(),
+        _ => {
+            error!(
+                "can't resolve controller {}, its callbacks are refused",
+                url
+            );
+            HashSet::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const SERVER: &str = "10.0.1.10";
+    const NORTH: &str = "10.0.2.10";
+
+    fn key(controller: &str, device_address: i32) -> DeviceKey {
+        DeviceKey {
+            controller: controller.to_string(),
+            com_port: 2,
+            pku_address: 0,
+            device_address,
+            aggregate_address: 1,
+        }
+    }
+
+    fn result(device_address: i32, code: i32) -> Value {
+        Value::Struct(vec![
+            ("ComPort".to_string(), Value::Int(2)),
+            ("PKUAddress".to_string(), Value::Int(0)),
+            ("DeviceAddress".to_string(), Value::Int(device_address)),
+            ("AggregateAddress".to_string(), Value::Int(1)),
+            ("Result".to_string(), Value::Int(code)),
+        ])
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn pending() -> PendingResults {
+        PendingResults::new(
+            "/".to_string(),
+            HashSet::from(["Result".to_string()]),
+            HashMap::from([
+                (String::new(), HashSet::from([ip(SERVER)])),
+                ("north".to_string(), HashSet::from([ip(NORTH)])),
+            ]),
+        )
+    }
+
+    #[tokio::test]
+    async fn correlate_by_device() {
+        let pending = pending();
+
+        let first = pending.register(key("", 1)).unwrap();
+        let second = pending.register(key("", 2)).unwrap();
+
+        // the result can't tell two commands of a device apart
+        assert!(pending.register(key("", 1)).is_none());
+
+        assert_eq!(
+            pending.accept(ip(SERVER), "Result", &[result(2, -1)]),
+            Ok(())
+        );
+        assert_eq!(
+            pending.accept(ip(SERVER), "Result", &[result(1, 0)]),
+            Ok(())
+        );
+
+        assert_eq!(first.await, Ok(0));
+        assert_eq!(second.await, Ok(-1));
+
+        // free again once answered or given up
+        assert!(pending.register(key("", 1)).is_some());
+        assert!(pending.register(key("", 1)).is_some());
+    }
+
+    #[tokio::test]
+    async fn correlate_by_controller() {
+        let pending = pending();
+
+        // the same bus addresses behind two controllers
+        let server = pending.register(key("", 1)).unwrap();
+        let mut north = pending.register(key("north", 1)).unwrap();
+
+        assert!(pending.allows(ip(NORTH)));
+        assert!(!pending.allows(ip("10.0.9.9")));
+
+        assert_eq!(
+            pending.accept(ip(SERVER), "Result", &[result(1, -1)]),
+            Ok(())
+        );
+        assert_eq!(server.await, Ok(-1));
+        assert!(north.try_recv().is_err());
+
+        assert_eq!(pending.accept(ip(NORTH), "Result", &[result(1, 0)]), Ok(()));
+        assert_eq!(north.await, Ok(0));
+    }
+
+    #[test]
+    fn reject_malformed_calls() {
+        let pending = pending();
+
+        assert_eq!(
+            pending
+                .accept(ip(SERVER), "Other", &[result(1, 0)])
+                .unwrap_err()
+                .0,
+            -32601
+        );
+        assert_eq!(
+            pending.accept(ip(SERVER), "Result", &[]).unwrap_err().0,
+            -32602
+        );
+        assert_eq!(
+            pending
+                .accept(ip(SERVER), "Result", &[Value::Struct(vec![])])
+                .unwrap_err()
+                .0,
+            -32602
+        );
+    }
+}
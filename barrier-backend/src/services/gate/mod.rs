@@ -0,0 +1,370 @@
+use crate::{
+    config::Config,
+    structs::{ControllerResult, Errors, Gate},
+};
+use derive_more::Display;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+pub mod breaker;
+pub mod callback;
+pub mod retry;
+pub mod rpc;
+#[cfg(test)]
+pub mod sim;
+pub mod status;
+pub mod xmlrpc;
+
+use callback::PendingResults;
+use xmlrpc::XmlRpcDriver;
+
+/// Standard XML-RPC fault codes, anything else is controller specific.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum Fault {
+    #[display(fmt = "parse error")]
+    Parse,
+    #[display(fmt = "invalid request")]
+    InvalidRequest,
+    #[display(fmt = "method not found")]
+    MethodNotFound,
+    #[display(fmt = "invalid params")]
+    InvalidParams,
+    #[display(fmt = "internal error")]
+    Internal,
+    #[display(fmt = "transport error")]
+    Transport,
+    #[display(fmt = "code {}", _0)]
+    Controller(i32),
+}
+
+impl From<i32> for Fault {
+    fn from(code: i32) -> Self {
+        match code {
+            -32700 => Fault::Parse,
+            -32600 => Fault::InvalidRequest,
+            -32601 => Fault::MethodNotFound,
+            -32602 => Fault::InvalidParams,
+            -32603 => Fault::Internal,
+            -32300 => Fault::Transport,
+            code => Fault::Controller(code),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+pub enum GateError {
+    #[display(fmt = "controller is unreachable: {}", _0)]
+    Unreachable(String),
+    #[display(fmt = "controller fault ({}): {}", fault, message)]
+    Fault { fault: Fault, message: String },
+    #[display(fmt = "controller rejected the command with code {}", code)]
+    Rejected { code: i32 },
+    #[display(fmt = "malformed controller response: {}", _0)]
+    Malformed(String),
+    #[display(fmt = "no driver configured for gate {}", _0)]
+    NoDriver(String),
+    #[display(fmt = "circuit breaker of gate {} is open", _0)]
+    CircuitOpen(String),
+    #[display(fmt = "another command of gate {} awaits its confirmation", _0)]
+    Busy(String),
+}
+
+impl GateError {
+    /// The controller did not answer at all.
+    pub fn is_transport(&self) -> bool {
+        matches!(
+            self,
+            GateError::Unreachable(_)
+                | GateError::Fault {
+                    fault: Fault::Transport,
+                    ..
+                }
+        )
+    }
+}
+
+impl From<GateError> for Errors {
+    fn from(e: GateError) -> Self {
+        match e {
+            GateError::Unreachable(_)
+            | GateError::Fault {
+                fault: Fault::Transport,
+                ..
+            } => Errors::ControllerUnreachable,
+            // a gate without a driver is a configuration bug, not an outage
+            GateError::NoDriver(_) => Errors::GateMisconfigured,
+            GateError::CircuitOpen(_) => Errors::CircuitOpen,
+            GateError::Busy(_) => Errors::GateBusy,
+            GateError::Fault { .. } | GateError::Rejected { .. } | GateError::Malformed(_) => {
+                Errors::ControllerRejected
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum Command {
+    /// Pulse the gate open, it closes by itself.
+    #[display(fmt = "open")]
+    Open,
+    #[display(fmt = "close")]
+    Close,
+    /// Keep the gate open until it is released.
+    #[display(fmt = "hold")]
+    Hold,
+    /// Return a held gate to normal operation.
+    #[display(fmt = "release")]
+    Release,
+}
+
+/// Physical state of a gate as last reported by its controller.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GateStatus {
+    #[display(fmt = "open")]
+    Open,
+    #[display(fmt = "closed")]
+    Closed,
+    /// The controller did not answer.
+    #[display(fmt = "offline")]
+    Offline,
+    /// The driver cannot query the state or it was not polled yet.
+    #[display(fmt = "unknown")]
+    Unknown,
+}
+
+#[async_trait::async_trait]
+pub trait GateDriver {
+    async fn execute(&self, gate: &Gate, command: Command) -> Result<ControllerResult, GateError>;
+
+    async fn status(&self, _gate: &Gate) -> Result<GateStatus, GateError> {
+        Ok(GateStatus::Unknown)
+    }
+
+    /// Controller the commands of the gate go to, the gates sharing it fail together.
+    fn endpoint(&self, gate: &Gate) -> String {
+        gate.name.clone()
+    }
+}
+
+/// Drivers a gate may select in `gate_mapping`, see `GateDrivers::from_config`.
+pub const DRIVERS: [&str; 2] = ["xmlrpc", "dry_run"];
+
+/// Dispatches every gate to the driver selected for it in `gate_mapping`.
+pub struct GateDrivers {
+    drivers: HashMap<String, Box<dyn GateDriver + Send + Sync>>,
+    gates: HashMap<String, String>,
+}
+
+impl GateDrivers {
+    pub fn new() -> Self {
+        Self {
+            drivers: HashMap::new(),
+            gates: HashMap::new(),
+        }
+    }
+
+    pub fn from_config(config: &Config, callbacks: Option<Arc<PendingResults>>) -> Self {
+        let mut drivers = Self::new();
+
+        drivers.register(
+            "xmlrpc",
+            Box::new(XmlRpcDriver::from_config(config, callbacks)),
+        );
+        drivers.register("dry_run", Box::new(DryRunDriver));
+
+        for (name, gate) in &config.gate_mapping {
+            let driver = if config.dry_run {
+                "dry_run"
+            } else {
+                &gate.driver
+            };
+
+            drivers.assign(name, driver);
+        }
+
+        drivers
+    }
+
+    pub fn register(&mut self, name: &str, driver: Box<dyn GateDriver + Send + Sync>) {
+        self.drivers.insert(name.to_string(), driver);
+    }
+
+    pub fn assign(&mut self, gate: &str, driver: &str) {
+        self.gates.insert(gate.to_string(), driver.to_string());
+    }
+}
+
+impl GateDrivers {
+    fn driver(&self, gate: &Gate) -> Result<&(dyn GateDriver + Send + Sync), GateError> {
+        self.gates
+            .get(&gate.name)
+            .and_then(|driver| self.drivers.get(driver))
+            .map(|driver| driver.as_ref())
+            .ok_or_else(|| {
+                error!("no driver configured for gate {}", gate.name);
+                GateError::NoDriver(gate.name.clone())
+            })
+    }
+}
+
+#[async_trait::async_trait]
+impl GateDriver for GateDrivers {
+    async fn execute(&self, gate: &Gate, command: Command) -> Result<ControllerResult, GateError> {
+        self.driver(gate)?.execute(gate, command).await
+    }
+
+    async fn status(&self, gate: &Gate) -> Result<GateStatus, GateError> {
+        self.driver(gate)?.status(gate).await
+    }
+
+    fn endpoint(&self, gate: &Gate) -> String {
+        match self.driver(gate) {
+            Ok(driver) => driver.endpoint(gate),
+            Err(_) => gate.name.clone(),
+        }
+    }
+}
+
+/// Pretends that every command succeeded, used when `dry_run` is set.
+pub struct DryRunDriver;
+
+#[async_trait::async_trait]
+impl GateDriver for DryRunDriver {
+    async fn execute(&self, gate: &Gate, command: Command) -> Result<ControllerResult, GateError> {
+        debug!(
+            "emulate {} gate {} with {} retries",
+            command, gate.id, gate.retries
+        );
+        Ok(ControllerResult {
+            code: 0,
+            confirmed: None,
+            attempts: 1,
+        })
+    }
+
+    async fn status(&self, _gate: &Gate) -> Result<GateStatus, GateError> {
+        Ok(GateStatus::Closed)
+    }
+}
+
+#[cfg(test)]
+use std::sync::Mutex;
+
+#[cfg(test)]
+pub struct FakeGateDriver {
+    executed: Mutex<Vec<(i32, Command)>>,
+    error: Option<GateError>,
+}
+
+#[cfg(test)]
+impl FakeGateDriver {
+    pub fn new() -> Self {
+        Self {
+            executed: Mutex::new(Vec::new()),
+            error: None,
+        }
+    }
+
+    pub fn failing(error: GateError) -> Self {
+        Self {
+            executed: Mutex::new(Vec::new()),
+            error: Some(error),
+        }
+    }
+
+    pub fn executed(&self) -> Vec<(i32, Command)> {
+        self.executed.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl GateDriver for FakeGateDriver {
+    async fn execute(&self, gate: &Gate, command: Command) -> Result<ControllerResult, GateError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+
+        self.executed.lock().unwrap().push((gate.id, command));
+        Ok(ControllerResult {
+            code: 0,
+            confirmed: None,
+            attempts: 1,
+        })
+    }
+
+    async fn status(&self, _gate: &Gate) -> Result<GateStatus, GateError> {
+        match &self.error {
+            Some(error) => Err(error.clone()),
+            None => Ok(GateStatus::Closed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn gate(id: i32, name: &str) -> Gate {
+        Gate {
+            id,
+            retries: 1,
+            name: name.to_string(),
+            description: "".to_string(),
+            control: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_by_gate_name() {
+        let mut drivers = GateDrivers::new();
+
+        drivers.register(
+            "fake",
+            Box::new(FakeGateDriver::failing(GateError::Rejected { code: -1 })),
+        );
+        drivers.register("dry_run", Box::new(DryRunDriver));
+        drivers.assign("barrier", "dry_run");
+        drivers.assign("door", "fake");
+
+        assert_eq!(
+            drivers.execute(&gate(1, "barrier"), Command::Open).await,
+            Ok(ControllerResult {
+                code: 0,
+                confirmed: None,
+                attempts: 1,
+            })
+        );
+        assert_eq!(
+            drivers.execute(&gate(2, "door"), Command::Hold).await,
+            Err(GateError::Rejected { code: -1 })
+        );
+        assert_eq!(
+            drivers.execute(&gate(3, "unknown"), Command::Open).await,
+            Err(GateError::NoDriver("unknown".to_string()))
+        );
+        assert_eq!(
+            drivers.status(&gate(1, "barrier")).await,
+            Ok(GateStatus::Closed)
+        );
+        assert_eq!(
+            drivers.status(&gate(3, "unknown")).await,
+            Err(GateError::NoDriver("unknown".to_string()))
+        );
+    }
+
+    #[test]
+    fn missing_driver_is_not_an_outage() {
+        let error = GateError::NoDriver("unknown".to_string());
+
+        assert!(!error.is_transport());
+        assert!(matches!(Errors::from(error), Errors::GateMisconfigured));
+        assert!(matches!(
+            Errors::from(GateError::Unreachable("timeout".to_string())),
+            Errors::ControllerUnreachable
+        ));
+    }
+}
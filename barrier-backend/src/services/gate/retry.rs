@@ -0,0 +1,260 @@
+//! Retries of a gate command with exponential backoff.
+
+use super::{Fault, GateError};
+use crate::config::RetryOverrides;
+use log::{debug, warn};
+use rand::Rng;
+use std::future::Future;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub backoff_multiplier: f64,
+    pub jitter: f64,
+    pub attempt_timeout: Duration,
+    pub retry_transport_errors: bool,
+}
+
+impl RetryPolicy {
+    pub fn resolve(retries: i32, defaults: &RetryOverrides, gate: &RetryOverrides) -> Self {
+        Self {
+            attempts: retries.max(1) as u32,
+            initial_backoff: Duration::from_millis(
+                gate.initial_backoff_ms
+                    .or(defaults.initial_backoff_ms)
+                    .unwrap_or(100),
+            ),
+            max_backoff: Duration::from_millis(
+                gate.max_backoff_ms
+                    .or(defaults.max_backoff_ms)
+                    .unwrap_or(2000),
+            ),
+            backoff_multiplier: gate
+                .backoff_multiplier
+                .or(defaults.backoff_multiplier)
+                .unwrap_or(2.0),
+            jitter: gate.jitter.or(defaults.jitter).unwrap_or(0.2),
+            attempt_timeout: Duration::from_millis(
+                gate.attempt_timeout_ms
+                    .or(defaults.attempt_timeout_ms)
+                    .unwrap_or(3000),
+            ),
+            retry_transport_errors: gate
+                .retry_transport_errors
+                .or(defaults.retry_transport_errors)
+                .unwrap_or(true),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.initial_backoff > self.max_backoff {
+            return Err("initial_backoff_ms must not exceed max_backoff_ms".to_string());
+        }
+
+        if !(1.0..).contains(&self.backoff_multiplier) {
+            return Err(format!(
+                "backoff_multiplier must be at least 1, got {}",
+                self.backoff_multiplier
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(format!(
+                "jitter must be between 0 and 1, got {}",
+                self.jitter
+            ));
+        }
+
+        if self.attempt_timeout.is_zero() {
+            return Err("attempt_timeout_ms must be positive".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Delay after the failed `attempt`, counting from 1, before the jitter is applied.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.backoff_multiplier.powi(attempt as i32 - 1);
+
+        self.initial_backoff
+            .mul_f64(factor.min(u32::MAX as f64))
+            .min(self.max_backoff)
+    }
+
+    fn jittered(&self, delay: Duration) -> Duration {
+        if self.jitter == 0.0 {
+            return delay;
+        }
+
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..self.jitter))
+    }
+
+    pub fn is_retryable(&self, error: &GateError) -> bool {
+        match error {
+            e if e.is_transport() => self.retry_transport_errors,
+            // the request itself is wrong, sending it again will not help
+            GateError::Fault {
+                fault:
+                    Fault::Parse | Fault::InvalidRequest | Fault::MethodNotFound | Fault::InvalidParams,
+                ..
+            }
+            | GateError::NoDriver(_)
+            | GateError::CircuitOpen(_)
+            | GateError::Busy(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Runs `attempt` until it succeeds, fails permanently or the attempts run out. Returns the
+    /// last outcome and the number of attempts made.
+    pub async fn run<T, F, Fut>(&self, what: &str, mut attempt: F) -> (Result<T, GateError>, u32)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, GateError>>,
+    {
+        let mut number = 1;
+
+        loop {
+            let started = Instant::now();
+            let result = match timeout(self.attempt_timeout, attempt()).await {
+                Ok(result) => result,
+                Err(_) => Err(GateError::Unreachable(format!(
+                    "no answer within {} ms",
+                    self.attempt_timeout.as_millis()
+                ))),
+            };
+            let elapsed = started.elapsed().as_millis();
+
+            let e = match result {
+                Ok(ok) => {
+                    debug!(
+                        "{}: attempt {}/{} succeeded in {} ms",
+                        what, number, self.attempts, elapsed
+                    );
+                    return (Ok(ok), number);
+                }
+                Err(e) => e,
+            };
+
+            warn!(
+                "{}: attempt {}/{} failed in {} ms: {}",
+                what, number, self.attempts, elapsed, e
+            );
+
+            if number >= self.attempts || !self.is_retryable(&e) {
+                return (Err(e), number);
+            }
+
+            sleep(self.jittered(self.backoff(number))).await;
+            number += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::cell::Cell;
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            backoff_multiplier: 2.0,
+            jitter: 0.0,
+            attempt_timeout: Duration::from_millis(50),
+            retry_transport_errors: true,
+        }
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = policy(6);
+
+        assert_eq!(
+            (1..=5).map(|n| policy.backoff(n)).collect::<Vec<_>>(),
+            vec![1, 2, 4, 5, 5]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn stop_at_first_success() {
+        let calls = Cell::new(0);
+
+        let (result, attempts) = policy(6)
+            .run("test", || {
+                calls.set(calls.get() + 1);
+                let n = calls.get();
+
+                async move {
+                    if n < 3 {
+                        Err(GateError::Unreachable("connection reset".to_string()))
+                    } else {
+                        Ok(n)
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(result, Ok(3));
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn give_up() {
+        let reset = || async { Err::<(), _>(GateError::Unreachable("reset".to_string())) };
+
+        assert_eq!(policy(4).run("test", reset).await.1, 4);
+
+        let mut no_transport = policy(4);
+        no_transport.retry_transport_errors = false;
+
+        assert_eq!(no_transport.run("test", reset).await.1, 1);
+
+        let invalid = || async {
+            Err::<(), _>(GateError::Fault {
+                fault: Fault::InvalidParams,
+                message: "".to_string(),
+            })
+        };
+
+        assert_eq!(policy(4).run("test", invalid).await.1, 1);
+    }
+
+    #[tokio::test]
+    async fn attempt_timeout() {
+        let (result, attempts) = policy(2)
+            .run("test", || {
+                futures::future::pending::<Result<(), GateError>>()
+            })
+            .await;
+
+        assert_eq!(
+            result,
+            Err(GateError::Unreachable("no answer within 50 ms".to_string()))
+        );
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn reject_invalid_policy() {
+        let mut invalid = policy(1);
+        invalid.jitter = 1.5;
+
+        assert!(invalid.validate().is_err());
+
+        invalid = policy(1);
+        invalid.backoff_multiplier = 0.5;
+
+        assert!(invalid.validate().is_err());
+        assert_eq!(policy(1).validate(), Ok(()));
+    }
+}
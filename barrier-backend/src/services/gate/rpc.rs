@@ -0,0 +1,372 @@
+//! Minimal XML-RPC value model, enough to talk to `ControlAccess` controllers.
+
+use derive_more::Display;
+use roxmltree::{Document, Node};
+use simple_xml_builder::XMLElement;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Boolean(bool),
+    String(String),
+    Double(f64),
+    Struct(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    Nil,
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            Value::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn member(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Struct(members) => members.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn to_xml(&self) -> XMLElement {
+        let mut value = XMLElement::new("value");
+        let typed = match self {
+            Value::Int(v) => text_element("int", v),
+            Value::Boolean(v) => text_element("boolean", if *v { 1 } else { 0 }),
+            Value::String(v) => text_element("string", v),
+            Value::Double(v) => text_element("double", v),
+            Value::Nil => XMLElement::new("nil"),
+            Value::Struct(members) => {
+                let mut st = XMLElement::new("struct");
+
+                for (name, v) in members {
+                    let mut member = XMLElement::new("member");
+
+                    member.add_child(text_element("name", name));
+                    member.add_child(v.to_xml());
+                    st.add_child(member);
+                }
+
+                st
+            }
+            Value::Array(values) => {
+                let mut array = XMLElement::new("array");
+                let mut data = XMLElement::new("data");
+
+                for v in values {
+                    data.add_child(v.to_xml());
+                }
+
+                array.add_child(data);
+                array
+            }
+        };
+
+        value.add_child(typed);
+        value
+    }
+}
+
+fn text_element(name: &str, text: impl ToString) -> XMLElement {
+    let mut element = XMLElement::new(name);
+
+    element.add_text(text);
+    element
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Response {
+    Success(Value),
+    Fault { code: i32, message: String },
+}
+
+#[derive(Debug, Display, PartialEq)]
+#[display(fmt = "{}", _0)]
+pub struct ParseError(String);
+
+fn error<T>(message: &str) -> Result<T, ParseError> {
+    Err(ParseError(message.to_string()))
+}
+
+fn elements<'a, 'input>(node: Node<'a, 'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children().filter(|n| n.is_element())
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Result<Node<'a, 'input>, ParseError> {
+    elements(node)
+        .find(|n| n.has_tag_name(name))
+        .ok_or_else(|| {
+            ParseError(format!(
+                "<{}> is missing <{}>",
+                node.tag_name().name(),
+                name
+            ))
+        })
+}
+
+fn text(node: Node) -> String {
+    node.text().unwrap_or_default().to_string()
+}
+
+fn parse_value(node: Node) -> Result<Value, ParseError> {
+    let typed = match elements(node).next() {
+        Some(typed) => typed,
+        // a value without a type element is a string
+        None => return Ok(Value::String(text(node))),
+    };
+
+    match typed.tag_name().name() {
+        "int" | "i4" => text(typed)
+            .trim()
+            .parse()
+            .map(Value::Int)
+            .or_else(|_| error("invalid <int>")),
+        "boolean" => match text(typed).trim() {
+            "0" => Ok(Value::Boolean(false)),
+            "1" => Ok(Value::Boolean(true)),
+            _ => error("invalid <boolean>"),
+        },
+        "double" => text(typed)
+            .trim()
+            .parse()
+            .map(Value::Double)
+            .or_else(|_| error("invalid <double>")),
+        "string" | "dateTime.iso8601" | "base64" => Ok(Value::String(text(typed))),
+        "nil" => Ok(Value::Nil),
+        "struct" => elements(typed)
+            .filter(|n| n.has_tag_name("member"))
+            .map(|member| {
+                let name = text(child(member, "name")?);
+                let value = parse_value(child(member, "value")?)?;
+
+                Ok((name, value))
+            })
+            .collect::<Result<_, _>>()
+            .map(Value::Struct),
+        "array" => elements(child(typed, "data")?)
+            .filter(|n| n.has_tag_name("value"))
+            .map(parse_value)
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        other => Err(ParseError(format!("unsupported value type <{}>", other))),
+    }
+}
+
+pub fn parse_response(body: &str) -> Result<Response, ParseError> {
+    let document = Document::parse(body).map_err(|e| ParseError(e.to_string()))?;
+    let root = document.root_element();
+
+    if !root.has_tag_name("methodResponse") {
+        return error("expected <methodResponse>");
+    }
+
+    if let Ok(fault) = child(root, "fault") {
+        let fault = parse_value(child(fault, "value")?)?;
+        let code = fault.member("faultCode").and_then(Value::as_int);
+        let message = fault.member("faultString").and_then(Value::as_str);
+
+        return match (code, message) {
+            (Some(code), message) => Ok(Response::Fault {
+                code,
+                message: message.unwrap_or_default().to_string(),
+            }),
+            _ => error("<fault> without faultCode"),
+        };
+    }
+
+    let param = child(child(root, "params")?, "param")?;
+
+    parse_value(child(param, "value")?).map(Response::Success)
+}
+
+pub fn parse_call(body: &str) -> Result<(String, Vec<Value>), ParseError> {
+    let document = Document::parse(body).map_err(|e| ParseError(e.to_string()))?;
+    let root = document.root_element();
+
+    if !root.has_tag_name("methodCall") {
+        return error("expected <methodCall>");
+    }
+
+    let method_name = text(child(root, "methodName")?);
+    let params = match child(root, "params") {
+        Ok(params) => elements(params)
+            .filter(|n| n.has_tag_name("param"))
+            .map(|param| parse_value(child(param, "value")?))
+            .collect::<Result<_, _>>()?,
+        Err(_) => Vec::new(),
+    };
+
+    Ok((method_name, params))
+}
+
+pub fn write_call(method: &str, values: &[Value]) -> XMLElement {
+    let mut call = XMLElement::new("methodCall");
+    let mut params = XMLElement::new("params");
+
+    for value in values {
+        let mut param = XMLElement::new("param");
+
+        param.add_child(value.to_xml());
+        params.add_child(param);
+    }
+
+    call.add_child(text_element("methodName", method));
+    call.add_child(params);
+
+    call
+}
+
+pub fn write_response(value: &Value) -> XMLElement {
+    let mut response = XMLElement::new("methodResponse");
+    let mut params = XMLElement::new("params");
+    let mut param = XMLElement::new("param");
+
+    param.add_child(value.to_xml());
+    params.add_child(param);
+    response.add_child(params);
+
+    response
+}
+
+pub fn write_fault(code: i32, message: &str) -> XMLElement {
+    let mut response = XMLElement::new("methodResponse");
+    let mut fault = XMLElement::new("fault");
+
+    fault.add_child(
+        Value::Struct(vec![
+            ("faultCode".to_string(), Value::Int(code)),
+            (
+                "faultString".to_string(),
+                Value::String(message.to_string()),
+            ),
+        ])
+        .to_xml(),
+    );
+    response.add_child(fault);
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_success() {
+        let body = r#"<?xml version="1.0"?>
+<methodResponse>
+  <params>
+    <param>
+      <value><struct>
+        <member><name>Result</name><value><int>0</int></value></member>
+        <member><name>Message</name><value>done</value></member>
+      </struct></value>
+    </param>
+  </params>
+</methodResponse>"#;
+
+        assert_eq!(
+            parse_response(body),
+            Ok(Response::Success(Value::Struct(vec![
+                ("Result".to_string(), Value::Int(0)),
+                ("Message".to_string(), Value::String("done".to_string())),
+            ])))
+        );
+    }
+
+    #[test]
+    fn parse_fault() {
+        let body = r#"<?xml version="1.0"?>
+<methodResponse>
+  <fault>
+    <value><struct>
+      <member><name>faultCode</name><value><int>4</int></value></member>
+      <member><name>faultString</name><value><string>Device is busy</string></value></member>
+    </struct></value>
+  </fault>
+</methodResponse>"#;
+
+        assert_eq!(
+            parse_response(body),
+            Ok(Response::Fault {
+                code: 4,
+                message: "Device is busy".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let value = Value::Struct(vec![
+            ("DeviceAddress".to_string(), Value::Int(3)),
+            ("Result".to_string(), Value::Int(-1)),
+            ("Ok".to_string(), Value::Boolean(false)),
+            (
+                "Tags".to_string(),
+                Value::Array(vec![Value::String("a&b".to_string()), Value::Nil]),
+            ),
+        ]);
+
+        assert_eq!(
+            parse_response(&write_response(&value).to_string()),
+            Ok(Response::Success(value))
+        );
+        assert_eq!(
+            parse_response(&write_fault(-32601, "unknown method").to_string()),
+            Ok(Response::Fault {
+                code: -32601,
+                message: "unknown method".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn call_round_trip() {
+        let values = vec![Value::Int(1), Value::String("x".to_string())];
+
+        assert_eq!(
+            parse_call(&write_call("GetStatus", &values).to_string()),
+            Ok(("GetStatus".to_string(), values))
+        );
+    }
+
+    #[test]
+    fn parse_method_call() {
+        let body = r#"<?xml version="1.0"?>
+<methodCall>
+  <methodName>Result</methodName>
+  <params>
+    <param><value><int>1</int></value></param>
+    <param><value><string>x</string></value></param>
+  </params>
+</methodCall>"#;
+
+        assert_eq!(
+            parse_call(body),
+            Ok((
+                "Result".to_string(),
+                vec![Value::Int(1), Value::String("x".to_string())]
+            ))
+        );
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(parse_response("OK").is_err());
+        assert!(parse_response("<methodResponse/>").is_err());
+        assert!(parse_response(
+            "<methodResponse><params><param><value><int>x</int></value></param></params></methodResponse>"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,349 @@
+//! Gate controller simulator speaking the XML-RPC `ControlAccess` protocol.
+//!
+//! Shared by the `barrier-gate-sim` binary and the integration tests. Every device, addressed
+//! by `ComPort`, `PKUAddress`, `DeviceAddress` and `AggregateAddress`, keeps its own state and
+//! answers `GetStatus` with 0 when closed and 1 when open.
+
+use super::rpc::{self, Value};
+use actix_web::{rt, web, HttpResponse};
+use log::{debug, info, warn};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone)]
+pub struct SimConfig {
+    /// Delay before every answer.
+    pub latency: Duration,
+    /// Share of the requests answered with HTTP 500.
+    pub failure_rate: f64,
+    /// Share of the requests answered with an XML-RPC fault.
+    pub fault_rate: f64,
+    /// Share of the commands answered with a negative `Result`.
+    pub reject_rate: f64,
+    /// Whether to call `MethodNameForAnswer` back on `IPSERVER:PORTSERVER`.
+    pub callback: bool,
+    pub callback_path: String,
+    /// How long an opened gate stays open.
+    pub pulse: Duration,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            failure_rate: 0.0,
+            fault_rate: 0.0,
+            reject_rate: 0.0,
+            callback: false,
+            callback_path: "/".to_string(),
+            pulse: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub com_port: i32,
+    pub pku_address: i32,
+    pub device_address: i32,
+    pub aggregate_address: i32,
+}
+
+impl Address {
+    fn from_value(value: &Value) -> Option<Self> {
+        let member = |name| value.member(name).and_then(Value::as_int);
+
+        Some(Self {
+            com_port: member("ComPort")?,
+            pku_address: member("PKUAddress")?,
+            device_address: member("DeviceAddress")?,
+            aggregate_address: member("AggregateAddress")?,
+        })
+    }
+
+    fn members(&self) -> Vec<(String, Value)> {
+        vec![
+            ("ComPort".to_string(), Value::Int(self.com_port)),
+            ("PKUAddress".to_string(), Value::Int(self.pku_address)),
+            ("DeviceAddress".to_string(), Value::Int(self.device_address)),
+            (
+                "AggregateAddress".to_string(),
+                Value::Int(self.aggregate_address),
+            ),
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceState {
+    Closed,
+    /// Opened by a pulse, closes by itself.
+    Open,
+    Held,
+}
+
+#[derive(Default)]
+struct Device {
+    opened_at: Option<Instant>,
+    held: bool,
+}
+
+pub struct Simulator {
+    config: SimConfig,
+    devices: Mutex<HashMap<Address, Device>>,
+    client: reqwest::Client,
+}
+
+/// Answer of the simulator, `None` stands for a broken HTTP response.
+type Answer = Option<String>;
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Self {
+        Self {
+            config,
+            devices: Mutex::new(HashMap::new()),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn state(&self, address: &Address) -> DeviceState {
+        let devices = self.devices.lock().unwrap();
+
+        match devices.get(address) {
+            Some(device) if device.held => DeviceState::Held,
+            Some(Device {
+                opened_at: Some(at),
+                ..
+            }) if at.elapsed() < self.config.pulse => DeviceState::Open,
+            _ => DeviceState::Closed,
+        }
+    }
+
+    fn roll(rate: f64) -> bool {
+        rate > 0.0 && rand::thread_rng().gen_bool(rate.min(1.0))
+    }
+
+    pub async fn handle(&self, body: &str) -> Answer {
+        if !self.config.latency.is_zero() {
+            rt::time::sleep(self.config.latency).await;
+        }
+
+        if Self::roll(self.config.failure_rate) {
+            warn!("injected HTTP failure");
+            return None;
+        }
+
+        if Self::roll(self.config.fault_rate) {
+            warn!("injected fault");
+            return Some(rpc::write_fault(4, "Device is busy").to_string());
+        }
+
+        let (method, params) = match rpc::parse_call(body) {
+            Ok(call) => call,
+            Err(e) => return Some(rpc::write_fault(-32700, &e.to_string()).to_string()),
+        };
+        let request = match params.first() {
+            Some(request) => request,
+            None => return Some(rpc::write_fault(-32602, "missing request struct").to_string()),
+        };
+        let address = match Address::from_value(request) {
+            Some(address) => address,
+            None => return Some(rpc::write_fault(-32602, "missing device address").to_string()),
+        };
+
+        let code = match method.as_str() {
+            "ControlAccess" => self.control(address, request),
+            "GetStatus" => match self.state(&address) {
+                DeviceState::Closed => 0,
+                DeviceState::Open | DeviceState::Held => 1,
+            },
+            _ => {
+                return Some(
+                    rpc::write_fault(-32601, &format!("unknown method {}", method)).to_string(),
+                )
+            }
+        };
+
+        let mut members = address.members();
+
+        members.push(("Result".to_string(), Value::Int(code)));
+
+        Some(rpc::write_response(&Value::Struct(members)).to_string())
+    }
+
+    fn control(&self, address: Address, request: &Value) -> i32 {
+        let command = request.member("Command").and_then(Value::as_int);
+        let code = if Self::roll(self.config.reject_rate) {
+            warn!("injected rejection of device {:?}", address);
+            -1
+        } else {
+            let mut devices = self.devices.lock().unwrap();
+            let device = devices.entry(address).or_default();
+
+            match command {
+                Some(0) => {
+                    device.opened_at = Some(Instant::now());
+                    0
+                }
+                Some(1) | Some(3) => {
+                    device.opened_at = None;
+                    device.held = false;
+                    0
+                }
+                Some(2) => {
+                    device.held = true;
+                    0
+                }
+                _ => -2,
+            }
+        };
+
+        info!("device {:?}: command {:?} -> {}", address, command, code);
+
+        if self.config.callback {
+            self.call_back(address, request, code);
+        }
+
+        code
+    }
+
+    /// Reports the outcome the way real controllers do, after the answer.
+    fn call_back(&self, address: Address, request: &Value, code: i32) {
+        let method = request
+            .member("MethodNameForAnswer")
+            .and_then(Value::as_str);
+        let ip = request.member("IPSERVER").and_then(Value::as_str);
+        let port = request.member("PORTSERVER").and_then(Value::as_int);
+
+        let (method, url) = match (method, ip, port) {
+            (Some(method), Some(ip), Some(port)) => (
+                method.to_string(),
+                format!("http://{}:{}{}", ip, port, self.config.callback_path),
+            ),
+            _ => {
+                warn!("no callback address in the request of {:?}", address);
+                return;
+            }
+        };
+
+        let mut members = address.members();
+
+        members.push(("Result".to_string(), Value::Int(code)));
+
+        let call = rpc::write_call(&method, &[Value::Struct(members)]).to_string();
+        let client = self.client.clone();
+
+        rt::spawn(async move {
+            match client
+                .post(&url)
+                .header("Content-Type", "text/xml")
+                .body(call)
+                .send()
+                .await
+            {
+                Ok(response) => debug!("callback to {}: {}", url, response.status()),
+                Err(e) => warn!("callback to {} failed: {}", url, e),
+            }
+        });
+    }
+}
+
+async fn handler(body: String, sim: web::Data<Arc<Simulator>>) -> HttpResponse {
+    match sim.handle(&body).await {
+        Some(answer) => HttpResponse::Ok().content_type("text/xml").body(answer),
+        None => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Serves the simulator on every path, controllers do not care about it.
+pub fn configure(cfg: &mut web::ServiceConfig, sim: Arc<Simulator>) {
+    cfg.app_data(web::Data::new(sim))
+        .service(web::resource("/{path:.*}").route(web::post().to(handler)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const DEVICE: Address = Address {
+        com_port: 2,
+        pku_address: 0,
+        device_address: 1,
+        aggregate_address: 1,
+    };
+
+    fn call(method: &str, command: Option<i32>) -> String {
+        let mut members = DEVICE.members();
+
+        if let Some(command) = command {
+            members.push(("Command".to_string(), Value::Int(command)));
+        }
+
+        rpc::write_call(method, &[Value::Struct(members)]).to_string()
+    }
+
+    fn result(answer: Answer) -> Option<i32> {
+        match rpc::parse_response(&answer?) {
+            Ok(rpc::Response::Success(value)) => value.member("Result").and_then(Value::as_int),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_device_state() {
+        let sim = Simulator::new(SimConfig::default());
+
+        assert_eq!(result(sim.handle(&call("GetStatus", None)).await), Some(0));
+        assert_eq!(
+            result(sim.handle(&call("ControlAccess", Some(2))).await),
+            Some(0)
+        );
+        assert_eq!(sim.state(&DEVICE), DeviceState::Held);
+        assert_eq!(result(sim.handle(&call("GetStatus", None)).await), Some(1));
+
+        sim.handle(&call("ControlAccess", Some(3))).await;
+
+        assert_eq!(sim.state(&DEVICE), DeviceState::Closed);
+    }
+
+    #[tokio::test]
+    async fn inject_failures() {
+        let failing = Simulator::new(SimConfig {
+            failure_rate: 1.0,
+            ..SimConfig::default()
+        });
+
+        assert_eq!(failing.handle(&call("ControlAccess", Some(0))).await, None);
+
+        let rejecting = Simulator::new(SimConfig {
+            reject_rate: 1.0,
+            ..SimConfig::default()
+        });
+
+        assert_eq!(
+            result(rejecting.handle(&call("ControlAccess", Some(0))).await),
+            Some(-1)
+        );
+        assert_eq!(rejecting.state(&DEVICE), DeviceState::Closed);
+
+        let faulty = Simulator::new(SimConfig {
+            fault_rate: 1.0,
+            ..SimConfig::default()
+        });
+
+        assert!(matches!(
+            rpc::parse_response(
+                &faulty
+                    .handle(&call("ControlAccess", Some(0)))
+                    .await
+                    .unwrap()
+            ),
+            Ok(rpc::Response::Fault { code: 4, .. })
+        ));
+    }
+}
@@ -0,0 +1,152 @@
+//! Cached gate states, refreshed in the background so that listing the gates does not wait
+//! for the controllers.
+
+use super::{GateDriver, GateStatus};
+use crate::{config::Config, structs::Gate};
+use actix_web::rt;
+use chrono::Utc;
+use log::{debug, warn};
+use std::{collections::HashMap, sync::Arc, sync::RwLock, time::Duration};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CachedStatus {
+    pub status: GateStatus,
+    /// Unix timestamp of the query.
+    pub checked_at: i64,
+}
+
+pub struct GateMonitor {
+    gates: Vec<Gate>,
+    statuses: RwLock<HashMap<String, CachedStatus>>,
+}
+
+impl GateMonitor {
+    pub fn new(gates: Vec<Gate>) -> Self {
+        Self {
+            gates,
+            statuses: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let gates = config
+            .gate_mapping
+            .iter()
+            .map(|(name, gate)| Gate {
+                id: gate.id,
+                name: name.clone(),
+                description: gate.description.clone(),
+                retries: gate.retries,
+                control: false,
+            })
+            .collect();
+
+        Self::new(gates)
+    }
+
+    pub fn get(&self, gate: &str) -> Option<CachedStatus> {
+        self.statuses.read().unwrap().get(gate).copied()
+    }
+
+    /// Gates which were not polled yet are assumed to be online.
+    pub fn is_online(&self, gate: &str) -> bool {
+        !matches!(
+            self.get(gate),
+            Some(CachedStatus {
+                status: GateStatus::Offline,
+                ..
+            })
+        )
+    }
+
+    /// Queries the controller and caches the answer.
+    pub async fn check(
+        &self,
+        driver: &(dyn GateDriver + Send + Sync),
+        gate: &Gate,
+    ) -> CachedStatus {
+        let status = match driver.status(gate).await {
+            Ok(status) => status,
+            Err(e) if e.is_transport() => GateStatus::Offline,
+            Err(e) => {
+                warn!("unable to query gate {}: {}", gate.name, e);
+                GateStatus::Unknown
+            }
+        };
+        let cached = CachedStatus {
+            status,
+            checked_at: Utc::now().timestamp(),
+        };
+
+        debug!("gate {} is {}", gate.name, status);
+        self.statuses
+            .write()
+            .unwrap()
+            .insert(gate.name.clone(), cached);
+
+        cached
+    }
+
+    pub async fn poll(&self, driver: &(dyn GateDriver + Send + Sync)) {
+        for gate in &self.gates {
+            self.check(driver, gate).await;
+        }
+    }
+
+    /// Polls every gate each `interval` until the server stops.
+    pub fn spawn(self: Arc<Self>, driver: Arc<dyn GateDriver + Send + Sync>, interval: Duration) {
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+                self.poll(driver.as_ref()).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::gate::{FakeGateDriver, GateError};
+    use pretty_assertions::assert_eq;
+
+    fn gate(id: i32, name: &str) -> Gate {
+        Gate {
+            id,
+            retries: 1,
+            name: name.to_string(),
+            description: "".to_string(),
+            control: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_polled_status() {
+        let monitor = GateMonitor::new(vec![gate(1, "barrier")]);
+
+        assert_eq!(monitor.get("barrier"), None);
+        assert!(monitor.is_online("barrier"));
+
+        monitor.poll(&FakeGateDriver::new()).await;
+
+        assert_eq!(monitor.get("barrier").unwrap().status, GateStatus::Closed);
+        assert!(monitor.is_online("barrier"));
+
+        monitor
+            .poll(&FakeGateDriver::failing(GateError::Unreachable(
+                "connection refused".to_string(),
+            )))
+            .await;
+
+        assert_eq!(monitor.get("barrier").unwrap().status, GateStatus::Offline);
+        assert!(!monitor.is_online("barrier"));
+
+        monitor
+            .poll(&FakeGateDriver::failing(GateError::Rejected { code: -1 }))
+            .await;
+
+        assert_eq!(monitor.get("barrier").unwrap().status, GateStatus::Unknown);
+    }
+}
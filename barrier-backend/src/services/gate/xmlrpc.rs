@@ -0,0 +1,838 @@
+use super::{
+    callback::{DeviceKey, PendingResults},
+    retry::RetryPolicy,
+    rpc::{self, Value},
+    Command, Fault, GateDriver, GateError, GateStatus,
+};
+use crate::{
+    config::{Config, Controller, RetryOverrides, XmlRpcOverrides},
+    structs::{ControllerResult, Gate},
+};
+use log::{debug, error, warn};
+use simple_xml_builder::XMLElement;
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+use tokio::{
+    sync::oneshot,
+    time::{timeout, Duration},
+};
+
+/// Controller server with its own connection pool.
+pub struct Endpoint {
+    url: String,
+    client: reqwest::Client,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl Endpoint {
+    pub fn new(controller: &Controller) -> Self {
+        let mut client = reqwest::Client::builder();
+
+        if let Some(ms) = controller.connect_timeout_ms {
+            client = client.connect_timeout(Duration::from_millis(ms));
+        }
+
+        if let Some(ms) = controller.timeout_ms {
+            client = client.timeout(Duration::from_millis(ms));
+        }
+
+        Self {
+            url: controller.url.clone(),
+            client: client.build().expect("HTTP client"),
+            username: controller.username.clone(),
+            password: controller.password.clone(),
+        }
+    }
+
+    fn post(&self, body: String) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/xml")
+            .body(body);
+
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+}
+
+pub struct XmlRpcDriver {
+    /// Controller of the gates without one of `controllers`.
+    server: Endpoint,
+    controllers: HashMap<String, Endpoint>,
+    gate_controller: HashMap<String, String>,
+    defaults: XmlRpcOverrides,
+    gates: HashMap<String, XmlRpcParams>,
+    retry: RetryOverrides,
+    gate_retry: HashMap<String, RetryOverrides>,
+    callbacks: Option<(Arc<PendingResults>, Duration)>,
+}
+
+impl XmlRpcDriver {
+    pub fn new(server_address: String, defaults: XmlRpcOverrides) -> Self {
+        Self {
+            server: Endpoint::new(&Controller {
+                url: server_address,
+                ..Controller::default()
+            }),
+            controllers: HashMap::new(),
+            gate_controller: HashMap::new(),
+            defaults,
+            gates: HashMap::new(),
+            retry: RetryOverrides::default(),
+            gate_retry: HashMap::new(),
+            callbacks: None,
+        }
+    }
+
+    pub fn from_config(config: &Config, callbacks: Option<Arc<PendingResults>>) -> Self {
+        let mut driver = Self::new(config.gate_server.clone(), config.xmlrpc.clone());
+
+        driver.retry = config.retry.clone();
+
+        for (name, controller) in &config.controllers {
+            driver
+                .controllers
+                .insert(name.clone(), Endpoint::new(controller));
+        }
+
+        if let (Some(callbacks), Some(callback)) = (callbacks, &config.callback) {
+            driver.callbacks = Some((callbacks, Duration::from_millis(callback.timeout_ms)));
+        }
+
+        for (name, gate) in &config.gate_mapping {
+            driver.gates.insert(
+                name.clone(),
+                XmlRpcParams::resolve(gate.id, &config.xmlrpc, &gate.xmlrpc),
+            );
+            driver.gate_retry.insert(name.clone(), gate.retry.clone());
+
+            if let Some(controller) = &gate.controller {
+                driver
+                    .gate_controller
+                    .insert(name.clone(), controller.clone());
+            }
+        }
+
+        driver
+    }
+
+    fn params(&self, gate: &Gate) -> XmlRpcParams {
+        self.gates.get(&gate.name).cloned().unwrap_or_else(|| {
+            XmlRpcParams::resolve(gate.id, &self.defaults, &XmlRpcOverrides::default())
+        })
+    }
+
+    /// Name of the controller of the gate, empty for `gate_server`.
+    fn controller(&self, gate: &Gate) -> &str {
+        self.gate_controller
+            .get(&gate.name)
+            .filter(|controller| self.controllers.contains_key(*controller))
+            .map_or("", String::as_str)
+    }
+
+    fn route(&self, gate: &Gate) -> &Endpoint {
+        self.gate_controller
+            .get(&gate.name)
+            .and_then(|controller| self.controllers.get(controller))
+            .unwrap_or(&self.server)
+    }
+
+    fn policy(&self, gate: &Gate) -> RetryPolicy {
+        let overrides = self.gate_retry.get(&gate.name).cloned().unwrap_or_default();
+
+        RetryPolicy::resolve(gate.retries, &self.retry, &overrides)
+    }
+}
+
+#[async_trait::async_trait]
+impl GateDriver for XmlRpcDriver {
+    async fn execute(&self, gate: &Gate, command: Command) -> Result<ControllerResult, GateError> {
+        let params = self.params(gate);
+        // register before sending, the controller may answer before `open` returns
+        let confirmation = match &self.callbacks {
+            Some((pending, wait)) => {
+                match pending.register(DeviceKey::new(self.controller(gate), &params)) {
+                    Some(answer) => Some((answer, *wait)),
+                    None => return Err(GateError::Busy(gate.name.clone())),
+                }
+            }
+            None => None,
+        };
+
+        let result = execute(self.route(gate), &params, command, &self.policy(gate)).await?;
+
+        match confirmation {
+            Some((answer, wait)) => confirm(result, answer, wait).await,
+            None => Ok(result),
+        }
+    }
+
+    /// Calls `status_method` for the device, `Unknown` without asking when it is not set.
+    async fn status(&self, gate: &Gate) -> Result<GateStatus, GateError> {
+        let params = self.params(gate);
+        let method = match &params.status_method {
+            Some(method) => method,
+            None => return Ok(GateStatus::Unknown),
+        };
+        let xml = rpc::write_call(method, &[params.address()]);
+        let wait = self.policy(gate).attempt_timeout;
+
+        let result = timeout(wait, send(self.route(gate), &xml))
+            .await
+            .map_err(|_| {
+                GateError::Unreachable(format!("no answer within {} ms", wait.as_millis()))
+            })??;
+
+        gate_status(result.code)
+    }
+
+    fn endpoint(&self, gate: &Gate) -> String {
+        self.route(gate).url.clone()
+    }
+}
+
+/// State of the `status_method` result: 0 is closed and 1 is open, other codes mean nothing
+/// known.
+fn gate_status(code: i32) -> Result<GateStatus, GateError> {
+    match code {
+        0 => Ok(GateStatus::Closed),
+        1 => Ok(GateStatus::Open),
+        code => Err(GateError::Malformed(format!(
+            "unknown status code {}",
+            code
+        ))),
+    }
+}
+
+/// Waits for the controller to report the physical outcome of the command.
+async fn confirm(
+    result: ControllerResult,
+    answer: oneshot::Receiver<i32>,
+    wait: Duration,
+) -> Result<ControllerResult, GateError> {
+    match timeout(wait, answer).await {
+        Ok(Ok(code)) if code < 0 => Err(GateError::Rejected { code }),
+        Ok(Ok(code)) => Ok(ControllerResult {
+            code,
+            confirmed: Some(true),
+            ..result
+        }),
+        _ => {
+            warn!("no confirmation from the controller in {:?}", wait);
+            Ok(ControllerResult {
+                confirmed: Some(false),
+                ..result
+            })
+        }
+    }
+}
+
+/// Members of the `ControlAccess` request for a single gate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XmlRpcParams {
+    pub com_port: i32,
+    pub pku_address: i32,
+    pub device_address: i32,
+    pub aggregate_address: i32,
+    pub open_command: i32,
+    pub close_command: i32,
+    pub hold_command: i32,
+    pub release_command: i32,
+    pub method_name_for_answer: String,
+    pub ip_server: String,
+    pub port_server: i32,
+    /// Not part of the documented protocol, the status is only queried when it is set.
+    pub status_method: Option<String>,
+}
+
+impl XmlRpcParams {
+    /// Gate overrides win over the global `[xmlrpc]` section, which wins over the built-in
+    /// defaults. `DeviceAddress` falls back to the gate id.
+    pub fn resolve(gate_id: i32, defaults: &XmlRpcOverrides, gate: &XmlRpcOverrides) -> Self {
+        Self {
+            com_port: gate.com_port.or(defaults.com_port).unwrap_or(2),
+            pku_address: gate.pku_address.or(defaults.pku_address).unwrap_or(0),
+            device_address: gate.device_address.unwrap_or(gate_id),
+            aggregate_address: gate
+                .aggregate_address
+                .or(defaults.aggregate_address)
+                .unwrap_or(1),
+            open_command: gate.open_command.or(defaults.open_command).unwrap_or(0),
+            close_command: gate.close_command.or(defaults.close_command).unwrap_or(1),
+            hold_command: gate.hold_command.or(defaults.hold_command).unwrap_or(2),
+            release_command: gate
+                .release_command
+                .or(defaults.release_command)
+                .unwrap_or(3),
+            method_name_for_answer: gate
+                .method_name_for_answer
+                .clone()
+                .or_else(|| defaults.method_name_for_answer.clone())
+                .unwrap_or_else(|| "Result".to_string()),
+            ip_server: gate
+                .ip_server
+                .clone()
+                .or_else(|| defaults.ip_server.clone())
+                .unwrap_or_else(|| "127.0.0.1".to_string()),
+            port_server: gate.port_server.or(defaults.port_server).unwrap_or(8080),
+            status_method: gate
+                .status_method
+                .clone()
+                .or_else(|| defaults.status_method.clone()),
+        }
+    }
+
+    /// Members addressing the device.
+    fn address(&self) -> Value {
+        Value::Struct(vec![
+            ("ComPort".to_string(), Value::Int(self.com_port)),
+            ("PKUAddress".to_string(), Value::Int(self.pku_address)),
+            ("DeviceAddress".to_string(), Value::Int(self.device_address)),
+            (
+                "AggregateAddress".to_string(),
+                Value::Int(self.aggregate_address),
+            ),
+        ])
+    }
+
+    /// Value of the `Command` member for the command.
+    pub fn command_code(&self, command: Command) -> i32 {
+        match command {
+            Command::Open => self.open_command,
+            Command::Close => self.close_command,
+            Command::Hold => self.hold_command,
+            Command::Release => self.release_command,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let addresses = [
+            ("com_port", self.com_port),
+            ("pku_address", self.pku_address),
+            ("device_address", self.device_address),
+            ("aggregate_address", self.aggregate_address),
+            ("open_command", self.open_command),
+            ("close_command", self.close_command),
+            ("hold_command", self.hold_command),
+            ("release_command", self.release_command),
+        ];
+
+        for (name, value) in addresses {
+            if value < 0 {
+                return Err(format!("{} must not be negative, got {}", name, value));
+            }
+        }
+
+        let methods = [
+            ("method_name_for_answer", Some(&self.method_name_for_answer)),
+            ("status_method", self.status_method.as_ref()),
+        ];
+
+        for (name, method) in methods
+            .into_iter()
+            .filter_map(|(name, method)| Some((name, method?)))
+        {
+            if method.is_empty()
+                || !method
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_.:/".contains(c))
+            {
+                return Err(format!(
+                    "{} {:?} is not a valid XML-RPC method name",
+                    name, method
+                ));
+            }
+        }
+
+        if self.ip_server.parse::<IpAddr>().is_err() {
+            return Err(format!(
+                "ip_server {:?} is not an IP address",
+                self.ip_server
+            ));
+        }
+
+        if !(1..=65535).contains(&self.port_server) {
+            return Err(format!(
+                "port_server must be between 1 and 65535, got {}",
+                self.port_server
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+struct XMLParam {
+    name: &'static str,
+    value: String,
+    attr_type: &'static str,
+}
+
+fn get_params(params: &XmlRpcParams, command: Command) -> [XMLParam; 8] {
+    [
+        XMLParam {
+            name: "ComPort",
+            value: params.com_port.to_string(),
+            attr_type: "int",
+        },
+        XMLParam {
+            name: "PKUAddress",
+            value: params.pku_address.to_string(),
+            attr_type: "int",
+        },
+        XMLParam {
+            name: "DeviceAddress",
+            value: params.device_address.to_string(),
+            attr_type: "int",
+        },
+        XMLParam {
+            name: "AggregateAddress",
+            value: params.aggregate_address.to_string(),
+            attr_type: "int",
+        },
+        XMLParam {
+            name: "Command",
+            value: params.command_code(command).to_string(),
+            attr_type: "int",
+        },
+        XMLParam {
+            name: "MethodNameForAnswer",
+            value: params.method_name_for_answer.clone(),
+            attr_type: "string",
+        },
+        XMLParam {
+            name: "IPSERVER",
+            value: params.ip_server.clone(),
+            attr_type: "string",
+        },
+        XMLParam {
+            name: "PORTSERVER",
+            value: params.port_server.to_string(),
+            attr_type: "int",
+        },
+    ]
+}
+
+fn generate_xml(params: &XmlRpcParams, command: Command) -> XMLElement {
+    let xml_params = get_params(params, command);
+
+    let mut method_call = XMLElement::new("methodCall");
+    let mut method_name = XMLElement::new("methodName");
+
+    method_name.add_text("ControlAccess");
+
+    let mut params = XMLElement::new("params");
+    let mut param = XMLElement::new("param");
+    let mut value = XMLElement::new("value");
+    let mut st = XMLElement::new("struct");
+
+    for p in &xml_params {
+        let mut member = XMLElement::new("member");
+        let mut name = XMLElement::new("name");
+        let mut value = XMLElement::new("value");
+        let mut t = XMLElement::new(p.attr_type);
+
+        t.add_text(&p.value);
+
+        name.add_text(p.name);
+        value.add_child(t);
+        member.add_child(name);
+        member.add_child(value);
+        st.add_child(member);
+    }
+
+    value.add_child(st);
+    param.add_child(value);
+    params.add_child(param);
+
+    method_call.add_child(params);
+    method_call.add_child(method_name);
+
+    method_call
+}
+
+/// Turns a decoded `methodResponse` into the controller result. The controller answers either
+/// with a bare result code or with a struct carrying it in the `Result` member; negative codes
+/// mean the command was refused.
+fn interpret(response: rpc::Response) -> Result<ControllerResult, GateError> {
+    let value = match response {
+        rpc::Response::Success(value) => value,
+        rpc::Response::Fault { code, message } => {
+            return Err(GateError::Fault {
+                fault: Fault::from(code),
+                message,
+            })
+        }
+    };
+
+    let code = match &value {
+        Value::Int(code) => Some(*code),
+        Value::Boolean(true) => Some(0),
+        Value::Boolean(false) => Some(-1),
+        value => value.member("Result").and_then(Value::as_int),
+    };
+
+    let code =
+        code.ok_or_else(|| GateError::Malformed("response without result code".to_string()))?;
+
+    if code < 0 {
+        return Err(GateError::Rejected { code });
+    }
+
+    Ok(ControllerResult {
+        code,
+        confirmed: None,
+        attempts: 1,
+    })
+}
+
+async fn send(endpoint: &Endpoint, xml: &XMLElement) -> Result<ControllerResult, GateError> {
+    let response = endpoint
+        .post(format!("{}", xml))
+        .send()
+        .await
+        .map_err(|e| GateError::Unreachable(e.to_string()))?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(GateError::Unreachable(format!("HTTP {}", status)));
+    }
+
+    let body = response
+        .text()
+        .await
+        .map_err(|e| GateError::Unreachable(e.to_string()))?;
+
+    interpret(rpc::parse_response(&body).map_err(|e| GateError::Malformed(e.to_string()))?)
+}
+
+async fn execute(
+    endpoint: &Endpoint,
+    params: &XmlRpcParams,
+    command: Command,
+    policy: &RetryPolicy,
+) -> Result<ControllerResult, GateError> {
+    let gate = params.device_address;
+
+    debug!(
+        "try to {} {} gate with {} attempts",
+        command, gate, policy.attempts
+    );
+
+    let xml = generate_xml(params, command);
+    let what = format!("{} {} gate", command, gate);
+
+    let (result, attempts) = policy.run(&what, || send(endpoint, &xml)).await;
+
+    match result {
+        Ok(result) => {
+            debug!("relay accepted {} after {} attempts", command, attempts);
+            Ok(ControllerResult { attempts, ..result })
+        }
+        Err(e) => {
+            error!(
+                "failed to {} {} gate after {} attempts: {}",
+                command, gate, attempts, e
+            );
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const EXPECTED: &str = r#"<?xml version = "1.0" encoding = "UTF-8"?>
+<methodCall>
+	<params>
+		<param>
+			<value>
+				<struct>
+					<member>
+						<name>ComPort</name>
+						<value>
+							<int>2</int>
+						</value>
+					</member>
+					<member>
+						<name>PKUAddress</name>
+						<value>
+							<int>0</int>
+						</value>
+					</member>
+					<member>
+						<name>DeviceAddress</name>
+						<value>
+							<int>666</int>
+						</value>
+					</member>
+					<member>
+						<name>AggregateAddress</name>
+						<value>
+							<int>1</int>
+						</value>
+					</member>
+					<member>
+						<name>Command</name>
+						<value>
+							<int>0</int>
+						</value>
+					</member>
+					<member>
+						<name>MethodNameForAnswer</name>
+						<value>
+							<string>Result</string>
+						</value>
+					</member>
+					<member>
+						<name>IPSERVER</name>
+						<value>
+							<string>127.0.0.1</string>
+						</value>
+					</member>
+					<member>
+						<name>PORTSERVER</name>
+						<value>
+							<int>8080</int>
+						</value>
+					</member>
+				</struct>
+			</value>
+		</param>
+	</params>
+	<methodName>ControlAccess</methodName>
+</methodCall>
+"#;
+
+    #[test]
+    fn check_function() {
+        let params = XmlRpcParams::resolve(
+            666,
+            &XmlRpcOverrides::default(),
+            &XmlRpcOverrides::default(),
+        );
+        let xml = generate_xml(&params, Command::Open);
+
+        assert_eq!(format!("{}", xml), EXPECTED);
+    }
+
+    #[test]
+    fn interpret_results() {
+        assert_eq!(
+            interpret(rpc::Response::Success(Value::Int(1))),
+            Ok(ControllerResult {
+                code: 1,
+                confirmed: None,
+                attempts: 1,
+            })
+        );
+        assert_eq!(
+            interpret(rpc::Response::Success(Value::Struct(vec![(
+                "Result".to_string(),
+                Value::Int(0)
+            )]))),
+            Ok(ControllerResult {
+                code: 0,
+                confirmed: None,
+                attempts: 1,
+            })
+        );
+        assert_eq!(
+            interpret(rpc::Response::Success(Value::Int(-3))),
+            Err(GateError::Rejected { code: -3 })
+        );
+        assert_eq!(
+            interpret(rpc::Response::Fault {
+                code: -32601,
+                message: "no such method".to_string()
+            }),
+            Err(GateError::Fault {
+                fault: Fault::MethodNotFound,
+                message: "no such method".to_string()
+            })
+        );
+        assert!(matches!(
+            interpret(rpc::Response::Success(Value::String("OK".to_string()))),
+            Err(GateError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(gate_status(0), Ok(GateStatus::Closed));
+        assert_eq!(gate_status(1), Ok(GateStatus::Open));
+        assert!(matches!(gate_status(2), Err(GateError::Malformed(_))));
+        assert!(matches!(gate_status(-5), Err(GateError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn wait_for_confirmation() {
+        let sent = ControllerResult {
+            code: 0,
+            confirmed: None,
+            attempts: 1,
+        };
+        let wait = Duration::from_millis(10);
+
+        let (tx, rx) = oneshot::channel();
+        tx.send(5).unwrap();
+        assert_eq!(
+            confirm(sent.clone(), rx, wait).await,
+            Ok(ControllerResult {
+                code: 5,
+                confirmed: Some(true),
+                attempts: 1,
+            })
+        );
+
+        let (tx, rx) = oneshot::channel();
+        tx.send(-1).unwrap();
+        assert_eq!(
+            confirm(sent.clone(), rx, wait).await,
+            Err(GateError::Rejected { code: -1 })
+        );
+
+        let (_tx, rx) = oneshot::channel();
+        assert_eq!(
+            confirm(sent, rx, wait).await,
+            Ok(ControllerResult {
+                code: 0,
+                confirmed: Some(false),
+                attempts: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn gate_overrides_win() {
+        let defaults = XmlRpcOverrides {
+            com_port: Some(4),
+            pku_address: Some(3),
+            ip_server: Some("10.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let gate = XmlRpcOverrides {
+            com_port: Some(5),
+            device_address: Some(12),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            XmlRpcParams::resolve(1, &defaults, &gate),
+            XmlRpcParams {
+                com_port: 5,
+                pku_address: 3,
+                device_address: 12,
+                aggregate_address: 1,
+                open_command: 0,
+                close_command: 1,
+                hold_command: 2,
+                release_command: 3,
+                method_name_for_answer: "Result".to_string(),
+                ip_server: "10.0.0.1".to_string(),
+                port_server: 8080,
+                status_method: None,
+            }
+        );
+    }
+
+    #[test]
+    fn route_to_controller() {
+        let mut config = Config {
+            gate_server: "http://127.0.0.1:9000/".to_string(),
+            ..Config::default()
+        };
+
+        config.controllers.insert(
+            "north".to_string(),
+            Controller {
+                url: "http://10.0.1.10/RPC2".to_string(),
+                username: Some("barrier".to_string()),
+                ..Controller::default()
+            },
+        );
+        config.gate_mapping.get_mut("Gate").unwrap().controller = Some("north".to_string());
+
+        let driver = XmlRpcDriver::from_config(&config, None);
+        let gate = |name: &str| Gate {
+            id: 1,
+            retries: 1,
+            name: name.to_string(),
+            description: "".to_string(),
+            control: false,
+        };
+
+        assert_eq!(driver.route(&gate("Gate")).url, "http://10.0.1.10/RPC2");
+        // one circuit per controller
+        assert_eq!(
+            GateDriver::endpoint(&driver, &gate("Gate")),
+            "http://10.0.1.10/RPC2"
+        );
+        assert_eq!(driver.route(&gate("Other")).url, "http://127.0.0.1:9000/");
+        assert_eq!(config.validate(), Ok(()));
+
+        config.gate_mapping.get_mut("Gate").unwrap().controller = Some("south".to_string());
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn command_codes() {
+        let params = XmlRpcParams::resolve(
+            1,
+            &XmlRpcOverrides {
+                hold_command: Some(7),
+                ..Default::default()
+            },
+            &XmlRpcOverrides {
+                close_command: Some(9),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(params.command_code(Command::Open), 0);
+        assert_eq!(params.command_code(Command::Close), 9);
+        assert_eq!(params.command_code(Command::Hold), 7);
+        assert_eq!(params.command_code(Command::Release), 3);
+        assert!(generate_xml(&params, Command::Hold)
+            .to_string()
+            .contains("<int>7</int>"));
+    }
+
+    #[test]
+    fn reject_invalid_params() {
+        let valid =
+            XmlRpcParams::resolve(1, &XmlRpcOverrides::default(), &XmlRpcOverrides::default());
+
+        assert_eq!(valid.validate(), Ok(()));
+
+        for invalid in [
+            XmlRpcOverrides {
+                com_port: Some(-1),
+                ..Default::default()
+            },
+            XmlRpcOverrides {
+                port_server: Some(70000),
+                ..Default::default()
+            },
+            XmlRpcOverrides {
+                ip_server: Some("gate server".to_string()),
+                ..Default::default()
+            },
+            XmlRpcOverrides {
+                method_name_for_answer: Some("".to_string()),
+                ..Default::default()
+            },
+        ] {
+            let params = XmlRpcParams::resolve(1, &XmlRpcOverrides::default(), &invalid);
+
+            assert!(params.validate().is_err());
+        }
+    }
+}
@@ -1,15 +1,57 @@
-use crate::structs::Gate;
-use jwt_simple::{
-    algorithms::{HS256Key, MACLike},
-    claims::Claims,
-    common::VerificationOptions,
-    prelude::Duration,
+//! Access tokens of the sessions.
+//!
+//! Tokens are signed with the HS256 `jwt_key`, or with the first of the `jwt_keys` key pairs so
+//! other services verify them with the public keys of `/.well-known/jwks.json`. The other pairs
+//! are still accepted, a key is rotated by putting the new one first and removing the old one
+//! once its tokens have expired.
+//!
+//! Refresh tokens are random, the `Db` only keeps their HMAC under `refresh_token_key`.
+
+use crate::{
+    config::{Config, JwtAlgorithm, JwtKey},
+    structs::Gate,
 };
-use serde::{Deserialize, Serialize};
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac, NewMac};
+use jwt_simple::prelude::*;
+use serde_json::json;
+use sha2::Sha256;
+use std::fs;
 use uuid::Uuid;
 
+/// Lifetime of the access tokens, the refresh tokens get new ones.
+pub const ACCESS_TOKEN_SECS: u64 = 5 * 60;
+/// Lifetime of the refresh tokens, and of the sessions not refreshed meanwhile.
+pub const REFRESH_TOKEN_SECS: u64 = 14 * 24 * 60 * 60;
+/// Rotated refresh tokens kept per session to notice a replay, an hour of refreshes.
+pub const USED_TOKENS_KEPT: usize = 12;
+
+/// DER of an Ed25519 PKCS#8 private key before the 32 bytes of its seed.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+/// DER of an Ed25519 public key before its 32 bytes.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+enum SigningKey {
+    Hs256(HS256Key),
+    EdDsa(Ed25519KeyPair),
+    Rs256(Box<RS256KeyPair>),
+}
+
+enum VerifyingKey {
+    EdDsa(Ed25519PublicKey),
+    Rs256(RS256PublicKey),
+}
+
 pub struct Jwt {
-    key: String,
+    signing: SigningKey,
+    /// Accepted asymmetric keys by `kid`, the signing one included.
+    keys: Vec<(String, VerifyingKey)>,
+    /// HMAC key of the stored refresh tokens.
+    refresh_key: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,48 +59,406 @@ pub struct JWTToken {
     pub username: String,
     pub session_id: String,
     pub available_rooms: Vec<Gate>,
+    /// Session started from the offline login cache while the directory was down.
+    #[serde(default)]
+    pub degraded: bool,
+}
+
+/// Bytes of the single PEM block of `pem` labelled `label`.
+fn pem_der(pem: &str, label: &str) -> Result<Vec<u8>, String> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let body = pem
+        .split_once(&begin)
+        .and_then(|(_, rest)| rest.split_once(&end))
+        .map(|(body, _)| body.split_whitespace().collect::<String>())
+        .ok_or_else(|| format!("no {} block", label))?;
+
+    Base64::decode_to_vec(&body, None).map_err(|e| e.to_string())
+}
+
+fn ed25519_key_pair(pem: &str) -> Result<Ed25519KeyPair, String> {
+    let der = pem_der(pem, "PRIVATE KEY")?;
+    let seed = der
+        .strip_prefix(&ED25519_PKCS8_PREFIX[..])
+        .ok_or_else(|| "not an Ed25519 PKCS#8 key".to_string())?;
+    let seed = ed25519_compact::Seed::from_slice(seed).map_err(|e| e.to_string())?;
+
+    if seed.iter().all(|byte| *byte == 0) {
+        return Err("all-zero seed".to_string());
+    }
+
+    Ed25519KeyPair::from_bytes(&ed25519_compact::KeyPair::from_seed(seed)[..])
+        .map_err(|e| e.to_string())
+}
+
+fn ed25519_public_key(pem: &str) -> Result<Ed25519PublicKey, String> {
+    let der = pem_der(pem, "PUBLIC KEY")?;
+    let key = der
+        .strip_prefix(&ED25519_SPKI_PREFIX[..])
+        .ok_or_else(|| "not an Ed25519 public key".to_string())?;
+
+    Ed25519PublicKey::from_bytes(key).map_err(|e| e.to_string())
+}
+
+/// Reads the key pair, or only the public key of a retired key.
+fn load_key(key: &JwtKey) -> Result<(Option<SigningKey>, VerifyingKey), String> {
+    let read = |file: &String| fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e));
+    let context = |e: String| format!("jwt_keys {:?}: {}", key.kid, e);
+
+    match (&key.private_key, &key.public_key, key.algorithm) {
+        (Some(file), _, JwtAlgorithm::EdDSA) => {
+            let pair = ed25519_key_pair(&read(file)?).map_err(context)?;
+            let public = pair.public_key();
+
+            Ok((
+                Some(SigningKey::EdDsa(pair.with_key_id(&key.kid))),
+                VerifyingKey::EdDsa(public),
+            ))
+        }
+        (Some(file), _, JwtAlgorithm::RS256) => {
+            let pair = RS256KeyPair::from_pem(&read(file)?).map_err(|e| context(e.to_string()))?;
+            let public = pair.public_key();
+
+            Ok((
+                Some(SigningKey::Rs256(Box::new(pair.with_key_id(&key.kid)))),
+                VerifyingKey::Rs256(public),
+            ))
+        }
+        (None, Some(file), JwtAlgorithm::EdDSA) => Ok((
+            None,
+            VerifyingKey::EdDsa(ed25519_public_key(&read(file)?).map_err(context)?),
+        )),
+        (None, Some(file), JwtAlgorithm::RS256) => Ok((
+            None,
+            VerifyingKey::Rs256(
+                RS256PublicKey::from_pem(&read(file)?).map_err(|e| context(e.to_string()))?,
+            ),
+        )),
+        (None, None, _) => Err(context("no key file".to_string())),
+    }
 }
 
 impl Jwt {
+    /// Signs and verifies with the HS256 secret.
     pub fn new(key: String) -> Self {
-        Self { key }
+        Self {
+            signing: SigningKey::Hs256(HS256Key::from_bytes(key.as_bytes())),
+            keys: Vec::new(),
+            refresh_key: key.into_bytes(),
+        }
     }
 
+    /// Loads `jwt_keys`, or takes `jwt_key` when there are none.
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        let refresh_key = config.refresh_token_key.as_bytes().to_vec();
+        let mut keys = config.jwt_keys.iter();
+        let signing = match keys.next() {
+            Some(key) => key,
+            None => {
+                return Ok(Self {
+                    refresh_key,
+                    ..Self::new(config.jwt_key.clone())
+                })
+            }
+        };
+
+        let (signing_key, verifying_key) = load_key(signing)?;
+        let mut jwt = Self {
+            signing: signing_key
+                .ok_or_else(|| format!("jwt_keys {:?}: no private key", signing.kid))?,
+            keys: vec![(signing.kid.clone(), verifying_key)],
+            refresh_key,
+        };
+
+        for key in keys {
+            jwt.keys.push((key.kid.clone(), load_key(key)?.1));
+        }
+
+        Ok(jwt)
+    }
+
+    /// Tokens of a new session, returns the access token, the refresh token and the session.
     pub fn issue_token(
         &self,
         username: String,
         available_rooms: Vec<Gate>,
         expired_in: Duration,
+        degraded: bool,
     ) -> (String, String, String) {
-        let key = HS256Key::from_bytes(self.key.as_bytes());
-        let refresh_token = Uuid::new_v4().to_string();
         let session_id = Uuid::new_v4().to_string();
+        let (access_token, refresh_token) = self.issue_session_token(
+            session_id.clone(),
+            username,
+            available_rooms,
+            expired_in,
+            degraded,
+        );
+
+        (access_token, refresh_token, session_id)
+    }
+
+    /// Tokens of the session, returns the access token and the refresh token.
+    pub fn issue_session_token(
+        &self,
+        session_id: String,
+        username: String,
+        available_rooms: Vec<Gate>,
+        expired_in: Duration,
+        degraded: bool,
+    ) -> (String, String) {
+        let refresh_token = Uuid::new_v4().to_string();
 
         let claims = JWTToken {
             username,
-            session_id: session_id.clone(),
+            session_id,
             available_rooms,
+            degraded,
         };
 
         let claims = Claims::with_custom_claims(claims, expired_in);
+        let token = match &self.signing {
+            SigningKey::Hs256(key) => key.authenticate(claims),
+            SigningKey::EdDsa(key) => key.sign(claims),
+            SigningKey::Rs256(key) => key.sign(claims),
+        };
 
-        (
-            key.authenticate(claims).expect("jwt token"),
-            refresh_token,
-            session_id,
-        )
+        (token.expect("jwt token"), refresh_token)
     }
 
-    pub fn verify_token(&self, token: String) -> Option<JWTToken> {
-        let key = HS256Key::from_bytes(self.key.as_bytes());
+    /// What the refresh token is stored and looked up as, useless to whoever reads the `Db`.
+    pub fn refresh_token_hash(&self, refresh_token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.refresh_key).expect("any HMAC key");
+
+        mac.update(refresh_token.as_bytes());
+        HEXLOWER.encode(&mac.finalize().into_bytes())
+    }
+
+    /// Tells the `refresh_token_key` apart without revealing it.
+    pub fn refresh_key_check(&self) -> String {
+        self.refresh_token_hash("refresh_token_key check")
+    }
 
+    pub fn verify_token(&self, token: String) -> Option<JWTToken> {
         let options = VerificationOptions {
             time_tolerance: Some(Duration::from_millis(0)),
             ..Default::default()
         };
 
-        let claims = key.verify_token::<JWTToken>(&token, Some(options)).ok()?;
+        let claims = match &self.signing {
+            SigningKey::Hs256(key) => key.verify_token::<JWTToken>(&token, Some(options)),
+            _ => {
+                let metadata = Token::decode_metadata(&token).ok()?;
+                let kid = metadata.key_id()?;
+
+                match self.keys.iter().find(|(id, _)| id == kid)? {
+                    (_, VerifyingKey::EdDsa(key)) => key.verify_token(&token, Some(options)),
+                    (_, VerifyingKey::Rs256(key)) => key.verify_token(&token, Some(options)),
+                }
+            }
+        };
+
+        Some(claims.ok()?.custom)
+    }
+
+    /// JWK set of the accepted keys, empty with the HS256 secret.
+    pub fn jwks(&self) -> serde_json::Value {
+        let encode =
+            |bytes: &[u8]| Base64UrlSafeNoPadding::encode_to_string(bytes).expect("base64");
+        let keys: Vec<serde_json::Value> = self
+            .keys
+            .iter()
+            .map(|(kid, key)| match key {
+                VerifyingKey::EdDsa(key) => json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "alg": "EdDSA",
+                    "use": "sig",
+                    "kid": kid,
+                    "x": encode(&key.to_bytes()),
+                }),
+                VerifyingKey::Rs256(key) => {
+                    let components = key.to_components();
+
+                    json!({
+                        "kty": "RSA",
+                        "alg": "RS256",
+                        "use": "sig",
+                        "kid": kid,
+                        "n": encode(&components.n),
+                        "e": encode(&components.e),
+                    })
+                }
+            })
+            .collect();
+
+        json!({ "keys": keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::path::PathBuf;
+
+    /// Writes the PEM of a new Ed25519 key pair, returns the private and the public key files.
+    fn ed25519_files() -> (PathBuf, PathBuf) {
+        let pair = Ed25519KeyPair::generate();
+        let bytes = pair.to_bytes();
+        let pem = |label: &str, prefix: &[u8], key: &[u8]| {
+            format!(
+                "-----BEGIN {0}-----\n{1}\n-----END {0}-----\n",
+                label,
+                Base64::encode_to_string([prefix, key].concat()).unwrap()
+            )
+        };
+        let file = |suffix: &str| {
+            std::env::temp_dir().join(format!("barrier-jwt-{}.{}", Uuid::new_v4(), suffix))
+        };
+        let (private, public) = (file("pem"), file("pub.pem"));
+
+        // the key pair is the seed followed by the public key
+        fs::write(
+            &private,
+            pem("PRIVATE KEY", &ED25519_PKCS8_PREFIX, &bytes[..32]),
+        )
+        .unwrap();
+        fs::write(
+            &public,
+            pem("PUBLIC KEY", &ED25519_SPKI_PREFIX, &bytes[32..]),
+        )
+        .unwrap();
+
+        (private, public)
+    }
+
+    fn key(kid: &str, private: Option<&PathBuf>, public: Option<&PathBuf>) -> JwtKey {
+        let path = |path: &PathBuf| path.to_string_lossy().to_string();
+
+        JwtKey {
+            kid: kid.to_string(),
+            algorithm: JwtAlgorithm::EdDSA,
+            private_key: private.map(path),
+            public_key: public.map(path),
+        }
+    }
+
+    fn issue(jwt: &Jwt) -> String {
+        jwt.issue_token(
+            "admin".to_string(),
+            Vec::new(),
+            Duration::from_mins(1),
+            false,
+        )
+        .0
+    }
+
+    #[test]
+    fn rotation() {
+        let (old_private, old_public) = ed25519_files();
+        let (new_private, _) = ed25519_files();
+        let old = Jwt::from_config(&Config {
+            jwt_keys: vec![key("2024-01", Some(&old_private), None)],
+            ..Config::default()
+        })
+        .unwrap();
+        let rotated = Jwt::from_config(&Config {
+            jwt_keys: vec![
+                key("2024-06", Some(&new_private), None),
+                key("2024-01", None, Some(&old_public)),
+            ],
+            ..Config::default()
+        })
+        .unwrap();
+        let old_token = issue(&old);
+
+        assert_eq!(
+            Token::decode_metadata(&old_token).unwrap().key_id(),
+            Some("2024-01")
+        );
+        // tokens of the old key stay valid during the rotation
+        assert!(rotated.verify_token(old_token.clone()).is_some());
+        assert!(rotated.verify_token(issue(&rotated)).is_some());
+        // the old set doesn't know the new key
+        assert!(old.verify_token(issue(&rotated)).is_none());
+        assert!(Jwt::new("secret".to_string())
+            .verify_token(old_token)
+            .is_none());
+
+        let jwks = rotated.jwks();
+
+        assert_eq!(jwks["keys"][0]["kid"], "2024-06");
+        assert_eq!(jwks["keys"][1]["kid"], "2024-01");
+        assert_eq!(jwks["keys"][1]["kty"], "OKP");
+        assert_eq!(Jwt::new("secret".to_string()).jwks(), json!({ "keys": [] }));
+
+        for file in [old_private, old_public, new_private] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn refresh_token_hash() {
+        let jwt = Jwt::new("secret".to_string());
+        let hash = jwt.refresh_token_hash("REFRESH_TOKEN");
+
+        assert_eq!(hash, jwt.refresh_token_hash("REFRESH_TOKEN"));
+        assert_eq!(hash.len(), 64);
+        assert_ne!(hash, jwt.refresh_token_hash("OTHER_REFRESH_TOKEN"));
+        assert_ne!(
+            hash,
+            Jwt::new("other secret".to_string()).refresh_token_hash("REFRESH_TOKEN")
+        );
+
+        // a new jwt_key keeps the stored refresh tokens
+        let jwt = |jwt_key: &str| {
+            Jwt::from_config(&Config {
+                jwt_key: jwt_key.to_string(),
+                refresh_token_key: "refresh secret".to_string(),
+                ..Config::default()
+            })
+            .unwrap()
+        };
+
+        assert_eq!(
+            jwt("secret").refresh_token_hash("REFRESH_TOKEN"),
+            jwt("rotated secret").refresh_token_hash("REFRESH_TOKEN")
+        );
+        assert_eq!(
+            jwt("secret").refresh_token_hash("REFRESH_TOKEN"),
+            Jwt::new("refresh secret".to_string()).refresh_token_hash("REFRESH_TOKEN")
+        );
+        assert_eq!(
+            jwt("secret").refresh_key_check(),
+            jwt("rotated secret").refresh_key_check()
+        );
+        assert_ne!(
+            jwt("secret").refresh_key_check(),
+            Jwt::new("secret".to_string()).refresh_key_check()
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_key_files() {
+        let (private, public) = ed25519_files();
+
+        // a public key can't sign
+        assert!(Jwt::from_config(&Config {
+            jwt_keys: vec![key("2024-01", None, Some(&public))],
+            ..Config::default()
+        })
+        .is_err());
+        assert!(Jwt::from_config(&Config {
+            jwt_keys: vec![JwtKey {
+                algorithm: JwtAlgorithm::RS256,
+                ..key("2024-01", Some(&private), None)
+            }],
+            ..Config::default()
+        })
+        .is_err());
 
-        Some(claims.custom)
+        fs::remove_file(private).unwrap();
+        fs::remove_file(public).unwrap();
     }
 }
@@ -2,3 +2,8 @@ pub mod auth;
 pub mod db;
 pub mod gate;
 pub mod jwt;
+pub mod oidc;
+pub mod revocation;
+pub mod throttle;
+pub mod totp;
+pub mod users;
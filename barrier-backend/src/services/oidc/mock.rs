@@ -0,0 +1,177 @@
+//! Identity provider for the tests: discovery, authorization with PKCE, token and JWKS
+//! endpoints, signing ID tokens with an ES256 key.
+
+use super::code_challenge;
+use actix_web::{http::header, web, HttpResponse};
+use jwt_simple::prelude::*;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+const KEY_ID: &str = "mock";
+
+/// Code issued by `/authorize`, redeemed once by `/token`.
+struct Grant {
+    redirect_uri: String,
+    code_challenge: String,
+    nonce: String,
+}
+
+pub struct MockIdp {
+    issuer: String,
+    client_id: String,
+    key: ES256KeyPair,
+    /// User who signs in at `/authorize` and their groups.
+    user: Mutex<(String, Vec<String>)>,
+    /// Nonce put in the ID tokens instead of the requested one.
+    nonce: Mutex<Option<String>>,
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+impl MockIdp {
+    pub fn new(issuer: String, client_id: &str) -> Self {
+        Self {
+            issuer,
+            client_id: client_id.to_string(),
+            key: ES256KeyPair::generate().with_key_id(KEY_ID),
+            user: Mutex::new((String::new(), Vec::new())),
+            nonce: Mutex::new(None),
+            grants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn sign_in(&self, username: &str, groups: &[&str]) {
+        *self.user.lock().unwrap() = (
+            username.to_string(),
+            groups.iter().map(|group| group.to_string()).collect(),
+        );
+    }
+
+    pub fn forge_nonce(&self, nonce: &str) {
+        *self.nonce.lock().unwrap() = Some(nonce.to_string());
+    }
+
+    fn id_token(&self, nonce: &str) -> String {
+        let (username, groups) = self.user.lock().unwrap().clone();
+        let nonce = self
+            .nonce
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| nonce.to_string());
+        let claims = Claims::with_custom_claims(
+            json!({ "preferred_username": username, "groups": groups }),
+            Duration::from_mins(5),
+        )
+        .with_issuer(&self.issuer)
+        .with_audience(&self.client_id)
+        .with_subject(format!("sub-{}", username))
+        .with_nonce(nonce);
+
+        self.key.sign(claims).expect("id token")
+    }
+}
+
+async fn discovery(idp: web::Data<Arc<MockIdp>>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "issuer": idp.issuer,
+        "authorization_endpoint": format!("{}/authorize", idp.issuer),
+        "token_endpoint": format!("{}/token", idp.issuer),
+        "jwks_uri": format!("{}/jwks", idp.issuer),
+    }))
+}
+
+async fn authorize(
+    query: web::Query<HashMap<String, String>>,
+    idp: web::Data<Arc<MockIdp>>,
+) -> HttpResponse {
+    let param = |name: &str| query.get(name).cloned().unwrap_or_default();
+
+    if param("response_type") != "code"
+        || param("client_id") != idp.client_id
+        || param("code_challenge_method") != "S256"
+    {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let code = Uuid::new_v4().to_string();
+    let location = reqwest::Url::parse_with_params(
+        &param("redirect_uri"),
+        &[("code", code.as_str()), ("state", &param("state"))],
+    )
+    .expect("redirect_uri");
+
+    idp.grants.lock().unwrap().insert(
+        code,
+        Grant {
+            redirect_uri: param("redirect_uri"),
+            code_challenge: param("code_challenge"),
+            nonce: param("nonce"),
+        },
+    );
+
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location.as_str()))
+        .finish()
+}
+
+async fn token(
+    form: web::Form<HashMap<String, String>>,
+    idp: web::Data<Arc<MockIdp>>,
+) -> HttpResponse {
+    let param = |name: &str| form.get(name).cloned().unwrap_or_default();
+    let grant = idp.grants.lock().unwrap().remove(&param("code"));
+
+    match grant {
+        Some(grant)
+            if param("grant_type") == "authorization_code"
+                && param("client_id") == idp.client_id
+                && param("redirect_uri") == grant.redirect_uri
+                && code_challenge(&param("code_verifier")) == grant.code_challenge =>
+        {
+            HttpResponse::Ok().json(json!({
+                "access_token": Uuid::new_v4().to_string(),
+                "token_type": "Bearer",
+                "id_token": idp.id_token(&grant.nonce),
+            }))
+        }
+        _ => HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+    }
+}
+
+async fn jwks(idp: web::Data<Arc<MockIdp>>) -> HttpResponse {
+    let key = idp.key.public_key();
+    // uncompressed SEC1 point: 0x04, x, y
+    let point = key.public_key().to_bytes_uncompressed();
+    let encode = |bytes: &[u8]| Base64UrlSafeNoPadding::encode_to_string(bytes).expect("base64");
+
+    HttpResponse::Ok().json(json!({
+        "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "alg": "ES256",
+            "use": "sig",
+            "kid": KEY_ID,
+            "x": encode(&point[1..33]),
+            "y": encode(&point[33..]),
+        }]
+    }))
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig, idp: Arc<MockIdp>) {
+    cfg.app_data(web::Data::new(idp))
+        .route(
+            "/.well-known/openid-configuration",
+            web::get().to(discovery),
+        )
+        .route("/authorize", web::get().to(authorize))
+        .route("/token", web::post().to(token))
+        .route("/jwks", web::get().to(jwks));
+}
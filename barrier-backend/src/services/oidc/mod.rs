@@ -0,0 +1,406 @@
+//! OpenID Connect login with the authorization code flow and PKCE.
+//!
+//! `/auth/oidc/start` redirects the browser to the identity provider, which sends it back to
+//! `/auth/oidc/callback` with a code. The code is exchanged for an ID token, whose groups claim
+//! maps to gates through `[gates]` and `[gate_control]` the same way LDAP groups do.
+//!
+//! The start sets a cookie the callback must bring back, so only the browser which started the
+//! login finishes it. The users are named `oidc:{sub}@{issuer}`, apart from the LDAP and local
+//! ones.
+
+use super::auth::Identity;
+use crate::{
+    config,
+    structs::{Errors, Gate},
+};
+use actix_web::cookie::{self, Cookie, SameSite};
+use derive_more::Display;
+use jwt_simple::prelude::*;
+use log::{debug, error};
+use rand::RngCore;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::Mutex as StdMutex,
+    time::{Duration as StdDuration, Instant},
+};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+
+#[cfg(test)]
+pub mod mock;
+
+/// How long the provider has to send the browser back.
+const PENDING_TTL: StdDuration = StdDuration::from_secs(10 * 60);
+/// Logins started at once, the oldest is dropped beyond it.
+const MAX_PENDING: usize = 1000;
+const TIMEOUT: StdDuration = StdDuration::from_secs(10);
+/// Binds the login to the browser which started it.
+pub const BROWSER_COOKIE: &str = "barrier_oidc";
+
+#[derive(Clone, Debug, Display, PartialEq, Eq)]
+pub enum OidcError {
+    #[display(fmt = "unknown or expired state")]
+    UnknownState,
+    #[display(fmt = "login started by another browser")]
+    OtherBrowser,
+    #[display(fmt = "identity provider refused the login: {}", _0)]
+    Denied(String),
+    #[display(fmt = "invalid ID token: {}", _0)]
+    InvalidToken(String),
+    #[display(fmt = "identity provider is unavailable: {}", _0)]
+    Unavailable(String),
+}
+
+impl From<OidcError> for Errors {
+    fn from(e: OidcError) -> Self {
+        match e {
+            OidcError::Unavailable(_) => Errors::AuthUnavailable,
+            _ => Errors::InvalidLogin,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: String,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+enum Key {
+    Rs256(RS256PublicKey),
+    Es256(ES256PublicKey),
+}
+
+impl Key {
+    fn from_jwk(jwk: &Jwk) -> Option<Self> {
+        let decode = |s: &Option<String>| {
+            Base64UrlSafeNoPadding::decode_to_vec(s.as_deref()?.as_bytes(), None).ok()
+        };
+
+        match (jwk.kty.as_str(), jwk.crv.as_deref()) {
+            ("RSA", _) => RS256PublicKey::from_components(&decode(&jwk.n)?, &decode(&jwk.e)?)
+                .ok()
+                .map(Key::Rs256),
+            ("EC", Some("P-256")) => {
+                let mut point = vec![0x04];
+
+                point.extend(decode(&jwk.x)?);
+                point.extend(decode(&jwk.y)?);
+                ES256PublicKey::from_bytes(&point).ok().map(Key::Es256)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    /// Signing keys by `kid`.
+    keys: HashMap<String, Key>,
+}
+
+/// Login started by `/auth/oidc/start`, keyed by its `state`.
+struct Pending {
+    /// Value of `BROWSER_COOKIE`.
+    browser: String,
+    verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+pub struct Oidc {
+    config: config::Oidc,
+    client: reqwest::Client,
+    gate_mappings: HashMap<String, Vec<Gate>>,
+    /// Discovered on the first login, the keys are fetched again for an unknown `kid`.
+    provider: Mutex<Option<Provider>>,
+    pending: StdMutex<HashMap<String, Pending>>,
+}
+
+fn random_string() -> String {
+    let mut bytes = [0u8; 32];
+
+    rand::thread_rng().fill_bytes(&mut bytes);
+    Base64UrlSafeNoPadding::encode_to_string(bytes).expect("base64")
+}
+
+pub fn code_challenge(verifier: &str) -> String {
+    Base64UrlSafeNoPadding::encode_to_string(Sha256::digest(verifier.as_bytes())).expect("base64")
+}
+
+impl Oidc {
+    pub fn new(config: &config::Oidc, gate_mappings: HashMap<String, Vec<Gate>>) -> Self {
+        Oidc {
+            config: config.clone(),
+            client: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("http client"),
+            gate_mappings,
+            provider: Mutex::new(None),
+            pending: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, OidcError> {
+        let unavailable = |e: reqwest::Error| OidcError::Unavailable(format!("{}: {}", url, e));
+        let body = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(unavailable)?
+            .text()
+            .await
+            .map_err(unavailable)?;
+
+        serde_json::from_str(&body).map_err(|e| OidcError::Unavailable(format!("{}: {}", url, e)))
+    }
+
+    async fn fetch_keys(&self, jwks_uri: &str) -> Result<HashMap<String, Key>, OidcError> {
+        let jwks: JwkSet = self.get_json(jwks_uri).await?;
+
+        Ok(jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| Some((jwk.kid.clone(), Key::from_jwk(jwk)?)))
+            .collect())
+    }
+
+    /// Discovers the provider unless already known.
+    async fn provider(&self) -> Result<MappedMutexGuard<'_, Provider>, OidcError> {
+        let mut provider = self.provider.lock().await;
+
+        if provider.is_none() {
+            let url = format!(
+                "{}/.well-known/openid-configuration",
+                self.config.issuer.trim_end_matches('/')
+            );
+            let discovery: Discovery = self.get_json(&url).await?;
+            let keys = self.fetch_keys(&discovery.jwks_uri).await?;
+
+            *provider = Some(Provider {
+                authorization_endpoint: discovery.authorization_endpoint,
+                token_endpoint: discovery.token_endpoint,
+                jwks_uri: discovery.jwks_uri,
+                keys,
+            });
+        }
+
+        Ok(MutexGuard::map(provider, |provider| {
+            provider.as_mut().expect("discovered above")
+        }))
+    }
+
+    /// Starts a login, returns the URL of the provider to send the browser to and the cookie
+    /// to set in it.
+    pub async fn start(&self) -> Result<(String, Cookie<'static>), OidcError> {
+        let authorization_endpoint = self.provider().await?.authorization_endpoint.clone();
+        let state = random_string();
+        let pending = Pending {
+            browser: random_string(),
+            verifier: random_string(),
+            nonce: random_string(),
+            created: Instant::now(),
+        };
+
+        let url = reqwest::Url::parse_with_params(
+            &authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("scope", &self.config.scopes.join(" ")),
+                ("state", &state),
+                ("nonce", &pending.nonce),
+                ("code_challenge", &code_challenge(&pending.verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| OidcError::Unavailable(format!("{}: {}", authorization_endpoint, e)))?;
+
+        let mut logins = self.pending.lock().unwrap();
+
+        logins.retain(|_, login| login.created.elapsed() < PENDING_TTL);
+
+        if logins.len() >= MAX_PENDING {
+            if let Some(oldest) = logins
+                .iter()
+                .min_by_key(|(_, login)| login.created)
+                .map(|(state, _)| state.clone())
+            {
+                logins.remove(&oldest);
+            }
+        }
+
+        // sent back by the redirect from the provider, another site, but not by its requests
+        let cookie = Cookie::build(BROWSER_COOKIE, pending.browser.clone())
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(self.config.redirect_uri.starts_with("https:"))
+            .max_age(cookie::time::Duration::seconds(PENDING_TTL.as_secs() as i64))
+            .finish();
+
+        logins.insert(state, pending);
+
+        Ok((url.into(), cookie))
+    }
+
+    /// Finishes the login started with `state` in the browser bringing the `BROWSER_COOKIE`
+    /// value `browser`, returns the user and the gates of their groups.
+    pub async fn callback(
+        &self,
+        state: &str,
+        code: &str,
+        browser: Option<&str>,
+    ) -> Result<(String, Identity), OidcError> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.created.elapsed() < PENDING_TTL)
+            .ok_or(OidcError::UnknownState)?;
+
+        // someone else's state and code, a victim would be logged in as them
+        if browser != Some(pending.browser.as_str()) {
+            return Err(OidcError::OtherBrowser);
+        }
+
+        let token_endpoint = self.provider().await?.token_endpoint.clone();
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &pending.verifier),
+        ];
+
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let unavailable = |e: reqwest::Error| OidcError::Unavailable(e.to_string());
+        let resp = self
+            .client
+            .post(&token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(unavailable)?;
+        let status = resp.status();
+        let body = resp.text().await.map_err(unavailable)?;
+
+        if status.is_server_error() {
+            return Err(OidcError::Unavailable(format!(
+                "{} answered {}",
+                token_endpoint, status
+            )));
+        }
+
+        if !status.is_success() {
+            return Err(OidcError::Denied(
+                serde_json::from_str::<ErrorResponse>(&body)
+                    .map(|e| e.error)
+                    .unwrap_or_else(|_| status.to_string()),
+            ));
+        }
+
+        let id_token = serde_json::from_str::<TokenResponse>(&body)
+            .map_err(|e| OidcError::InvalidToken(e.to_string()))?
+            .id_token;
+        let claims = self.verify(&id_token, &pending.nonce).await?;
+
+        // the names of the provider may be taken by LDAP or local users
+        let username = claims
+            .subject
+            .map(|subject| format!("oidc:{}@{}", subject, self.config.issuer))
+            .ok_or_else(|| OidcError::InvalidToken("no sub".to_string()))?;
+        let groups = match claims.custom.get(&self.config.groups_claim) {
+            Some(Value::Array(groups)) => groups
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+
+        debug!("OIDC groups of {:?}: {:?}", username, groups);
+
+        Ok((username, Identity::new("oidc", &self.gate_mappings, groups)))
+    }
+
+    /// Checks the signature, issuer, audience, expiry and nonce of the ID token.
+    async fn verify(
+        &self,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<JWTClaims<serde_json::Map<String, Value>>, OidcError> {
+        let invalid = |e: jwt_simple::Error| OidcError::InvalidToken(e.to_string());
+        let metadata = Token::decode_metadata(id_token).map_err(invalid)?;
+        let kid = metadata.key_id().unwrap_or_default().to_string();
+
+        let mut provider = self.provider().await?;
+
+        if !provider.keys.contains_key(&kid) {
+            // the provider rotated its keys
+            provider.keys = self.fetch_keys(&provider.jwks_uri).await?;
+        }
+
+        let options = VerificationOptions {
+            required_nonce: Some(nonce.to_string()),
+            allowed_issuers: Some(HashSet::from_strings(&[&self.config.issuer])),
+            allowed_audiences: Some(HashSet::from_strings(&[&self.config.client_id])),
+            time_tolerance: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+
+        let claims = match (metadata.algorithm(), provider.keys.get(&kid)) {
+            ("RS256", Some(Key::Rs256(key))) => key.verify_token(id_token, Some(options)),
+            ("ES256", Some(Key::Es256(key))) => key.verify_token(id_token, Some(options)),
+            (algorithm, _) => {
+                error!("No {} key {:?} from the identity provider", algorithm, kid);
+                return Err(OidcError::InvalidToken(format!(
+                    "no {} key {:?}",
+                    algorithm, kid
+                )));
+            }
+        };
+
+        claims.map_err(invalid)
+    }
+}
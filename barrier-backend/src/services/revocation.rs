@@ -0,0 +1,139 @@
+//! Revoked access tokens.
+//!
+//! An access token stays valid until it expires, so ending a session also refuses the access
+//! tokens of its `session_id` until the last of them has expired. The list lives in the `Db`
+//! for every instance and in memory for the `JWTToken` extractor, reloaded every
+//! `SYNC_INTERVAL` to see the sessions revoked elsewhere.
+
+use super::{db::Db, jwt::ACCESS_TOKEN_SECS};
+use chrono::Utc;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Revokes every session of the user and removes their refresh tokens, returns the revoked
+/// sessions with the moment their access tokens have expired.
+pub async fn end_sessions(db: &dyn Db, username: &str, now: i64) -> Vec<(String, i64)> {
+    let until = now + ACCESS_TOKEN_SECS as i64;
+    let mut revoked = Vec::new();
+
+    for session in db.sessions(username).await {
+        db.revoke_session(&session.session_id, until).await;
+        revoked.push((session.session_id, until));
+    }
+
+    db.remove_by_username(username).await;
+    revoked
+}
+
+#[derive(Default)]
+pub struct Revocations {
+    /// Revoked sessions with the moment their access tokens have expired.
+    revoked: Mutex<HashMap<String, i64>>,
+    synced: Mutex<Option<Instant>>,
+}
+
+impl Revocations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn remember(&self, revoked: impl IntoIterator<Item = (String, i64)>, now: i64) {
+        let mut sessions = self.revoked.lock().unwrap();
+
+        sessions.extend(revoked);
+        sessions.retain(|_, until| *until > now);
+    }
+
+    pub async fn revoke(&self, db: &dyn Db, session_id: &str) {
+        let now = Utc::now().timestamp();
+        let until = now + ACCESS_TOKEN_SECS as i64;
+
+        db.revoke_session(session_id, until).await;
+        self.remember([(session_id.to_string(), until)], now);
+    }
+
+    /// Ends every session of the user, see `end_sessions`.
+    pub async fn revoke_user(&self, db: &dyn Db, username: &str) {
+        let now = Utc::now().timestamp();
+
+        self.remember(end_sessions(db, username, now).await, now);
+    }
+
+    /// Takes `db` unlocked, it is only locked to reload the list.
+    pub async fn is_revoked(&self, db: &AsyncMutex<Box<dyn Db + Send>>, session_id: &str) -> bool {
+        let stale = self
+            .synced
+            .lock()
+            .unwrap()
+            .map(|synced| synced.elapsed() >= SYNC_INTERVAL)
+            .unwrap_or(true);
+        let now = Utc::now().timestamp();
+
+        if stale {
+            let revoked = db.lock().await.revoked_sessions(now).await;
+
+            *self.revoked.lock().unwrap() = revoked.into_iter().collect();
+            *self.synced.lock().unwrap() = Some(Instant::now());
+        }
+
+        self.revoked
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .map(|until| *until > now)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::db::{Cache, SessionItem};
+
+    #[tokio::test]
+    async fn revoked_until_expiry() {
+        let db: Box<dyn Db + Send> = Box::new(Cache::new().await);
+        let db = AsyncMutex::new(db);
+        let revocations = Revocations::new();
+        let now = Utc::now().timestamp();
+
+        for session_id in ["PHONE", "LAPTOP"] {
+            db.lock()
+                .await
+                .store_session(&SessionItem {
+                    session_id: session_id.to_string(),
+                    username: "admin".to_string(),
+                    user_agent: "curl".to_string(),
+                    ip: "10.0.0.1".to_string(),
+                    created: mongodb::bson::DateTime::now(),
+                    last_refresh: mongodb::bson::DateTime::now(),
+                })
+                .await;
+        }
+
+        assert!(!revocations.is_revoked(&db, "PHONE").await);
+
+        revocations.revoke(db.lock().await.as_ref(), "PHONE").await;
+        assert!(revocations.is_revoked(&db, "PHONE").await);
+        assert!(!revocations.is_revoked(&db, "LAPTOP").await);
+
+        // revoked by another instance, seen on the next reload
+        db.lock().await.revoke_session("TABLET", now + 60).await;
+        db.lock().await.revoke_session("EXPIRED", now - 1).await;
+        *revocations.synced.lock().unwrap() = None;
+        assert!(revocations.is_revoked(&db, "TABLET").await);
+        assert!(!revocations.is_revoked(&db, "EXPIRED").await);
+
+        revocations
+            .revoke_user(db.lock().await.as_ref(), "admin")
+            .await;
+        assert!(revocations.is_revoked(&db, "LAPTOP").await);
+        assert!(db.lock().await.sessions("admin").await.is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+//! Brute-force protection of the logins.
+//!
+//! Failed logins are counted per user and per address over a sliding window through the `Db`,
+//! so the limits hold across instances. Every failure of a user delays their next login more,
+//! reaching the limit locks the user or the address out until `lockout_secs` after the last
+//! failure.
+
+use super::db::Db;
+use crate::config;
+use std::time::Duration;
+
+pub struct Throttle {
+    config: config::Throttle,
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+impl Throttle {
+    pub fn new(config: &config::Throttle) -> Self {
+        Throttle {
+            config: config.clone(),
+        }
+    }
+
+    /// Seconds left of a lockout after `failures`, `None` when not locked out.
+    fn locked_for(&self, failures: &[i64], max: u32, now: i64) -> Option<u64> {
+        let last = failures.last()?;
+        let until = last + self.config.lockout_secs as i64;
+
+        (max > 0 && failures.len() >= max as usize && until > now).then(|| (until - now) as u64)
+    }
+
+    /// Delay of the next login after `failures` of the user.
+    fn delay(&self, failures: usize) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+
+        let factor = 1u64.checked_shl(failures as u32 - 1).unwrap_or(u64::MAX);
+
+        Duration::from_millis(
+            self.config
+                .delay_ms
+                .saturating_mul(factor)
+                .min(self.config.max_delay_ms),
+        )
+    }
+
+    /// The delay before checking the password, or the seconds to wait while locked out.
+    pub async fn check(
+        &self,
+        db: &dyn Db,
+        username: &str,
+        ip: &str,
+        now: i64,
+    ) -> Result<Duration, u64> {
+        let since = now - self.config.window_secs as i64;
+        let user = db.login_failures(&user_key(username), since).await;
+        let from_ip = db.login_failures(&ip_key(ip), since).await;
+
+        let locked = [
+            self.locked_for(&user, self.config.max_user_failures, now),
+            self.locked_for(&from_ip, self.config.max_ip_failures, now),
+        ];
+
+        match locked.iter().flatten().max() {
+            Some(retry_after) => Err(*retry_after),
+            None => Ok(self.delay(user.len())),
+        }
+    }
+
+    pub async fn failed(&self, db: &dyn Db, username: &str, ip: &str, now: i64) {
+        db.add_login_failure(&user_key(username), now).await;
+        db.add_login_failure(&ip_key(ip), now).await;
+    }
+
+    /// Forgets the failures of the user, the address keeps its own.
+    pub async fn succeeded(&self, db: &dyn Db, username: &str) {
+        db.clear_login_failures(&user_key(username)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::db::Cache;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn delay_and_lockout() {
+        let db = Cache::new().await;
+        let throttle = Throttle::new(&config::Throttle {
+            window_secs: 60,
+            max_user_failures: 3,
+            max_ip_failures: 5,
+            lockout_secs: 30,
+            delay_ms: 100,
+            max_delay_ms: 150,
+        });
+        let check = |username: &'static str, ip: &'static str, now| {
+            let (throttle, db) = (&throttle, &db);
+
+            async move { throttle.check(db, username, ip, now).await }
+        };
+
+        assert_eq!(check("john", "10.0.0.1", 0).await, Ok(Duration::ZERO));
+
+        throttle.failed(&db, "john", "10.0.0.1", 0).await;
+        assert_eq!(
+            check("john", "10.0.0.1", 1).await,
+            Ok(Duration::from_millis(100))
+        );
+
+        throttle.failed(&db, "john", "10.0.0.1", 1).await;
+        assert_eq!(
+            check("john", "10.0.0.2", 2).await,
+            Ok(Duration::from_millis(150))
+        );
+
+        throttle.failed(&db, "john", "10.0.0.2", 10).await;
+        assert_eq!(check("john", "10.0.0.3", 10).await, Err(30));
+        assert_eq!(check("john", "10.0.0.3", 35).await, Err(5));
+        // the lockout is over, one more failure locks the user out again
+        assert_eq!(
+            check("john", "10.0.0.3", 40).await,
+            Ok(Duration::from_millis(150))
+        );
+        // failures slide out of the window
+        assert_eq!(
+            check("john", "10.0.0.3", 62).await,
+            Ok(Duration::from_millis(100))
+        );
+
+        // the address is locked out for everyone
+        for (i, username) in ["jane", "jack", "jill"].iter().enumerate() {
+            throttle
+                .failed(&db, username, "10.0.0.1", 20 + i as i64)
+                .await;
+        }
+        assert_eq!(check("joe", "10.0.0.1", 30).await, Err(22));
+
+        throttle.succeeded(&db, "john").await;
+        assert_eq!(check("john", "10.0.0.3", 40).await, Ok(Duration::ZERO));
+    }
+}
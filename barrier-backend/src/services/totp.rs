@@ -0,0 +1,237 @@
+//! TOTP second factor of RFC 6238: 6 digits every 30 seconds with HMAC-SHA1, which every
+//! authenticator app understands.
+//!
+//! A login needing the second factor stops at a challenge after the password, the code then
+//! completes it through `/auth/login/verify`. Logins through OIDC leave the second factor to the
+//! identity provider.
+
+use super::auth::Identity;
+use crate::config;
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha1::Sha1;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+pub const STEP_SECS: u64 = 30;
+/// Steps accepted before and after the current one, for clocks running apart.
+const SKEW: u64 = 1;
+/// How long the user has to enter the code.
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Wrong codes before a challenge is dropped and the password is needed again.
+const MAX_ATTEMPTS: u32 = 5;
+const MAX_CHALLENGES: usize = 1000;
+
+/// Base32 secret as entered into the authenticator app.
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+pub fn now_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time")
+        .as_secs()
+        / STEP_SECS
+}
+
+/// The code of `step`, `None` for a malformed secret.
+pub fn code(secret: &str, step: u64) -> Option<String> {
+    let key = BASE32_NOPAD
+        .decode(secret.trim_end_matches('=').as_bytes())
+        .ok()?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+
+    mac.update(&step.to_be_bytes());
+
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    Some(format!("{:06}", value % 1_000_000))
+}
+
+/// The step `code` belongs to, codes of `last_step` and before are not taken twice.
+pub fn verify(secret: &str, code: &str, now: u64, last_step: Option<u64>) -> Option<u64> {
+    (now.saturating_sub(SKEW)..=now + SKEW)
+        .filter(|step| last_step.map(|last| *step > last).unwrap_or(true))
+        .find(|step| self::code(secret, *step).as_deref() == Some(code))
+}
+
+/// `otpauth://` URI for the QR code of the authenticator app.
+pub fn uri(issuer: &str, username: &str, secret: &str) -> String {
+    let mut uri = reqwest::Url::parse("otpauth://totp/").expect("otpauth URI");
+
+    uri.set_path(&format!("{}:{}", issuer, username));
+    uri.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", issuer);
+
+    uri.into()
+}
+
+/// Login waiting for its code.
+pub struct Challenge {
+    pub username: String,
+    pub identity: Identity,
+    /// Secret enrolled by this login, for users needing a second factor without one.
+    pub enroll: Option<String>,
+    created: Instant,
+    attempts: u32,
+}
+
+pub struct Totp {
+    config: config::Totp,
+    challenges: Mutex<HashMap<String, Challenge>>,
+}
+
+impl Totp {
+    pub fn new(config: &config::Totp) -> Self {
+        Totp {
+            config: config.clone(),
+            challenges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.config.issuer
+    }
+
+    /// Whether the groups or the gates of the user call for a second factor.
+    pub fn required(&self, identity: &Identity) -> bool {
+        identity
+            .groups
+            .iter()
+            .any(|group| self.config.groups.contains(group))
+            || identity
+                .gates
+                .iter()
+                .any(|gate| self.config.gates.contains(&gate.name))
+    }
+
+    /// Stops the login at a challenge, returns its id.
+    pub fn challenge(&self, username: &str, identity: Identity, enroll: Option<String>) -> String {
+        let id = Uuid::new_v4().to_string();
+        let mut challenges = self.challenges.lock().unwrap();
+
+        challenges.retain(|_, challenge| challenge.created.elapsed() < CHALLENGE_TTL);
+
+        if challenges.len() >= MAX_CHALLENGES {
+            if let Some(oldest) = challenges
+                .iter()
+                .min_by_key(|(_, challenge)| challenge.created)
+                .map(|(id, _)| id.clone())
+            {
+                challenges.remove(&oldest);
+            }
+        }
+
+        challenges.insert(
+            id.clone(),
+            Challenge {
+                username: username.to_string(),
+                identity,
+                enroll,
+                created: Instant::now(),
+                attempts: 0,
+            },
+        );
+
+        id
+    }
+
+    /// Takes the challenge out for its code to be checked.
+    pub fn take(&self, id: &str) -> Option<Challenge> {
+        self.challenges
+            .lock()
+            .unwrap()
+            .remove(id)
+            .filter(|challenge| challenge.created.elapsed() < CHALLENGE_TTL)
+    }
+
+    /// Puts back a challenge answered with a wrong code, unless it has run out of attempts.
+    pub fn retry(&self, id: &str, mut challenge: Challenge) {
+        challenge.attempts += 1;
+
+        if challenge.attempts < MAX_ATTEMPTS {
+            self.challenges
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), challenge);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::Gate;
+    use pretty_assertions::assert_eq;
+
+    // the secret of the RFC 6238 SHA1 test vectors, "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn rfc_6238_codes() {
+        // the RFC lists 8 digits, these are the last 6
+        assert_eq!(code(RFC_SECRET, 59 / STEP_SECS).unwrap(), "287082");
+        assert_eq!(code(RFC_SECRET, 1111111109 / STEP_SECS).unwrap(), "081804");
+        assert_eq!(code(RFC_SECRET, 1234567890 / STEP_SECS).unwrap(), "005924");
+        assert_eq!(code(RFC_SECRET, 20000000000 / STEP_SECS).unwrap(), "353130");
+        assert_eq!(code("not base32!", 1), None);
+    }
+
+    #[test]
+    fn verify_window_and_replay() {
+        let now = 1234567890 / STEP_SECS;
+        let previous = code(RFC_SECRET, now - 1).unwrap();
+
+        assert_eq!(verify(RFC_SECRET, &previous, now, None), Some(now - 1));
+        assert_eq!(verify(RFC_SECRET, &previous, now + 2, None), None);
+        assert_eq!(verify(RFC_SECRET, &previous, now, Some(now - 1)), None);
+        assert_eq!(verify(RFC_SECRET, "000000", now, None), None);
+        assert_eq!(generate_secret().len(), 32);
+    }
+
+    #[test]
+    fn required_by_groups_or_gates() {
+        let totp = Totp::new(&config::Totp {
+            groups: vec!["admins".to_string()],
+            gates: vec!["server_room".to_string()],
+            ..config::Totp::default()
+        });
+        let identity = |groups: &[&str], gate: &str| Identity {
+            provider: "ldap".to_string(),
+            gates: vec![Gate {
+                id: 1,
+                retries: 1,
+                name: gate.to_string(),
+                description: "".to_string(),
+                control: false,
+            }],
+            groups: groups.iter().map(|group| group.to_string()).collect(),
+            degraded: false,
+        };
+
+        assert!(totp.required(&identity(&["admins"], "barrier")));
+        assert!(totp.required(&identity(&["developers"], "server_room")));
+        assert!(!totp.required(&identity(&["developers"], "barrier")));
+        assert_eq!(
+            uri("Barrier", "john doe", "ABC"),
+            "otpauth://totp/Barrier:john%20doe?secret=ABC&issuer=Barrier"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Local users for small sites and contractors outside the directory.
+//!
+//! The users live in a TOML file with their argon2id password hashes and groups, the groups map
+//! to gates through `[gates]` and `[gate_control]` the same way LDAP groups do.
+
+use super::auth::AuthError;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    /// PHC string of the argon2id hash.
+    pub password: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Users {
+    #[serde(default)]
+    pub users: BTreeMap<String, User>,
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("failed to hash the password: {}", e))
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+impl Users {
+    /// Reads the users, a missing file has none.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    /// Replaces the file at once, so a running server never reads half of it.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let s = toml::to_string(self).map_err(|e| e.to_string())?;
+        let tmp = path.with_extension("tmp");
+
+        write_private(&tmp, &s)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn add(&mut self, name: &str, password: &str, groups: Vec<String>) -> Result<(), String> {
+        if name.is_empty() || password.is_empty() {
+            return Err("the name and the password must not be empty".to_string());
+        }
+
+        if self.users.contains_key(name) {
+            return Err(format!("user {} already exists", name));
+        }
+
+        self.users.insert(
+            name.to_string(),
+            User {
+                password: hash_password(password)?,
+                groups,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        self.users
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| format!("no user {}", name))
+    }
+
+    pub fn set_password(&mut self, name: &str, password: &str) -> Result<(), String> {
+        if password.is_empty() {
+            return Err("the password must not be empty".to_string());
+        }
+
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| format!("no user {}", name))?;
+
+        user.password = hash_password(password)?;
+
+        Ok(())
+    }
+
+    /// The user with this name and password.
+    pub fn verify(&self, name: &str, password: &str) -> Result<&User, AuthError> {
+        match self.users.get(name) {
+            Some(user) if verify_password(password, &user.password) => Ok(user),
+            Some(_) => Err(AuthError::InvalidCredentials),
+            None => {
+                // take as long as a wrong password, not to tell which users exist
+                let _ = hash_password(password);
+                Err(AuthError::UnknownUser)
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, s: &str) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, s: &str) -> io::Result<()> {
+    fs::write(path, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn manage_users() {
+        let mut users = Users::default();
+
+        users
+            .add("contractor", "secret", vec!["developers".to_string()])
+            .unwrap();
+
+        assert!(users.users["contractor"].password.starts_with("$argon2id$"));
+        assert!(users.add("contractor", "other", Vec::new()).is_err());
+        assert_eq!(
+            users
+                .verify("contractor", "secret")
+                .map(|u| u.groups.clone()),
+            Ok(vec!["developers".to_string()])
+        );
+        assert_eq!(
+            users.verify("contractor", "wrong"),
+            Err(AuthError::InvalidCredentials)
+        );
+        assert_eq!(
+            users.verify("nobody", "secret"),
+            Err(AuthError::UnknownUser)
+        );
+
+        users.set_password("contractor", "changed").unwrap();
+
+        assert_eq!(
+            users.verify("contractor", "secret"),
+            Err(AuthError::InvalidCredentials)
+        );
+        assert!(users.verify("contractor", "changed").is_ok());
+
+        users.remove("contractor").unwrap();
+
+        assert!(users.remove("contractor").is_err());
+        assert_eq!(users, Users::default());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path =
+            std::env::temp_dir().join(format!("barrier-users-{}.toml", uuid::Uuid::new_v4()));
+        let mut users = Users::load(&path).unwrap();
+
+        assert_eq!(users, Users::default());
+
+        users.add("guest", "secret", Vec::new()).unwrap();
+        users.save(&path).unwrap();
+
+        let loaded = Users::load(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, users);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::services::gate::{breaker::CircuitState, GateStatus};
 use actix_web::{
     error,
     http::{header, StatusCode},
@@ -12,6 +13,22 @@ pub enum Errors {
     InvalidLogin,
     #[display(fmt = "Unauthorized access")]
     Unauthorized,
+    #[display(fmt = "Gate controller rejected the command")]
+    ControllerRejected,
+    #[display(fmt = "Gate controller is unreachable")]
+    ControllerUnreachable,
+    #[display(fmt = "Gate controller is failing, try again later")]
+    CircuitOpen,
+    #[display(fmt = "Gate is not configured properly, contact an administrator")]
+    GateMisconfigured,
+    #[display(fmt = "Another command of the gate is in progress")]
+    GateBusy,
+    #[display(fmt = "Authentication is unavailable, try again later")]
+    AuthUnavailable,
+    #[display(fmt = "Second factor is not enrolled, ask an administrator")]
+    TotpNotEnrolled,
+    #[display(fmt = "Too many failed logins, try again in {} seconds", retry_after)]
+    TooManyAttempts { retry_after: u64 },
 }
 
 #[derive(Serialize)]
@@ -25,6 +42,22 @@ pub struct Gate {
     pub name: String,
     pub description: String,
     pub retries: i32,
+    /// Allows close, hold and release besides plain open.
+    #[serde(default)]
+    pub control: bool,
+}
+
+/// What the gate controller answered to a command.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControllerResult {
+    pub code: i32,
+    /// Whether the controller called back to confirm the command, `None` when callbacks are
+    /// disabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirmed: Option<bool>,
+    /// Requests sent to the controller, more than one means it is flaky.
+    #[serde(default)]
+    pub attempts: u32,
 }
 
 impl PartialEq for Gate {
@@ -37,7 +70,13 @@ impl Eq for Gate {}
 
 impl error::ResponseError for Errors {
     fn error_response(&self) -> HttpResponse {
-        HttpResponseBuilder::new(self.status_code())
+        let mut response = HttpResponseBuilder::new(self.status_code());
+
+        if let Errors::TooManyAttempts { retry_after } = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response
             .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
             .json(CommonError {
                 error: self.to_string(),
@@ -46,8 +85,15 @@ impl error::ResponseError for Errors {
 
     fn status_code(&self) -> StatusCode {
         match *self {
-            Errors::InvalidLogin => StatusCode::FORBIDDEN,
+            Errors::InvalidLogin | Errors::TotpNotEnrolled => StatusCode::FORBIDDEN,
             Errors::Unauthorized => StatusCode::UNAUTHORIZED,
+            Errors::ControllerRejected => StatusCode::BAD_GATEWAY,
+            Errors::ControllerUnreachable | Errors::CircuitOpen | Errors::AuthUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Errors::TooManyAttempts { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Errors::GateBusy => StatusCode::CONFLICT,
+            Errors::GateMisconfigured => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -71,6 +117,57 @@ pub mod login {
         pub access_token: String,
         pub refresh_token: String,
     }
+
+    /// Login stopped for a TOTP code, completed by `/auth/login/verify`.
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Challenge {
+        pub challenge: String,
+        /// Secret to add to the authenticator app when the user has none yet.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub enroll: Option<Enrollment>,
+    }
+
+    /// Tokens, or a challenge when the user needs a second factor.
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    #[serde(untagged)]
+    pub enum LoginResponse {
+        Tokens(Response),
+        Challenge(Challenge),
+    }
+
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct VerifyRequest {
+        pub challenge: String,
+        pub code: String,
+    }
+
+    /// TOTP secret for the authenticator app.
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Enrollment {
+        /// Base32 secret.
+        pub secret: String,
+        /// `otpauth://` URI, usually shown as a QR code.
+        pub uri: String,
+    }
+
+    /// First code of the enrolled secret.
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct ConfirmRequest {
+        pub code: String,
+    }
+
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct ConfirmResponse {
+        pub success: bool,
+    }
+
+    /// Query the identity provider sends the browser back with.
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct OidcCallback {
+        pub state: String,
+        pub code: Option<String>,
+        pub error: Option<String>,
+    }
 }
 
 pub mod logout {
@@ -82,20 +179,93 @@ pub mod logout {
     }
 }
 
+pub mod sessions {
+    use super::*;
+
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Session {
+        pub id: String,
+        pub user_agent: String,
+        /// Address of the last login or refresh.
+        pub ip: String,
+        /// Unix timestamps of the login and of the last refresh.
+        pub created: i64,
+        pub last_refresh: i64,
+        /// The session of the token asking.
+        pub current: bool,
+    }
+
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Response {
+        pub sessions: Vec<Session>,
+    }
+}
+
 pub mod open {
     use super::*;
 
     #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
     pub struct Response {
         pub success: bool,
+        pub result: ControllerResult,
     }
 }
 
 pub mod gates {
     use super::*;
 
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct GateInfo {
+        #[serde(flatten)]
+        pub gate: Gate,
+        /// Whether the controller answered the last status query.
+        pub online: bool,
+    }
+
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Response {
+        pub gates: Vec<GateInfo>,
+    }
+}
+
+pub mod status {
+    use super::*;
+
     #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
     pub struct Response {
-        pub gates: Vec<Gate>,
+        pub gate: String,
+        pub status: GateStatus,
+        /// Unix timestamp of the last query.
+        pub checked_at: i64,
+    }
+}
+
+pub mod health {
+    use super::*;
+
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Circuit {
+        pub id: i32,
+        pub gate: String,
+        pub state: CircuitState,
+        pub failures: u32,
+        /// Unix timestamp of the moment the circuit opened.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub opened_at: Option<i64>,
+    }
+
+    /// Readiness, public.
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Response {
+        /// False while any circuit is open.
+        pub healthy: bool,
+    }
+
+    /// Circuits of the gates of the user.
+    #[derive(PartialEq, Debug, Eq, Serialize, Deserialize)]
+    pub struct Details {
+        /// False while the circuit of any of these gates is open.
+        pub healthy: bool,
+        pub circuits: Vec<Circuit>,
     }
 }
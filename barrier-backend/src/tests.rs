@@ -1,20 +1,49 @@
 use super::*;
-use crate::services::db::Cache;
+use crate::{
+    config::{Callback, ConfigGate},
+    services::db::Cache,
+    structs::ControllerResult,
+};
 use actix_web::{http::StatusCode, test};
-use services::auth::FakeAuth;
+use services::{
+    auth::FakeAuth,
+    gate::{
+        callback::DeviceKey,
+        sim::{self, Address, DeviceState, SimConfig, Simulator},
+        FakeGateDriver, GateError, GateStatus,
+    },
+    oidc::mock::{self, MockIdp},
+};
 
 macro_rules! init_test_env {
     () => {{
+        init_test_env!(Arc::new(FakeGateDriver::new()))
+    }};
+    ($gate_driver:expr) => {{
+        init_test_env!($gate_driver, None)
+    }};
+    ($gate_driver:expr, $oidc:expr) => {{
+        init_test_env!($gate_driver, $oidc, Config::default())
+    }};
+    ($gate_driver:expr, $oidc:expr, $config:expr) => {{
+        init_test_env!($gate_driver, $oidc, $config, Cache::new().await)
+    }};
+    ($gate_driver:expr, $oidc:expr, $config:expr, $cache:expr) => {{
         flexi_logger::Logger::try_with_env_or_str("crit")
             .unwrap()
             .start()
             .ok();
 
         let jwt = Jwt::new(JWT_SIGN_KEY.to_string());
-        let cache = Cache::new().await;
+        let cache: Cache = $cache;
         let mut auth = FakeAuth::new();
-        let mut config = Config::default();
-        config.dry_run = true;
+        let config: Config = $config;
+        let gate_driver: Arc<dyn GateDriver + Send + Sync> = $gate_driver;
+        let oidc: Option<Arc<Oidc>> = $oidc;
+        let totp = Arc::new(Totp::new(&config.totp));
+        let throttle = Arc::new(Throttle::new(&config.throttle));
+        let revocations = Arc::new(Revocations::new());
+        let monitor = Arc::new(GateMonitor::new(Vec::new()));
 
         auth.add_user(
             LOGIN_1,
@@ -25,44 +54,72 @@ macro_rules! init_test_env {
                     retries: 1,
                     name: "".to_string(),
                     description: "".to_string(),
+                    control: false,
                 },
                 Gate {
                     id: 2,
                     retries: 1,
                     name: "".to_string(),
                     description: "".to_string(),
+                    control: false,
                 },
             ],
         );
         cache
-            .store_refresh(
-                LOGIN_1,
-                REFRESH_TOKEN_1,
-                &[
+            .store_refresh(&RefreshTokenItem {
+                username: LOGIN_1.to_string(),
+                session_id: String::new(),
+                token_hash: jwt.refresh_token_hash(REFRESH_TOKEN_1),
+                issued: mongodb::bson::DateTime::now(),
+                rooms: vec![
                     Gate {
                         id: 1,
                         retries: 1,
                         name: "".to_string(),
                         description: "".to_string(),
+                        control: false,
                     },
                     Gate {
                         id: 2,
                         retries: 1,
                         name: "".to_string(),
                         description: "".to_string(),
+                        control: false,
                     },
                 ],
-            )
+                used: false,
+                degraded: false,
+            })
             .await;
 
-        let auth: Box<dyn Auth + Send> = Box::new(auth);
-        let auth = Arc::new(Mutex::new(auth));
+        let auth: Arc<dyn Auth + Send + Sync> = Arc::new(auth);
         let cache: Box<dyn Db + Send> = Box::new(cache);
         let cache = Arc::new(Mutex::new(cache));
+        let breaker = Arc::new(CircuitBreaker::new(
+            gate_driver,
+            &config.breaker,
+            cache.clone(),
+        ));
+        let gate_driver: Arc<dyn GateDriver + Send + Sync> = breaker.clone();
         let config = Arc::new(Mutex::new(config));
         let jwt = Arc::new(Mutex::new(jwt));
 
-        let app = App::new().configure(move |cfg| configure_app(cfg, auth, cache, config, jwt));
+        let app = App::new().configure(move |cfg| {
+            configure_app(
+                cfg,
+                auth,
+                cache,
+                config,
+                jwt,
+                gate_driver,
+                oidc,
+                totp,
+                throttle,
+                revocations,
+                monitor,
+                breaker,
+            )
+        });
 
         test::init_service(app).await
     }};
@@ -239,6 +296,35 @@ async fn got_200_on_success_logout() {
     assert_eq!(resp.status(), StatusCode::OK);
 }
 
+#[actix_rt::test]
+async fn access_token_revoked_on_logout() {
+    let app = init_test_env!();
+
+    let phone = login!(app, LOGIN_1, PASSWORD_1);
+    let laptop = login!(app, LOGIN_1, PASSWORD_1);
+    let list = |access_token: &str| {
+        test::TestRequest::get()
+            .insert_header(("Authorization", format!("Bearer {}", access_token)))
+            .uri("/gates/list")
+            .to_request()
+    };
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", laptop.access_token)))
+        .uri("/auth/logout")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // neither device gets through before its token expires
+    for token in [&phone.access_token, &laptop.access_token] {
+        let resp = test::call_service(&app, list(token)).await;
+
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
 #[actix_rt::test]
 async fn got_401_on_failed_logout() {
     let app = init_test_env!();
@@ -279,6 +365,200 @@ async fn use_refresh_token_only_once() {
     assert_eq!(resp.status(), StatusCode::NOT_FOUND);
 }
 
+#[actix_rt::test]
+async fn refresh_token_reuse_revokes_session() {
+    let app = init_test_env!();
+    let stolen = login!(app, LOGIN_1, PASSWORD_1);
+    let refresh = |refresh_token: &str| {
+        test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(&login::RefreshRequest {
+                refresh_token: refresh_token.to_string(),
+            })
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, refresh(&stolen.refresh_token)).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let rotated: login::Response = test::read_body_json(resp).await;
+
+    // the rotated token comes back
+    let resp = test::call_service(&app, refresh(&stolen.refresh_token)).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // the whole family is gone with it
+    let resp = test::call_service(&app, refresh(&rotated.refresh_token)).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", rotated.access_token)))
+        .uri("/gates/list")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn refresh_token_reuse_revokes_family_without_session() {
+    let jwt = Jwt::new(JWT_SIGN_KEY.to_string());
+    let (access_token, _) = jwt.issue_session_token(
+        "GONE".to_string(),
+        LOGIN_1.to_string(),
+        Vec::new(),
+        Duration::from_secs(ACCESS_TOKEN_SECS),
+        false,
+    );
+    let cache = Cache::new().await;
+
+    // the session record is gone, its tokens are not
+    for (token, used) in [("ROTATED_TOKEN", true), ("LATEST_TOKEN", false)] {
+        cache
+            .store_refresh(&RefreshTokenItem {
+                username: LOGIN_1.to_string(),
+                session_id: "GONE".to_string(),
+                token_hash: jwt.refresh_token_hash(token),
+                issued: mongodb::bson::DateTime::now(),
+                rooms: Vec::new(),
+                used,
+                degraded: false,
+            })
+            .await;
+    }
+
+    let app = init_test_env!(
+        Arc::new(FakeGateDriver::new()),
+        None,
+        Config::default(),
+        cache
+    );
+    let refresh = |refresh_token: &str| {
+        test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(&login::RefreshRequest {
+                refresh_token: refresh_token.to_string(),
+            })
+            .to_request()
+    };
+
+    let resp = test::call_service(&app, refresh("ROTATED_TOKEN")).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = test::call_service(&app, refresh("LATEST_TOKEN")).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", access_token)))
+        .uri("/gates/list")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn legacy_refresh_token_reuse_revokes_its_session() {
+    let app = init_test_env!();
+    let refresh = |refresh_token: &str| {
+        test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(&login::RefreshRequest {
+                refresh_token: refresh_token.to_string(),
+            })
+            .to_request()
+    };
+
+    // the token from before the sessions were kept starts one
+    let resp = test::call_service(&app, refresh(REFRESH_TOKEN_1)).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let rotated: login::Response = test::read_body_json(resp).await;
+    let resp = test::call_service(&app, refresh(REFRESH_TOKEN_1)).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = test::call_service(&app, refresh(&rotated.refresh_token)).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", rotated.access_token)))
+        .uri("/gates/list")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn token_is_refused_without_revocation_list() {
+    let jwt = Jwt::new(JWT_SIGN_KEY.to_string());
+    let (access_token, _, _) = jwt.issue_token(
+        LOGIN_1.to_string(),
+        Vec::new(),
+        Duration::from_secs(ACCESS_TOKEN_SECS),
+        false,
+    );
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Arc::new(Mutex::new(jwt))))
+            .route(
+                "/",
+                web::get().to(|token: JWTToken| async move { token.username }),
+            ),
+    )
+    .await;
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", access_token)))
+        .uri("/")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[actix_rt::test]
+async fn got_404_on_refresh_of_expired_session() {
+    let cache = Cache::new().await;
+
+    // the session is gone, its token is not
+    cache
+        .store_refresh(&RefreshTokenItem {
+            username: LOGIN_1.to_string(),
+            session_id: "EXPIRED".to_string(),
+            token_hash: Jwt::new(JWT_SIGN_KEY.to_string()).refresh_token_hash("EXPIRED_TOKEN"),
+            issued: mongodb::bson::DateTime::now(),
+            rooms: Vec::new(),
+            used: false,
+            degraded: false,
+        })
+        .await;
+
+    let app = init_test_env!(
+        Arc::new(FakeGateDriver::new()),
+        None,
+        Config::default(),
+        cache
+    );
+    let req = test::TestRequest::post()
+        .uri("/auth/refresh")
+        .set_json(&login::RefreshRequest {
+            refresh_token: "EXPIRED_TOKEN".to_string(),
+        })
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
 #[actix_rt::test]
 async fn got_404_on_invalid_refresh() {
     let app = init_test_env!();
@@ -306,16 +586,19 @@ async fn got_401_on_expired_token() {
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
             Gate {
                 id: 2,
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
         ]
         .to_vec(),
         Duration::from_secs(0),
+        false,
     );
 
     let req = test::TestRequest::get()
@@ -340,16 +623,19 @@ async fn got_403_on_expired_token() {
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
             Gate {
                 id: 2,
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
         ]
         .to_vec(),
         Duration::from_secs(0),
+        false,
     );
 
     let req = test::TestRequest::get()
@@ -362,6 +648,330 @@ async fn got_403_on_expired_token() {
     assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
 }
 
+macro_rules! login_challenge {
+    ($app:ident) => {{
+        let req = test::TestRequest::post()
+            .uri("/auth/login")
+            .set_json(&login::LoginRequest {
+                login: LOGIN_1.to_string(),
+                password: PASSWORD_1.to_string(),
+            })
+            .to_request();
+
+        match test::read_body_json(test::call_service(&$app, req).await).await {
+            login::LoginResponse::Challenge(challenge) => challenge,
+            login::LoginResponse::Tokens(_) => panic!("logged in without a second factor"),
+        }
+    }};
+}
+
+macro_rules! verify {
+    ($app:ident, $challenge:expr, $code:expr) => {{
+        let req = test::TestRequest::post()
+            .uri("/auth/login/verify")
+            .set_json(&login::VerifyRequest {
+                challenge: $challenge.to_string(),
+                code: $code.to_string(),
+            })
+            .to_request();
+
+        test::call_service(&$app, req).await.status()
+    }};
+}
+
+#[actix_rt::test]
+async fn totp_asked_for_once_enrolled() {
+    let app = init_test_env!();
+    let body = login!(app, LOGIN_1, PASSWORD_1);
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", body.access_token)))
+        .uri("/auth/totp/enroll")
+        .to_request();
+    let enrollment: login::Enrollment =
+        test::read_body_json(test::call_service(&app, req).await).await;
+
+    assert!(enrollment
+        .uri
+        .starts_with("otpauth://totp/Barrier:login1?secret="));
+
+    // not asked for before it is confirmed
+    login!(app, LOGIN_1, PASSWORD_1);
+
+    let now = totp::now_step();
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", body.access_token)))
+        .uri("/auth/totp/confirm")
+        .set_json(&login::ConfirmRequest {
+            code: totp::code(&enrollment.secret, now).unwrap(),
+        })
+        .to_request();
+
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let challenge = login_challenge!(app);
+    let next = totp::code(&enrollment.secret, now + 1).unwrap();
+    let wrong = format!("{:06}", (next.parse::<u32>().unwrap() + 1) % 1_000_000);
+
+    assert_eq!(challenge.enroll, None);
+    assert_eq!(
+        verify!(app, challenge.challenge, wrong),
+        StatusCode::FORBIDDEN
+    );
+    // already taken by the confirmation
+    assert_eq!(
+        verify!(
+            app,
+            challenge.challenge,
+            totp::code(&enrollment.secret, now).unwrap()
+        ),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(verify!(app, challenge.challenge, next), StatusCode::OK);
+    assert_eq!(
+        verify!(app, challenge.challenge, next),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_rt::test]
+async fn totp_required_enrolls_on_login() {
+    let config = || Config {
+        totp: config::Totp {
+            groups: vec!["users".to_string()],
+            ..config::Totp::default()
+        },
+        ..Config::default()
+    };
+    let app = init_test_env!(Arc::new(FakeGateDriver::new()), None, config());
+
+    // the password alone can't bind an authenticator
+    let req = test::TestRequest::post()
+        .uri("/auth/login")
+        .set_json(&login::LoginRequest {
+            login: LOGIN_1.to_string(),
+            password: PASSWORD_1.to_string(),
+        })
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    // `totp allow`
+    let cache = Cache::new().await;
+
+    cache
+        .store_totp(&TotpItem {
+            username: LOGIN_1.to_string(),
+            secret: String::new(),
+            confirmed: false,
+            last_step: None,
+            enroll_allowed: true,
+        })
+        .await;
+
+    let app = init_test_env!(Arc::new(FakeGateDriver::new()), None, config(), cache);
+    let challenge = login_challenge!(app);
+    let secret = challenge.enroll.unwrap().secret;
+    let code = totp::code(&secret, totp::now_step()).unwrap();
+
+    assert_eq!(verify!(app, challenge.challenge, code), StatusCode::OK);
+
+    // enrolled now, the same code is not taken twice
+    let challenge = login_challenge!(app);
+
+    assert_eq!(challenge.enroll, None);
+    assert_eq!(
+        verify!(app, challenge.challenge, code),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(verify!(app, "forged", code), StatusCode::FORBIDDEN);
+}
+
+#[actix_rt::test]
+async fn totp_allow_outlives_an_unconfirmed_enrollment() {
+    let cache = Cache::new().await;
+
+    cache
+        .store_totp(&TotpItem {
+            username: LOGIN_1.to_string(),
+            secret: String::new(),
+            confirmed: false,
+            last_step: None,
+            enroll_allowed: true,
+        })
+        .await;
+
+    let app = init_test_env!(
+        Arc::new(FakeGateDriver::new()),
+        None,
+        Config {
+            totp: config::Totp {
+                groups: vec!["users".to_string()],
+                ..config::Totp::default()
+            },
+            ..Config::default()
+        },
+        cache
+    );
+    let req = test::TestRequest::post()
+        .uri("/auth/refresh")
+        .set_json(&login::RefreshRequest {
+            refresh_token: REFRESH_TOKEN_1.to_string(),
+        })
+        .to_request();
+    let body: login::Response = test::read_body_json(test::call_service(&app, req).await).await;
+
+    // started and left unconfirmed
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", body.access_token)))
+        .uri("/auth/totp/enroll")
+        .to_request();
+
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert!(login_challenge!(app).enroll.is_some());
+}
+
+#[actix_rt::test]
+async fn locked_out_after_failed_logins() {
+    let app = init_test_env!(
+        Arc::new(FakeGateDriver::new()),
+        None,
+        Config {
+            throttle: config::Throttle {
+                max_user_failures: 2,
+                lockout_secs: 60,
+                delay_ms: 0,
+                ..config::Throttle::default()
+            },
+            ..Config::default()
+        }
+    );
+    let attempt = |password: &str| {
+        test::TestRequest::post()
+            .uri("/auth/login")
+            .set_json(&login::LoginRequest {
+                login: LOGIN_1.to_string(),
+                password: password.to_string(),
+            })
+            .to_request()
+    };
+
+    for _ in 0..2 {
+        let resp = test::call_service(&app, attempt("guess")).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    // even the right password is refused until the lockout is over
+    let resp = test::call_service(&app, attempt(PASSWORD_1)).await;
+    let retry_after: u64 = resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(retry_after > 0 && retry_after <= 60);
+}
+
+#[actix_rt::test]
+async fn jwks_is_empty_with_shared_secret() {
+    let app = init_test_env!();
+
+    let req = test::TestRequest::get()
+        .uri("/.well-known/jwks.json")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+
+    assert_eq!(body, serde_json::json!({ "keys": [] }));
+}
+
+#[actix_rt::test]
+async fn revoke_one_session() {
+    let app = init_test_env!();
+    let login_from = |user_agent: &str| {
+        test::TestRequest::post()
+            .uri("/auth/login")
+            .insert_header((header::USER_AGENT, user_agent))
+            .set_json(&login::LoginRequest {
+                login: LOGIN_1.to_string(),
+                password: PASSWORD_1.to_string(),
+            })
+            .to_request()
+    };
+    let refresh = |refresh_token: String| {
+        test::TestRequest::post()
+            .uri("/auth/refresh")
+            .set_json(&login::RefreshRequest { refresh_token })
+            .to_request()
+    };
+    let laptop: login::Response =
+        test::read_body_json(test::call_service(&app, login_from("laptop")).await).await;
+    let phone: login::Response =
+        test::read_body_json(test::call_service(&app, login_from("phone")).await).await;
+
+    // the session outlives its refresh
+    let resp = test::call_service(&app, refresh(laptop.refresh_token)).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let laptop: login::Response = test::read_body_json(resp).await;
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", laptop.access_token)))
+        .uri("/auth/sessions")
+        .to_request();
+    let body: sessions::Response = test::read_body_json(test::call_service(&app, req).await).await;
+
+    let mut devices: Vec<(&str, bool)> = body
+        .sessions
+        .iter()
+        .map(|session| (session.user_agent.as_str(), session.current))
+        .collect();
+
+    // both logged in within the same millisecond
+    devices.sort();
+    assert_eq!(devices, vec![("laptop", true), ("phone", false)]);
+
+    let phone_session = body
+        .sessions
+        .iter()
+        .find(|session| session.user_agent == "phone")
+        .unwrap();
+
+    let revoke = |id: &str| {
+        test::TestRequest::delete()
+            .insert_header(("Authorization", format!("Bearer {}", laptop.access_token)))
+            .uri(&format!("/auth/sessions/{}", id))
+            .to_request()
+    };
+    let resp = test::call_service(&app, revoke(&phone_session.id)).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = test::call_service(&app, revoke(&phone_session.id)).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // only the phone is logged out
+    let resp = test::call_service(&app, refresh(phone.refresh_token)).await;
+
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = test::call_service(&app, refresh(laptop.refresh_token)).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
 // end auth
 
 #[actix_rt::test]
@@ -376,16 +986,19 @@ async fn open_the_gate() {
                 retries: 1,
                 name: "bathroom".to_string(),
                 description: "".to_string(),
+                control: false,
             },
             Gate {
                 id: 2,
                 retries: 1,
                 name: "kitchen".to_string(),
                 description: "".to_string(),
+                control: false,
             },
         ]
         .to_vec(),
         Duration::from_secs(60),
+        false,
     );
 
     // open the available room
@@ -411,79 +1024,926 @@ async fn open_the_gate() {
 }
 
 #[actix_rt::test]
-async fn list_of_gates() {
-    let app = init_test_env!();
+async fn open_the_gate_through_driver() {
+    let gate_driver = Arc::new(FakeGateDriver::new());
+    let app = init_test_env!(gate_driver.clone());
 
     let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
         "admin".to_string(),
-        [
-            Gate {
-                id: 3,
-                retries: 1,
-                name: "".to_string(),
-                description: "".to_string(),
-            },
-            Gate {
-                id: 4,
-                retries: 1,
-                name: "".to_string(),
-                description: "".to_string(),
-            },
-            Gate {
-                id: 10,
-                retries: 1,
-                name: "".to_string(),
+        [Gate {
+            id: 7,
+            retries: 1,
+            name: "bathroom".to_string(),
+            description: "".to_string(),
+            control: false,
+        }]
+        .to_vec(),
+        Duration::from_secs(60),
+        false,
+    );
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/open/bathroom")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: open::Response = test::read_body_json(resp).await;
+
+    assert!(body.success);
+    assert_eq!(
+        body.result,
+        ControllerResult {
+            code: 0,
+            confirmed: None,
+            attempts: 1,
+        }
+    );
+    assert_eq!(gate_driver.executed(), vec![(7, Command::Open)]);
+}
+
+#[actix_rt::test]
+async fn open_the_gate_with_broken_driver() {
+    let app = init_test_env!(Arc::new(FakeGateDriver::failing(GateError::Unreachable(
+        "connection refused".to_string()
+    ))));
+
+    let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
+        "admin".to_string(),
+        [Gate {
+            id: 7,
+            retries: 1,
+            name: "bathroom".to_string(),
+            description: "".to_string(),
+            control: false,
+        }]
+        .to_vec(),
+        Duration::from_secs(60),
+        false,
+    );
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/open/bathroom")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[actix_rt::test]
+async fn fail_fast_when_controller_is_down() {
+    let app = init_test_env!(Arc::new(FakeGateDriver::failing(GateError::Unreachable(
+        "connection refused".to_string()
+    ))));
+
+    let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
+        "admin".to_string(),
+        [Gate {
+            id: 7,
+            retries: 1,
+            name: "bathroom".to_string(),
+            description: "".to_string(),
+            control: false,
+        }]
+        .to_vec(),
+        Duration::from_secs(60),
+        false,
+    );
+
+    let open = || {
+        test::TestRequest::post()
+            .insert_header(("Authorization", format!("Bearer {}", token.0)))
+            .uri("/gates/open/bathroom")
+            .to_request()
+    };
+
+    for _ in 0..Config::default().breaker.failure_threshold {
+        let resp = test::call_service(&app, open()).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+
+        assert_eq!(body["error"], Errors::ControllerUnreachable.to_string());
+    }
+
+    let resp = test::call_service(&app, open()).await;
+
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let body: serde_json::Value = test::read_body_json(resp).await;
+
+    assert_eq!(body["error"], Errors::CircuitOpen.to_string());
+
+    let req = test::TestRequest::get().uri("/health").to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+
+    // no gates for anyone
+    assert_eq!(body, serde_json::json!({ "healthy": false }));
+
+    let req = test::TestRequest::get().uri("/gates/health").to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/health")
+        .to_request();
+    let body: health::Details = test::read_body_json(test::call_service(&app, req).await).await;
+
+    assert!(!body.healthy);
+    assert_eq!(body.circuits[0].gate, "bathroom");
+}
+
+#[actix_rt::test]
+async fn open_the_gate_rejected_by_controller() {
+    let app = init_test_env!(Arc::new(FakeGateDriver::failing(GateError::Rejected {
+        code: -2
+    })));
+
+    let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
+        "admin".to_string(),
+        [Gate {
+            id: 7,
+            retries: 1,
+            name: "bathroom".to_string(),
+            description: "".to_string(),
+            control: false,
+        }]
+        .to_vec(),
+        Duration::from_secs(60),
+        false,
+    );
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/open/bathroom")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[actix_rt::test]
+async fn control_the_gate() {
+    let gate_driver = Arc::new(FakeGateDriver::new());
+    let app = init_test_env!(gate_driver.clone());
+
+    let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
+        "admin".to_string(),
+        [
+            Gate {
+                id: 1,
+                retries: 1,
+                name: "barrier".to_string(),
                 description: "".to_string(),
+                control: true,
             },
             Gate {
-                id: 100,
+                id: 2,
                 retries: 1,
-                name: "".to_string(),
+                name: "door".to_string(),
                 description: "".to_string(),
+                control: false,
             },
         ]
         .to_vec(),
         Duration::from_secs(60),
+        false,
     );
 
-    let req = test::TestRequest::get()
+    for command in ["hold", "release", "close"] {
+        let req = test::TestRequest::post()
+            .insert_header(("Authorization", format!("Bearer {}", token.0)))
+            .uri(&format!("/gates/barrier/{}", command))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    // plain open grant is not enough to hold the gate
+    let req = test::TestRequest::post()
         .insert_header(("Authorization", format!("Bearer {}", token.0)))
-        .uri("/gates/list")
+        .uri("/gates/door/hold")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    assert_eq!(
+        gate_driver.executed(),
+        vec![
+            (1, Command::Hold),
+            (1, Command::Release),
+            (1, Command::Close)
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn controller_callback() {
+    let controller: std::net::SocketAddr = "10.0.1.10:80".parse().unwrap();
+    let callbacks = Arc::new(PendingResults::new(
+        "/RPC2".to_string(),
+        ["Result".to_string()].into_iter().collect(),
+        [(String::new(), [controller.ip()].into_iter().collect())]
+            .into_iter()
+            .collect(),
+    ));
+    let served = callbacks.clone();
+    let app =
+        test::init_service(App::new().configure(move |cfg| configure_callbacks(cfg, served))).await;
+
+    let answer = callbacks
+        .register(DeviceKey {
+            controller: String::new(),
+            com_port: 2,
+            pku_address: 0,
+            device_address: 4,
+            aggregate_address: 1,
+        })
+        .unwrap();
+
+    let call = r#"<?xml version="1.0"?>
+<methodCall>
+  <methodName>Result</methodName>
+  <params><param><value><struct>
+    <member><name>ComPort</name><value><int>2</int></value></member>
+    <member><name>PKUAddress</name><value><int>0</int></value></member>
+    <member><name>DeviceAddress</name><value><int>4</int></value></member>
+    <member><name>AggregateAddress</name><value><int>1</int></value></member>
+    <member><name>Result</name><value><int>0</int></value></member>
+  </struct></value></param></params>
+</methodCall>"#;
+
+    // anyone else can't forge the result
+    let req = test::TestRequest::post()
+        .uri("/RPC2")
+        .peer_addr("10.0.9.9:80".parse().unwrap())
+        .set_payload(call.replace("Result</name><value><int>0", "Result</name><value><int>-1"))
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let req = test::TestRequest::post()
+        .uri("/RPC2")
+        .peer_addr(controller)
+        .set_payload(call)
         .to_request();
 
     let resp = test::call_service(&app, req).await;
 
     assert_eq!(resp.status(), StatusCode::OK);
 
-    let body: gates::Response = test::read_body_json(resp).await;
+    let body = test::read_body(resp).await;
 
     assert_eq!(
-        &body.gates,
-        &[
+        rpc::parse_response(std::str::from_utf8(&body).unwrap()),
+        Ok(rpc::Response::Success(Value::Boolean(true)))
+    );
+    assert_eq!(answer.await, Ok(0));
+
+    // unknown method is a fault
+    let req = test::TestRequest::post()
+        .uri("/RPC2")
+        .peer_addr(controller)
+        .set_payload(call.replace("<methodName>Result", "<methodName>Other"))
+        .to_request();
+
+    let body = test::read_body(test::call_service(&app, req).await).await;
+
+    assert!(matches!(
+        rpc::parse_response(std::str::from_utf8(&body).unwrap()),
+        Ok(rpc::Response::Fault { code: -32601, .. })
+    ));
+}
+
+#[actix_rt::test]
+async fn list_of_gates() {
+    let app = init_test_env!();
+
+    let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
+        "admin".to_string(),
+        [
             Gate {
                 id: 3,
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
             Gate {
                 id: 4,
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
             Gate {
                 id: 10,
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
             Gate {
                 id: 100,
                 retries: 1,
                 name: "".to_string(),
                 description: "".to_string(),
+                control: false,
             },
         ]
+        .to_vec(),
+        Duration::from_secs(60),
+        false,
+    );
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/list")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: gates::Response = test::read_body_json(resp).await;
+
+    assert_eq!(
+        body.gates
+            .iter()
+            .map(|info| (info.gate.id, info.online))
+            .collect::<Vec<_>>(),
+        vec![(3, true), (4, true), (10, true), (100, true)]
+    );
+}
+
+#[actix_rt::test]
+async fn gate_status() {
+    let app = init_test_env!();
+
+    let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
+        "admin".to_string(),
+        [Gate {
+            id: 1,
+            retries: 1,
+            name: "barrier".to_string(),
+            description: "".to_string(),
+            control: false,
+        }]
+        .to_vec(),
+        Duration::from_secs(60),
+        false,
+    );
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/barrier/status")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: status::Response = test::read_body_json(resp).await;
+
+    assert_eq!(body.gate, "barrier");
+    assert_eq!(body.status, GateStatus::Closed);
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/door/status")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn offline_gate_in_list() {
+    let app = init_test_env!(Arc::new(FakeGateDriver::failing(GateError::Unreachable(
+        "connection refused".to_string()
+    ))));
+
+    let token = Jwt::new(JWT_SIGN_KEY.to_string()).issue_token(
+        "admin".to_string(),
+        [Gate {
+            id: 1,
+            retries: 1,
+            name: "barrier".to_string(),
+            description: "".to_string(),
+            control: false,
+        }]
+        .to_vec(),
+        Duration::from_secs(60),
+        false,
+    );
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/barrier/status")
+        .to_request();
+
+    let body: status::Response = test::read_body_json(test::call_service(&app, req).await).await;
+
+    assert_eq!(body.status, GateStatus::Offline);
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", token.0)))
+        .uri("/gates/list")
+        .to_request();
+
+    let body: gates::Response = test::read_body_json(test::call_service(&app, req).await).await;
+
+    assert!(!body.gates[0].online);
+}
+
+/// Serves the simulator on a random port, returns its URL.
+fn start_simulator(config: SimConfig) -> (Arc<Simulator>, String) {
+    let sim = Arc::new(Simulator::new(config));
+    let served = sim.clone();
+    let server = HttpServer::new(move || {
+        let sim = served.clone();
+
+        App::new().configure(move |cfg| sim::configure(cfg, sim))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("http://{}/", server.addrs()[0]);
+
+    actix_rt::spawn(server.run());
+
+    (sim, url)
+}
+
+fn simulated_config(gate_server: String) -> Config {
+    let mut config = Config {
+        gate_server,
+        ..Config::default()
+    };
+
+    config.xmlrpc.status_method = Some("GetStatus".to_string());
+    config.gate_mapping.clear();
+    config.gate_mapping.insert(
+        "barrier".to_string(),
+        ConfigGate {
+            id: 1,
+            description: "".to_string(),
+            retries: 1,
+            driver: "xmlrpc".to_string(),
+            controller: None,
+            xmlrpc: Default::default(),
+            retry: Default::default(),
+        },
     );
+
+    config
+}
+
+const SIMULATED_BARRIER: Address = Address {
+    com_port: 2,
+    pku_address: 0,
+    device_address: 1,
+    aggregate_address: 1,
+};
+
+fn barrier_token() -> String {
+    Jwt::new(JWT_SIGN_KEY.to_string())
+        .issue_token(
+            "admin".to_string(),
+            [Gate {
+                id: 1,
+                retries: 1,
+                name: "barrier".to_string(),
+                description: "".to_string(),
+                control: false,
+            }]
+            .to_vec(),
+            Duration::from_secs(60),
+            false,
+        )
+        .0
+}
+
+#[actix_rt::test]
+async fn open_the_gate_through_simulator() {
+    let (sim, url) = start_simulator(SimConfig::default());
+    let app = init_test_env!(Arc::new(GateDrivers::from_config(
+        &simulated_config(url),
+        None
+    )));
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", barrier_token())))
+        .uri("/gates/open/barrier")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(sim.state(&SIMULATED_BARRIER), DeviceState::Open);
+
+    let req = test::TestRequest::get()
+        .insert_header(("Authorization", format!("Bearer {}", barrier_token())))
+        .uri("/gates/barrier/status")
+        .to_request();
+
+    let body: status::Response = test::read_body_json(test::call_service(&app, req).await).await;
+
+    assert_eq!(body.status, GateStatus::Open);
+}
+
+#[actix_rt::test]
+async fn simulator_rejects_the_command() {
+    let (_, url) = start_simulator(SimConfig {
+        reject_rate: 1.0,
+        ..SimConfig::default()
+    });
+    let app = init_test_env!(Arc::new(GateDrivers::from_config(
+        &simulated_config(url),
+        None
+    )));
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", barrier_token())))
+        .uri("/gates/open/barrier")
+        .to_request();
+
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[actix_rt::test]
+async fn simulator_confirms_the_command() {
+    let callbacks = Arc::new(PendingResults::new(
+        "/".to_string(),
+        ["Result".to_string()].into_iter().collect(),
+        [(
+            String::new(),
+            ["127.0.0.1".parse().unwrap()].into_iter().collect(),
+        )]
+        .into_iter()
+        .collect(),
+    ));
+    let served = callbacks.clone();
+    let callback_server = HttpServer::new(move || {
+        let served = served.clone();
+        App::new().configure(move |cfg| configure_callbacks(cfg, served))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let callback_addr = callback_server.addrs()[0];
+
+    actix_rt::spawn(callback_server.run());
+
+    let (_, url) = start_simulator(SimConfig {
+        callback: true,
+        ..SimConfig::default()
+    });
+    let mut config = simulated_config(url);
+
+    config.xmlrpc.ip_server = Some(callback_addr.ip().to_string());
+    config.xmlrpc.port_server = Some(callback_addr.port() as i32);
+    config.callback = Some(Callback {
+        path: "/".to_string(),
+        timeout_ms: 2000,
+        listen_addr: callback_addr.to_string(),
+        allow: Vec::new(),
+    });
+
+    let app = init_test_env!(Arc::new(GateDrivers::from_config(&config, Some(callbacks))));
+
+    let req = test::TestRequest::post()
+        .insert_header(("Authorization", format!("Bearer {}", barrier_token())))
+        .uri("/gates/open/barrier")
+        .to_request();
+
+    let body: open::Response = test::read_body_json(test::call_service(&app, req).await).await;
+
+    assert_eq!(body.result.confirmed, Some(true));
+}
+
+const OIDC_CLIENT: &str = "barrier";
+const OIDC_REDIRECT: &str = "http://barrier.example/auth/oidc/callback";
+
+/// Serves the identity provider on a random port, returns it with the login configured for it.
+fn start_mock_idp() -> (Arc<MockIdp>, Arc<Oidc>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let idp = Arc::new(MockIdp::new(issuer.clone(), OIDC_CLIENT));
+    let served = idp.clone();
+    let server = HttpServer::new(move || {
+        let idp = served.clone();
+
+        App::new().configure(move |cfg| mock::configure(cfg, idp))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap();
+
+    actix_rt::spawn(server.run());
+
+    let mut config = simulated_config(String::new());
+
+    config
+        .gates
+        .insert("guards".to_string(), vec!["barrier".to_string()]);
+
+    let oidc = Oidc::new(
+        &config::Oidc {
+            issuer,
+            client_id: OIDC_CLIENT.to_string(),
+            client_secret: None,
+            redirect_uri: OIDC_REDIRECT.to_string(),
+            scopes: vec!["openid".to_string(), "groups".to_string()],
+            groups_claim: "groups".to_string(),
+        },
+        config.get_mappings(),
+    );
+
+    (idp, Arc::new(oidc))
+}
+
+/// Follows `/auth/oidc/start` to the identity provider, returns where it sends the browser back
+/// with the cookie the start set.
+macro_rules! oidc_authorize {
+    ($app:ident) => {{
+        let req = test::TestRequest::get()
+            .uri("/auth/oidc/start")
+            .to_request();
+        let resp = test::call_service(&$app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::FOUND);
+
+        let browser = resp
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == BROWSER_COOKIE)
+            .unwrap()
+            .into_owned();
+
+        assert_eq!(browser.http_only(), Some(true));
+
+        let authorize = resp
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let resp = client.get(&authorize).send().await.unwrap();
+        let callback = reqwest::Url::parse(resp.headers()["location"].to_str().unwrap()).unwrap();
+
+        assert!(callback.as_str().starts_with(OIDC_REDIRECT));
+
+        (callback, browser)
+    }};
+}
+
+#[actix_rt::test]
+async fn oidc_login_maps_groups_to_gates() {
+    let (idp, oidc) = start_mock_idp();
+    let app = init_test_env!(Arc::new(FakeGateDriver::new()), Some(oidc));
+
+    idp.sign_in("contractor", &["guards", "visitors"]);
+
+    let (callback, browser) = oidc_authorize!(app);
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/auth/oidc/callback?{}",
+            callback.query().unwrap()
+        ))
+        .cookie(browser.clone())
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let body: login::Response = test::read_body_json(resp).await;
+    let token = Jwt::new(JWT_SIGN_KEY.to_string())
+        .verify_token(body.access_token)
+        .unwrap();
+
+    // apart from a directory user named contractor
+    assert_eq!(
+        token.username,
+        format!("oidc:sub-contractor@{}", idp.issuer())
+    );
+    assert_eq!(
+        token
+            .available_rooms
+            .iter()
+            .map(|gate| gate.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["barrier"]
+    );
+
+    // the refresh token is the same as after a password login
+    let req = test::TestRequest::post()
+        .uri("/auth/refresh")
+        .set_json(&login::RefreshRequest {
+            refresh_token: body.refresh_token,
+        })
+        .to_request();
+
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    // the state is used up
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/auth/oidc/callback?{}",
+            callback.query().unwrap()
+        ))
+        .cookie(browser)
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_rt::test]
+async fn oidc_rejects_unknown_state_and_wrong_verifier() {
+    let (idp, oidc) = start_mock_idp();
+    let app = init_test_env!(Arc::new(FakeGateDriver::new()), Some(oidc));
+
+    idp.sign_in("contractor", &["guards"]);
+
+    let req = test::TestRequest::get()
+        .uri("/auth/oidc/callback?state=forged&code=forged")
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    // the code of one login redeemed with the verifier of another fails PKCE
+    let first: std::collections::HashMap<_, _> =
+        oidc_authorize!(app).0.query_pairs().into_owned().collect();
+    let (second, browser) = oidc_authorize!(app);
+    let second: std::collections::HashMap<_, _> = second.query_pairs().into_owned().collect();
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/auth/oidc/callback?state={}&code={}",
+            second["state"], first["code"]
+        ))
+        .cookie(browser)
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/auth/oidc/callback?state=forged&error=access_denied")
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_rt::test]
+async fn oidc_rejects_replayed_id_token() {
+    let (idp, oidc) = start_mock_idp();
+    let app = init_test_env!(Arc::new(FakeGateDriver::new()), Some(oidc));
+
+    idp.sign_in("contractor", &["guards"]);
+    idp.forge_nonce("replayed");
+
+    let (callback, browser) = oidc_authorize!(app);
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/auth/oidc/callback?{}",
+            callback.query().unwrap()
+        ))
+        .cookie(browser)
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_rt::test]
+async fn oidc_login_finishes_in_the_starting_browser() {
+    let (idp, oidc) = start_mock_idp();
+    let app = init_test_env!(Arc::new(FakeGateDriver::new()), Some(oidc));
+
+    idp.sign_in("attacker", &["guards"]);
+
+    // the attacker's state and code, followed by the victim's browser
+    let (callback, _) = oidc_authorize!(app);
+    let (_, victim) = oidc_authorize!(app);
+    let uri = format!("/auth/oidc/callback?{}", callback.query().unwrap());
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let (callback, _) = oidc_authorize!(app);
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/auth/oidc/callback?{}",
+            callback.query().unwrap()
+        ))
+        .cookie(victim)
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[actix_rt::test]
+async fn oidc_login_asks_for_the_second_factor() {
+    let (idp, oidc) = start_mock_idp();
+    let username = format!("oidc:sub-contractor@{}", idp.issuer());
+    let config = || Config {
+        totp: config::Totp {
+            gates: vec!["barrier".to_string()],
+            ..config::Totp::default()
+        },
+        ..Config::default()
+    };
+    let app = init_test_env!(
+        Arc::new(FakeGateDriver::new()),
+        Some(oidc.clone()),
+        config()
+    );
+
+    idp.sign_in("contractor", &["guards"]);
+
+    let (callback, browser) = oidc_authorize!(app);
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/auth/oidc/callback?{}",
+            callback.query().unwrap()
+        ))
+        .cookie(browser)
+        .to_request();
+
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    // `totp allow`
+    let cache = Cache::new().await;
+
+    cache
+        .store_totp(&TotpItem {
+            username,
+            secret: String::new(),
+            confirmed: false,
+            last_step: None,
+            enroll_allowed: true,
+        })
+        .await;
+
+    let app = init_test_env!(Arc::new(FakeGateDriver::new()), Some(oidc), config(), cache);
+    let (callback, browser) = oidc_authorize!(app);
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/auth/oidc/callback?{}",
+            callback.query().unwrap()
+        ))
+        .cookie(browser)
+        .to_request();
+    let challenge = match test::read_body_json(test::call_service(&app, req).await).await {
+        login::LoginResponse::Challenge(challenge) => challenge,
+        login::LoginResponse::Tokens(_) => panic!("logged in without a second factor"),
+    };
+    let code = totp::code(&challenge.enroll.unwrap().secret, totp::now_step()).unwrap();
+
+    assert_eq!(verify!(app, challenge.challenge, code), StatusCode::OK);
 }